@@ -1,17 +1,20 @@
-/// Accumulator,OPC A	operand is AC (implied single byte instruction)
-/// absolute	OPC $LLHH	operand is address $HHLL *
-/// X	absolute, X-indexed	OPC $LLHH,X	operand is address; effective address is address incremented by X with carry **
-/// Y	absolute, Y-indexed	OPC $LLHH,Y	operand is address; effective address is address incremented by Y with carry **
-/// immediate	OPC #$BB	operand is byte BB
-/// implied	OPC	operand implied
-/// indirect	OPC ($LLHH)	operand is address; effective address is contents of word at address: C.w($HHLL)
-/// X-indexed, indirect	OPC ($LL,X)	operand is zeropage address; effective address is word in (LL + X, LL + X + 1), inc. without carry: C.w($00LL + X)
-/// indirect, Y-indexed	OPC ($LL),Y	operand is zeropage address; effective address is word in (LL, LL + 1) incremented by Y with carry: C.w($00LL) + Y
-/// relative	OPC $BB	branch target is PC + signed offset BB ***
-/// zeropage	OPC $LL	operand is zeropage address (hi-byte is zero, address = $00LL)
-/// zeropage, X-indexed	OPC $LL,X	operand is zeropage address; effective address is address incremented by X without carry **
-/// zeropage, Y-indexed	OPC $LL,Y	operand is zeropage address; effective address is address incremented by Y without carry **
-
+/// Accumulator,OPC A operand is AC (implied single byte instruction)
+/// absolute OPC $LLHH operand is address $HHLL *
+/// X absolute, X-indexed OPC $LLHH,X operand is address; effective address is address incremented by X with carry **
+/// Y absolute, Y-indexed OPC $LLHH,Y operand is address; effective address is address incremented by Y with carry **
+/// immediate OPC #$BB operand is byte BB
+/// implied OPC operand implied
+/// indirect OPC ($LLHH) operand is address; effective address is contents of word at address: C.w($HHLL)
+/// X-indexed, indirect OPC ($LL,X) operand is zeropage address; effective address is word in (LL + X, LL + X + 1), inc. without carry: C.w($00LL + X)
+/// indirect, Y-indexed OPC ($LL),Y operand is zeropage address; effective address is word in (LL, LL + 1) incremented by Y with carry: C.w($00LL) + Y
+/// relative OPC $BB branch target is PC + signed offset BB ***
+/// zeropage OPC $LL operand is zeropage address (hi-byte is zero, address = $00LL)
+/// zeropage, X-indexed OPC $LL,X operand is zeropage address; effective address is address incremented by X without carry **
+/// zeropage, Y-indexed OPC $LL,Y operand is zeropage address; effective address is address incremented by Y without carry **
+/// 65C02 only:
+/// zeropage indirect OPC ($LL) operand is zeropage address; effective address is word in (LL, LL + 1): C.w($00LL)
+/// absolute X-indexed indirect OPC ($LLHH,X) operand is address; effective address is word at address incremented by X: C.w($HHLL + X)
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum AddrMode {
     Accumulator,
     Absolute,
@@ -26,4 +29,6 @@ pub enum AddrMode {
     ZeroPage,
     ZeroPageX,
     ZeroPageY,
+    ZeroPageIndirect,
+    AbsoluteIndirectX,
 }
\ No newline at end of file
@@ -0,0 +1,203 @@
+//! Delta modulation channel, 0x4010-0x4013.
+//!
+//! 0x4010 IL-- RRRR IRQ enable, loop, rate index
+//! 0x4011 -DDD DDDD direct load of the output level
+//! 0x4012 AAAA AAAA sample address, 0xC000 + A * 64
+//! 0x4013 LLLL LLLL sample length, L * 16 + 1 bytes
+//!
+//! Sample bytes are read through the CPU bus. The channel cannot do that itself, it asks for a byte
+//! with `fetch_request` and the bus answers with `fill_sample_buffer`, stalling the CPU while it does.
+
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// NTSC rates in CPU cycles
+static RATE_TABLE: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+
+// cycles the CPU is halted for each sample fetch, the real value depends on the instruction being executed
+pub const FETCH_STALL_CYCLES: u8 = 4;
+
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    timer_period: u16,
+    timer: u16,
+    output_level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    pub bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    pub irq_pending: bool,
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dmc {
+    pub fn new() -> Self {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            timer_period: RATE_TABLE[0],
+            timer: 0,
+            output_level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq_pending: false,
+        }
+    }
+    pub fn write_register(&mut self,register: u16,data: u8) {
+        match register & 0b11 {
+            0 => {
+                self.irq_enabled = data & 0b1000_0000 != 0;
+                self.looping = data & 0b0100_0000 != 0;
+                self.timer_period = RATE_TABLE[(data & 0x0F) as usize];
+                if !self.irq_enabled {
+                    self.irq_pending = false;
+                }
+            },
+            1 => self.output_level = data & 0b0111_1111,
+            2 => self.sample_address = 0xC000 | ((data as u16) << 6),
+            _ => self.sample_length = ((data as u16) << 4) | 1,
+        }
+    }
+    pub fn set_enabled(&mut self,enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        }else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+    // address of the next sample byte, when the buffer is empty and the sample is not finished
+    pub fn fetch_request(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        }else {
+            None
+        }
+    }
+    pub fn fill_sample_buffer(&mut self,data: u8) {
+        self.sample_buffer = Some(data);
+        // the address wraps from 0xFFFF to 0x8000
+        self.current_address = if self.current_address == 0xFFFF { 0x8000 } else { self.current_address + 1 };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            }else if self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+    }
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+        if !self.silence {
+            // the level moves by 2 and stays inside 0..=127
+            if self.shift_register & 1 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            }else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift_register = data;
+                },
+                None => self.silence = true,
+            }
+        }
+    }
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+impl Snapshot for Dmc {
+    fn save_state(&self,state: &mut StateWriter) {
+        state.bool(self.irq_enabled);
+        state.bool(self.looping);
+        state.u16(self.timer_period);
+        state.u16(self.timer);
+        state.u8(self.output_level);
+        state.u16(self.sample_address);
+        state.u16(self.sample_length);
+        state.u16(self.current_address);
+        state.u16(self.bytes_remaining);
+        state.bool(self.sample_buffer.is_some());
+        state.u8(self.sample_buffer.unwrap_or(0));
+        state.u8(self.shift_register);
+        state.u8(self.bits_remaining);
+        state.bool(self.silence);
+        state.bool(self.irq_pending);
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        self.irq_enabled = state.bool()?;
+        self.looping = state.bool()?;
+        self.timer_period = state.u16()?;
+        state.ensure(RATE_TABLE.contains(&self.timer_period))?;
+        self.timer = state.u16()?;
+        self.output_level = state.u8()?;
+        self.sample_address = state.u16()?;
+        self.sample_length = state.u16()?;
+        self.current_address = state.u16()?;
+        self.bytes_remaining = state.u16()?;
+        let buffered = state.bool()?;
+        let sample = state.u8()?;
+        self.sample_buffer = buffered.then_some(sample);
+        self.shift_register = state.u8()?;
+        self.bits_remaining = state.u8()?;
+        state.ensure((1..=8).contains(&self.bits_remaining))?;
+        self.silence = state.bool()?;
+        self.irq_pending = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::{load, save};
+    #[test]
+    fn test_load_rejects_impossible_values() {
+        let mut dmc = Dmc::new();
+        dmc.write_register(0x4010,0x0F);
+        let data = save(&dmc,0);
+        // the 21 byte payload ends the state, timer_period is at offset 2 and bits_remaining at 18
+        let start = data.len() - 21;
+        let mut loaded = Dmc::new();
+        load(&mut loaded,0,&data).unwrap();
+        assert_eq!(loaded.timer_period,RATE_TABLE[15]);
+        for (offset,bytes) in [(2,&[0x00, 0x00][..]),(18,&[0x00]),(18,&[0x09])] {
+            let mut corrupt = data.clone();
+            corrupt[start + offset..start + offset + bytes.len()].copy_from_slice(bytes);
+            assert!(matches!(load(&mut Dmc::new(),0,&corrupt),Err(StateError::Corrupt { .. })));
+        }
+    }
+}
@@ -0,0 +1,386 @@
+//! 2A03 audio processing unit.
+//!
+//! CPU facing registers:
+//! [0x4000 .. 0x4003] pulse 1
+//! [0x4004 .. 0x4007] pulse 2
+//! [0x4008 .. 0x400B] triangle
+//! [0x400C .. 0x400F] noise
+//! [0x4010 .. 0x4013] DMC
+//! 0x4015 (write) channel enables, (read) length counter / DMC / IRQ status
+//! 0x4017 (write) frame counter mode and IRQ inhibit
+//!
+//! The frame counter clocks envelopes and the linear counter every quarter frame, length counters
+//! and sweeps every half frame, in a 4 step sequence that can raise an IRQ or a 5 step one that cannot.
+//! https://www.nesdev.org/wiki/APU_Frame_Counter
+//!
+//! The channels are combined by the nonlinear mixer and resampled to the host rate by averaging
+//! every CPU cycle that falls into a host sample. Samples are in [0.0, 1.0].
+
+mod dmc;
+mod noise;
+mod pulse;
+mod triangle;
+mod units;
+mod wav;
+
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use dmc::Dmc;
+use noise::Noise;
+use pulse::Pulse;
+use triangle::Triangle;
+
+pub use dmc::FETCH_STALL_CYCLES;
+pub use wav::encode_wav;
+
+pub const APU_STATUS: u16 = 0x4015;
+pub const FRAME_COUNTER: u16 = 0x4017;
+
+pub const CPU_CLOCK_RATE: u64 = 1_789_773;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+// frame counter steps in CPU cycles, NTSC
+const QUARTER_FRAME_1: u32 = 7457;
+const HALF_FRAME_1: u32 = 14913;
+const QUARTER_FRAME_3: u32 = 22371;
+const FOUR_STEP_IRQ: u32 = 29828;
+const FOUR_STEP_LAST: u32 = 29829;
+const FOUR_STEP_PERIOD: u32 = 29830;
+const FIVE_STEP_LAST: u32 = 37281;
+const FIVE_STEP_PERIOD: u32 = 37282;
+
+pub struct Apu {
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    five_step_mode: bool,
+    frame_irq_inhibit: bool,
+    frame_irq_pending: bool,
+    frame_cycle: u32,
+    // pulse timers only run on every other CPU cycle
+    odd_cycle: bool,
+    sample_rate: u32,
+    sample_clock: u64,
+    sample_sum: f32,
+    sample_count: u32,
+    samples: Vec<f32>,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new(DEFAULT_SAMPLE_RATE)
+    }
+}
+
+impl Apu {
+    pub fn new(sample_rate: u32) -> Self {
+        Apu {
+            pulse_1: Pulse::new(true),
+            pulse_2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            five_step_mode: false,
+            frame_irq_inhibit: false,
+            frame_irq_pending: false,
+            frame_cycle: 0,
+            odd_cycle: false,
+            sample_rate,
+            sample_clock: 0,
+            sample_sum: 0.0,
+            sample_count: 0,
+            samples: Vec::new(),
+        }
+    }
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    pub fn set_sample_rate(&mut self,sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_clock = 0;
+    }
+    /// Hands out the samples produced since the last call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+    // a reset silences every channel as if $4015 was written with 0 and restarts the frame counter in its last mode
+    pub fn reset(&mut self) {
+        self.write_register(APU_STATUS,0);
+        self.frame_irq_pending = false;
+        self.frame_cycle = 0;
+    }
+    // level of the APU IRQ line, frame counter and DMC share it
+    pub fn irq(&self) -> bool {
+        self.frame_irq_pending || self.dmc.irq_pending
+    }
+    // register op
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        let channels = [
+            &self.pulse_1.length_counter,
+            &self.pulse_2.length_counter,
+            &self.triangle.length_counter,
+            &self.noise.length_counter,
+        ];
+        for (bit,length_counter) in channels.iter().enumerate() {
+            if length_counter.active() {
+                status |= 1 << bit;
+            }
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 0b0001_0000;
+        }
+        if self.frame_irq_pending {
+            status |= 0b0100_0000;
+        }
+        if self.dmc.irq_pending {
+            status |= 0b1000_0000;
+        }
+        // reading acknowledges the frame IRQ, not the DMC one
+        self.frame_irq_pending = false;
+        status
+    }
+    pub fn write_register(&mut self,addr: u16,data: u8) {
+        match addr {
+            0x4000 ..= 0x4003 => self.pulse_1.write_register(addr,data),
+            0x4004 ..= 0x4007 => self.pulse_2.write_register(addr,data),
+            0x4008 ..= 0x400B => self.triangle.write_register(addr,data),
+            0x400C ..= 0x400F => self.noise.write_register(addr,data),
+            0x4010 ..= 0x4013 => self.dmc.write_register(addr,data),
+            APU_STATUS => {
+                self.pulse_1.length_counter.set_enabled(data & 0b0000_0001 != 0);
+                self.pulse_2.length_counter.set_enabled(data & 0b0000_0010 != 0);
+                self.triangle.length_counter.set_enabled(data & 0b0000_0100 != 0);
+                self.noise.length_counter.set_enabled(data & 0b0000_1000 != 0);
+                self.dmc.set_enabled(data & 0b0001_0000 != 0);
+                self.dmc.irq_pending = false;
+            },
+            FRAME_COUNTER => {
+                self.five_step_mode = data & 0b1000_0000 != 0;
+                self.frame_irq_inhibit = data & 0b0100_0000 != 0;
+                if self.frame_irq_inhibit {
+                    self.frame_irq_pending = false;
+                }
+                self.frame_cycle = 0;
+                // entering 5 step mode clocks every unit right away
+                if self.five_step_mode {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            },
+            _ => {},
+        }
+    }
+    // DMC sample fetch, serviced by the bus
+    pub fn dmc_fetch_request(&self) -> Option<u16> {
+        self.dmc.fetch_request()
+    }
+    pub fn dmc_fill(&mut self,data: u8) {
+        self.dmc.fill_sample_buffer(data);
+    }
+    // clock op
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_1.clock_quarter_frame();
+        self.pulse_2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.clock_quarter_frame();
+    }
+    fn clock_half_frame(&mut self) {
+        self.pulse_1.clock_half_frame();
+        self.pulse_2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        let raise_irq = !self.five_step_mode && !self.frame_irq_inhibit;
+        match self.frame_cycle {
+            QUARTER_FRAME_1 | QUARTER_FRAME_3 => self.clock_quarter_frame(),
+            HALF_FRAME_1 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            },
+            FOUR_STEP_IRQ if raise_irq => self.frame_irq_pending = true,
+            FOUR_STEP_LAST if !self.five_step_mode => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                if raise_irq {
+                    self.frame_irq_pending = true;
+                }
+            },
+            FOUR_STEP_PERIOD if !self.five_step_mode => {
+                if raise_irq {
+                    self.frame_irq_pending = true;
+                }
+                self.frame_cycle = 0;
+            },
+            FIVE_STEP_LAST => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            },
+            FIVE_STEP_PERIOD => self.frame_cycle = 0,
+            _ => {},
+        }
+    }
+    /// Advances the APU by one CPU cycle.
+    pub fn tick(&mut self) {
+        self.clock_frame_counter();
+        if self.odd_cycle {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+
+        self.sample_sum += self.output();
+        self.sample_count += 1;
+        self.sample_clock += self.sample_rate as u64;
+        if self.sample_clock >= CPU_CLOCK_RATE {
+            self.sample_clock -= CPU_CLOCK_RATE;
+            self.samples.push(self.sample_sum / self.sample_count as f32);
+            self.sample_sum = 0.0;
+            self.sample_count = 0;
+        }
+    }
+    // nonlinear mixer, https://www.nesdev.org/wiki/APU_Mixer
+    pub fn output(&self) -> f32 {
+        let pulse = (self.pulse_1.output() + self.pulse_2.output()) as f32;
+        let pulse_out = if pulse == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulse + 100.0) };
+        let tnd = self.triangle.output() as f32 / 8227.0 + self.noise.output() as f32 / 12241.0 + self.dmc.output() as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd + 100.0) };
+        pulse_out + tnd_out
+    }
+}
+
+// the host sample rate is a setting of the front end, it is not part of the state. Neither are the samples
+// not collected yet, loading leaves them alone so a rejected state loses nothing, `Console` drops them on success
+impl Snapshot for Apu {
+    fn save_state(&self,state: &mut StateWriter) {
+        state.section(b"APU ",|state| {
+            self.pulse_1.save_state(state);
+            self.pulse_2.save_state(state);
+            self.triangle.save_state(state);
+            self.noise.save_state(state);
+            self.dmc.save_state(state);
+            state.bool(self.five_step_mode);
+            state.bool(self.frame_irq_inhibit);
+            state.bool(self.frame_irq_pending);
+            state.u32(self.frame_cycle);
+            state.bool(self.odd_cycle);
+            state.u64(self.sample_clock);
+            state.f32(self.sample_sum);
+            state.u32(self.sample_count);
+        });
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        state.section(b"APU ",|state| {
+            self.pulse_1.load_state(state)?;
+            self.pulse_2.load_state(state)?;
+            self.triangle.load_state(state)?;
+            self.noise.load_state(state)?;
+            self.dmc.load_state(state)?;
+            self.five_step_mode = state.bool()?;
+            self.frame_irq_inhibit = state.bool()?;
+            self.frame_irq_pending = state.bool()?;
+            self.frame_cycle = state.u32()?;
+            self.odd_cycle = state.bool()?;
+            self.sample_clock = state.u64()?;
+            self.sample_sum = state.f32()?;
+            self.sample_count = state.u32()?;
+            state.ensure(self.sample_clock < CPU_CLOCK_RATE)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_status_reports_length_counters() {
+        let mut apu = Apu::default();
+        // writes to a disabled channel do not load the length counter
+        apu.write_register(0x4003,0b0000_1000);
+        assert_eq!(apu.read_status() & 0x0F,0);
+        apu.write_register(APU_STATUS,0b0000_1001);
+        apu.write_register(0x4003,0b0000_1000);
+        apu.write_register(0x400F,0b0000_1000);
+        assert_eq!(apu.read_status() & 0x0F,0b1001);
+        apu.write_register(APU_STATUS,0b0000_0001);
+        assert_eq!(apu.read_status() & 0x0F,0b0001);
+    }
+    #[test]
+    fn test_length_counter_runs_out_on_half_frames() {
+        let mut apu = Apu::default();
+        apu.write_register(APU_STATUS,0b0000_0001);
+        // index 3 loads 2
+        apu.write_register(0x4003,0b0001_1000);
+        apu.write_register(FRAME_COUNTER,0b0100_0000);
+        for _ in 0..HALF_FRAME_1 {
+            apu.tick();
+        }
+        assert_eq!(apu.read_status() & 1,1);
+        for _ in HALF_FRAME_1..FOUR_STEP_LAST {
+            apu.tick();
+        }
+        assert_eq!(apu.read_status() & 1,0);
+    }
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::default();
+        for _ in 0..FOUR_STEP_IRQ - 1 {
+            apu.tick();
+        }
+        assert!(!apu.irq());
+        apu.tick();
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & 0b0100_0000,0b0100_0000);
+        assert_eq!(apu.read_status() & 0b0100_0000,0);
+        // 5 step mode never raises it
+        apu.write_register(FRAME_COUNTER,0b1000_0000);
+        for _ in 0..FIVE_STEP_PERIOD * 2 {
+            apu.tick();
+        }
+        assert!(!apu.irq());
+    }
+    #[test]
+    fn test_sample_rate() {
+        let mut apu = Apu::new(48_000);
+        apu.write_register(APU_STATUS,0b0000_0001);
+        apu.write_register(0x4000,0b1011_1111);
+        apu.write_register(0x4002,0xFD);
+        apu.write_register(0x4003,0b0000_1000);
+        for _ in 0..CPU_CLOCK_RATE / 10 {
+            apu.tick();
+        }
+        let samples = apu.take_samples();
+        assert!((4_799..=4_800).contains(&samples.len()));
+        assert!(samples.iter().all(|sample| (0.0..=1.0).contains(sample)));
+        assert!(samples.iter().any(|sample| *sample > 0.1));
+        assert!(apu.take_samples().is_empty());
+    }
+    #[test]
+    fn test_dmc_fetches_and_raises_irq() {
+        let mut apu = Apu::default();
+        // IRQ enabled, fastest rate, sample at 0xC040, 17 bytes
+        apu.write_register(0x4010,0b1000_1111);
+        apu.write_register(0x4012,1);
+        apu.write_register(0x4013,1);
+        assert_eq!(apu.dmc_fetch_request(),None);
+        apu.write_register(APU_STATUS,0b0001_0000);
+        let mut fetched = Vec::new();
+        for _ in 0..54 * 8 * 20 {
+            apu.tick();
+            if let Some(addr) = apu.dmc_fetch_request() {
+                fetched.push(addr);
+                apu.dmc_fill(0xFF);
+            }
+        }
+        assert_eq!(fetched,(0xC040..0xC051).collect::<Vec<u16>>());
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & 0b1001_0000,0b1000_0000);
+        assert!(apu.dmc.output() > 0);
+    }
+}
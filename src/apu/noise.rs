@@ -0,0 +1,148 @@
+//! Noise channel, 0x400C-0x400F.
+//!
+//! 0x400C --LC VVVV length counter halt / envelope loop, constant volume, volume / envelope period
+//! 0x400E M--- PPPP mode, timer period index
+//! 0x400F LLLL L--- length counter load
+//!
+//! A 15 bit linear feedback shift register produces the noise, mode 1 taps bit 6 instead of bit 1
+//! which gives a short, metallic sounding sequence.
+
+use super::units::{Envelope, LengthCounter};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// NTSC timer periods in CPU cycles
+static PERIOD_TABLE: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+
+pub struct Noise {
+    shift_register: u16,
+    short_mode: bool,
+    timer_period: u16,
+    timer: u16,
+    pub length_counter: LengthCounter,
+    envelope: Envelope,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Noise {
+            // loaded with 1 on power up
+            shift_register: 1,
+            short_mode: false,
+            timer_period: PERIOD_TABLE[0],
+            timer: 0,
+            length_counter: LengthCounter::default(),
+            envelope: Envelope::default(),
+        }
+    }
+    pub fn write_register(&mut self,register: u16,data: u8) {
+        match register & 0b11 {
+            0 => {
+                self.length_counter.halt = data & 0b0010_0000 != 0;
+                self.envelope.write_control(data);
+            },
+            1 => {},
+            2 => {
+                self.short_mode = data & 0b1000_0000 != 0;
+                self.timer_period = PERIOD_TABLE[(data & 0x0F) as usize];
+            },
+            _ => {
+                self.length_counter.load(data >> 3);
+                self.envelope.start = true;
+            },
+        }
+    }
+    // clocked every CPU cycle, the period table already counts CPU cycles
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        }else {
+            self.timer -= 1;
+        }
+    }
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+    }
+    pub fn output(&self) -> u8 {
+        if !self.length_counter.active() || self.shift_register & 1 != 0 {
+            0
+        }else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl Snapshot for Noise {
+    fn save_state(&self,state: &mut StateWriter) {
+        state.u16(self.shift_register);
+        state.bool(self.short_mode);
+        state.u16(self.timer_period);
+        state.u16(self.timer);
+        self.length_counter.save_state(state);
+        self.envelope.save_state(state);
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        self.shift_register = state.u16()?;
+        self.short_mode = state.bool()?;
+        self.timer_period = state.u16()?;
+        state.ensure(PERIOD_TABLE.contains(&self.timer_period))?;
+        self.timer = state.u16()?;
+        self.length_counter.load_state(state)?;
+        self.envelope.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::{load, save};
+    fn sequence_length(short_mode: bool) -> usize {
+        let mut noise = Noise::new();
+        noise.short_mode = short_mode;
+        let mut steps = 0;
+        loop {
+            noise.timer = 0;
+            noise.clock_timer();
+            steps += 1;
+            if noise.shift_register == 1 {
+                return steps;
+            }
+        }
+    }
+    #[test]
+    fn test_lfsr_periods() {
+        assert_eq!(sequence_length(false),32767);
+        assert_eq!(sequence_length(true),93);
+    }
+    #[test]
+    fn test_load_state() {
+        // whatever a live channel saves loads again, for every period
+        for index in 0..16 {
+            let mut noise = Noise::new();
+            noise.write_register(0x400E,0x80 | index);
+            noise.write_register(0x400F,0x08);
+            for _ in 0..1000 {
+                noise.clock_timer();
+            }
+            let data = save(&noise,0);
+            let mut loaded = Noise::new();
+            load(&mut loaded,0,&data).unwrap();
+            assert_eq!(save(&loaded,0),data);
+        }
+        // timer_period follows the 14 byte header, the shift register and the mode
+        let mut corrupt = save(&Noise::new(),0);
+        corrupt[17..19].copy_from_slice(&5u16.to_le_bytes());
+        assert!(matches!(load(&mut Noise::new(),0,&corrupt),Err(StateError::Corrupt { .. })));
+    }
+}
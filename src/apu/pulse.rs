@@ -0,0 +1,194 @@
+//! Pulse channels, 0x4000-0x4003 and 0x4004-0x4007.
+//!
+//! 0x4000 DDLC VVVV duty, length counter halt / envelope loop, constant volume, volume / envelope period
+//! 0x4001 EPPP NSSS sweep enable, period, negate, shift
+//! 0x4002 LLLL LLLL timer low
+//! 0x4003 LLLL LHHH length counter load, timer high
+//!
+//! The timer is clocked every other CPU cycle and steps an 8 step duty sequence.
+
+use super::units::{Envelope, LengthCounter};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+static DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+pub struct Pulse {
+    // pulse 1 negates the sweep change with ones' complement, pulse 2 with two's complement
+    ones_complement: bool,
+    duty: u8,
+    sequence_step: u8,
+    timer_period: u16,
+    timer: u16,
+    pub length_counter: LengthCounter,
+    envelope: Envelope,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement,
+            duty: 0,
+            sequence_step: 0,
+            timer_period: 0,
+            timer: 0,
+            length_counter: LengthCounter::default(),
+            envelope: Envelope::default(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+    pub fn write_register(&mut self,register: u16,data: u8) {
+        match register & 0b11 {
+            0 => {
+                self.duty = data >> 6;
+                self.length_counter.halt = data & 0b0010_0000 != 0;
+                self.envelope.write_control(data);
+            },
+            1 => {
+                self.sweep_enabled = data & 0b1000_0000 != 0;
+                self.sweep_period = (data >> 4) & 0b111;
+                self.sweep_negate = data & 0b0000_1000 != 0;
+                self.sweep_shift = data & 0b111;
+                self.sweep_reload = true;
+            },
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | (((data & 0b111) as u16) << 8);
+                self.length_counter.load(data >> 3);
+                self.sequence_step = 0;
+                self.envelope.start = true;
+            },
+        }
+    }
+    // clocked every APU cycle, i.e. every other CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_step = (self.sequence_step + 1) % 8;
+        }else {
+            self.timer -= 1;
+        }
+    }
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.sweep_muted() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        }else {
+            self.sweep_divider -= 1;
+        }
+    }
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.timer_period + change
+        }else if self.ones_complement {
+            self.timer_period.saturating_sub(change + 1)
+        }else {
+            self.timer_period.saturating_sub(change)
+        }
+    }
+    // the sweep unit mutes the channel even when it is disabled
+    fn sweep_muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x7FF
+    }
+    pub fn output(&self) -> u8 {
+        if !self.length_counter.active() || self.sweep_muted() || DUTY_TABLE[self.duty as usize][self.sequence_step as usize] == 0 {
+            0
+        }else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl Snapshot for Pulse {
+    fn save_state(&self,state: &mut StateWriter) {
+        state.u8(self.duty);
+        state.u8(self.sequence_step);
+        state.u16(self.timer_period);
+        state.u16(self.timer);
+        self.length_counter.save_state(state);
+        self.envelope.save_state(state);
+        state.bool(self.sweep_enabled);
+        state.u8(self.sweep_period);
+        state.bool(self.sweep_negate);
+        state.u8(self.sweep_shift);
+        state.u8(self.sweep_divider);
+        state.bool(self.sweep_reload);
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        self.duty = state.u8()?;
+        self.sequence_step = state.u8()?;
+        state.ensure(self.duty < 4 && self.sequence_step < 8)?;
+        self.timer_period = state.u16()?;
+        self.timer = state.u16()?;
+        self.length_counter.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.sweep_enabled = state.bool()?;
+        self.sweep_period = state.u8()?;
+        self.sweep_negate = state.bool()?;
+        self.sweep_shift = state.u8()?;
+        self.sweep_divider = state.u8()?;
+        self.sweep_reload = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_sweep_negate_differs_between_channels() {
+        let mut pulse_1 = Pulse::new(true);
+        let mut pulse_2 = Pulse::new(false);
+        for pulse in [&mut pulse_1,&mut pulse_2] {
+            pulse.length_counter.set_enabled(true);
+            // enabled, period 0, negate, shift 1
+            pulse.write_register(1,0b1000_1001);
+            pulse.write_register(2,0x00);
+            pulse.write_register(3,0x01);
+            pulse.clock_half_frame();
+        }
+        assert_eq!(pulse_1.timer_period,0x100 - 0x80 - 1);
+        assert_eq!(pulse_2.timer_period,0x100 - 0x80);
+    }
+    #[test]
+    fn test_low_period_is_muted() {
+        let mut pulse = Pulse::new(true);
+        pulse.length_counter.set_enabled(true);
+        pulse.write_register(0,0b0011_1111);
+        pulse.write_register(2,0x07);
+        pulse.write_register(3,0x08);
+        assert!((0..8).all(|_| {
+            pulse.clock_timer();
+            pulse.output() == 0
+        }));
+        pulse.write_register(2,0x08);
+        let outputs: Vec<u8> = (0..8 * 9).map(|_| {
+            pulse.clock_timer();
+            pulse.output()
+        }).collect();
+        assert!(outputs.contains(&15));
+    }
+}
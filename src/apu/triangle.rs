@@ -0,0 +1,100 @@
+//! Triangle channel, 0x4008-0x400B.
+//!
+//! 0x4008 CRRR RRRR length counter halt / linear counter control, linear counter reload value
+//! 0x400A LLLL LLLL timer low
+//! 0x400B LLLL LHHH length counter load, timer high
+//!
+//! The timer is clocked every CPU cycle and steps a 32 step triangle, it only advances while
+//! both the length counter and the linear counter are non zero.
+
+use super::units::LengthCounter;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+static SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+#[derive(Default)]
+pub struct Triangle {
+    sequence_step: u8,
+    timer_period: u16,
+    timer: u16,
+    pub length_counter: LengthCounter,
+    linear_counter: u8,
+    linear_counter_period: u8,
+    linear_counter_reload: bool,
+    // same bit as the length counter halt flag
+    control: bool,
+}
+
+impl Triangle {
+    pub fn write_register(&mut self,register: u16,data: u8) {
+        match register & 0b11 {
+            0 => {
+                self.control = data & 0b1000_0000 != 0;
+                self.length_counter.halt = self.control;
+                self.linear_counter_period = data & 0b0111_1111;
+            },
+            1 => {},
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | (((data & 0b111) as u16) << 8);
+                self.length_counter.load(data >> 3);
+                self.linear_counter_reload = true;
+            },
+        }
+    }
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length_counter.active() && self.linear_counter > 0 {
+                self.sequence_step = (self.sequence_step + 1) % 32;
+            }
+        }else {
+            self.timer -= 1;
+        }
+    }
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_counter_reload {
+            self.linear_counter = self.linear_counter_period;
+        }else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_counter_reload = false;
+        }
+    }
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+    }
+    // a halted triangle keeps outputting its current step instead of dropping to 0
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.sequence_step as usize]
+    }
+}
+
+impl Snapshot for Triangle {
+    fn save_state(&self,state: &mut StateWriter) {
+        state.u8(self.sequence_step);
+        state.u16(self.timer_period);
+        state.u16(self.timer);
+        self.length_counter.save_state(state);
+        state.u8(self.linear_counter);
+        state.u8(self.linear_counter_period);
+        state.bool(self.linear_counter_reload);
+        state.bool(self.control);
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        self.sequence_step = state.u8()?;
+        state.ensure((self.sequence_step as usize) < SEQUENCE.len())?;
+        self.timer_period = state.u16()?;
+        self.timer = state.u16()?;
+        self.length_counter.load_state(state)?;
+        self.linear_counter = state.u8()?;
+        self.linear_counter_period = state.u8()?;
+        self.linear_counter_reload = state.bool()?;
+        self.control = state.bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+//! Building blocks shared by several channels: the length counter and the volume envelope.
+
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// length counter load values, indexed by bits 3-7 of the fourth channel register
+pub static LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// silences a channel once it counts down to 0, clocked by the half frame signal
+#[derive(Default)]
+pub struct LengthCounter {
+    pub counter: u8,
+    pub enabled: bool,
+    pub halt: bool,
+}
+
+impl LengthCounter {
+    pub fn load(&mut self,index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index & 0x1F) as usize];
+        }
+    }
+    pub fn set_enabled(&mut self,enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+    pub fn clock(&mut self) {
+        if self.counter > 0 && !self.halt {
+            self.counter -= 1;
+        }
+    }
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+// either a constant volume or a sawtooth decaying from 15, clocked by the quarter frame signal
+#[derive(Default)]
+pub struct Envelope {
+    pub start: bool,
+    pub looping: bool,
+    pub constant_volume: bool,
+    // the constant volume, and the divider period of the decay
+    pub volume: u8,
+    divider: u8,
+    decay_level: u8,
+}
+
+impl Envelope {
+    // bits 0-5 of the first channel register, bit 5 doubles as the length counter halt flag
+    pub fn write_control(&mut self,data: u8) {
+        self.looping = data & 0b0010_0000 != 0;
+        self.constant_volume = data & 0b0001_0000 != 0;
+        self.volume = data & 0b0000_1111;
+    }
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay_level = 15;
+            self.divider = self.volume;
+        }else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay_level > 0 {
+                self.decay_level -= 1;
+            }else if self.looping {
+                self.decay_level = 15;
+            }
+        }else {
+            self.divider -= 1;
+        }
+    }
+    pub fn output(&self) -> u8 {
+        if self.constant_volume { self.volume } else { self.decay_level }
+    }
+}
+
+impl Snapshot for LengthCounter {
+    fn save_state(&self,state: &mut StateWriter) {
+        state.u8(self.counter);
+        state.bool(self.enabled);
+        state.bool(self.halt);
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        self.counter = state.u8()?;
+        self.enabled = state.bool()?;
+        self.halt = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Envelope {
+    fn save_state(&self,state: &mut StateWriter) {
+        state.bool(self.start);
+        state.bool(self.looping);
+        state.bool(self.constant_volume);
+        state.u8(self.volume);
+        state.u8(self.divider);
+        state.u8(self.decay_level);
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        self.start = state.bool()?;
+        self.looping = state.bool()?;
+        self.constant_volume = state.bool()?;
+        self.volume = state.u8()?;
+        self.divider = state.u8()?;
+        self.decay_level = state.u8()?;
+        Ok(())
+    }
+}
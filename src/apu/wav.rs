@@ -0,0 +1,44 @@
+//! WAV encoding of the mixer output: RIFF container, 16 bit mono PCM.
+//!
+//! The mixer produces samples in [0.0, 1.0], they are scaled to [0, 32767] as they are,
+//! so silence stays at 0.
+
+pub fn encode_wav(samples: &[f32],sample_rate: u32) -> Vec<u8> {
+    let data_size = samples.len() as u32 * 2;
+    let mut wav = Vec::with_capacity(44 + data_size as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, 1 channel
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    // byte rate, block align, bits per sample
+    wav.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_size.to_le_bytes());
+    for sample in samples {
+        let value = (sample.clamp(0.0,1.0) * i16::MAX as f32) as i16;
+        wav.extend_from_slice(&value.to_le_bytes());
+    }
+    wav
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_wav_header_and_samples() {
+        let wav = encode_wav(&[0.0,1.0,0.5],44_100);
+        assert_eq!(wav.len(),44 + 6);
+        assert_eq!(&wav[..4],b"RIFF");
+        assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()),42);
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()),44_100);
+        assert_eq!(&wav[36..40],b"data");
+        assert_eq!(&wav[44..],&[0x00, 0x00, 0xFF, 0x7F, 0xFF, 0x3F]);
+    }
+}
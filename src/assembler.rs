@@ -0,0 +1,618 @@
+//! Two pass 6502 assembler, the opcode table of the selected CPU variant is searched in reverse
+//! to find the opcode of every mnemonic and addressing mode.
+//!
+//! Source syntax, one statement per line, `;` starts a comment:
+//!
+//! label:               defines label as the current address, may be followed by a statement
+//! name = expr          defines a constant, everything it uses must be defined above it
+//! .org expr            continues assembling at expr, starting a new segment
+//! .byte expr,"text"    bytes and ASCII strings
+//! .word expr           little endian words
+//! LDA ($20),Y          instructions, mnemonics are case insensitive
+//!
+//! Operands: A  #expr  expr  expr,X  expr,Y  (expr)  (expr,X)  (expr),Y
+//! Zero page modes are picked when the address is known to fit in a byte by the time the
+//! instruction is reached, forward references get the absolute mode. Branch operands are the
+//! target address, the assembler works out the offset.
+//!
+//! Expressions: $hex, %binary, decimal, 'c', symbols and * for the address of the current statement,
+//! with unary - ~ < (low byte) > (high byte) and the binary operators * / % + - << >> & ^ |
+//! in the usual C precedence, grouped with parentheses.
+
+use std::collections::HashMap;
+use std::fmt;
+use crate::addressing_modes::AddrMode;
+use crate::bus::Bus;
+use crate::cpu::CpuVariant;
+use crate::ops_codes::{opcode_table, OpCode};
+
+pub const DEFAULT_ORIGIN: u16 = 0x8000;
+
+#[derive(Debug, PartialEq)]
+pub enum AsmError {
+    Syntax { line: usize, message: String },
+    // the mnemonic does not exist, or not with this addressing mode, on the selected variant
+    InvalidInstruction { line: usize, instruction: String },
+    UndefinedSymbol { line: usize, name: String },
+    DuplicateSymbol { line: usize, name: String },
+    // a value that does not fit its operand, or a branch target further than -128..127 bytes away
+    OutOfRange { line: usize, value: i64 },
+    // an expression divides by zero or takes a remainder modulo zero
+    DivisionByZero { line: usize },
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self,f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmError::Syntax { line, message } => write!(f,"line {}: {}",line,message),
+            AsmError::InvalidInstruction { line, instruction } => write!(f,"line {}: invalid instruction {}",line,instruction),
+            AsmError::UndefinedSymbol { line, name } => write!(f,"line {}: undefined symbol {}",line,name),
+            AsmError::DuplicateSymbol { line, name } => write!(f,"line {}: symbol {} is already defined",line,name),
+            AsmError::OutOfRange { line, value } => write!(f,"line {}: value {} is out of range",line,value),
+            AsmError::DivisionByZero { line } => write!(f,"line {}: division by zero",line),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Bytes assembled to consecutive addresses starting at `origin`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Segment {
+    pub origin: u16,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    /// Writes the segment into memory, e.g. to patch a running program.
+    pub fn write_to<B: Bus>(&self,bus: &mut B) {
+        for (i,byte) in self.data.iter().enumerate() {
+            bus.write(self.origin.wrapping_add(i as u16),*byte);
+        }
+    }
+}
+
+/// Assembles `source` into one segment per `.org`, code before the first `.org` goes to `DEFAULT_ORIGIN`.
+pub fn assemble(source: &str,variant: CpuVariant) -> Result<Vec<Segment>,AsmError> {
+    let statements = source.lines().enumerate()
+        .map(|(i,text)| parse_line(i + 1,text))
+        .collect::<Result<Vec<_>,_>>()?;
+    let mut assembler = Assembler { variant, symbols: HashMap::new(), modes: vec![None; statements.len()] };
+    assembler.pass(&statements,false)?;
+    assembler.pass(&statements,true)
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Number(i64),
+    Symbol(String),
+    // the address of the statement
+    Pc,
+    Unary(char,Box<Expr>),
+    Binary(&'static str,Box<Expr>,Box<Expr>),
+}
+
+// why an expression has no value
+#[derive(Debug)]
+enum Unresolved {
+    // the first symbol that is still undefined
+    Symbol(String),
+    DivisionByZero,
+}
+
+impl Unresolved {
+    fn into_error(self,line: usize) -> AsmError {
+        match self {
+            Unresolved::Symbol(name) => AsmError::UndefinedSymbol { line, name },
+            Unresolved::DivisionByZero => AsmError::DivisionByZero { line },
+        }
+    }
+}
+
+impl Expr {
+    fn eval(&self,symbols: &HashMap<String,i64>,pc: u16) -> Result<i64,Unresolved> {
+        Ok(match self {
+            Expr::Number(value) => *value,
+            Expr::Symbol(name) => *symbols.get(name).ok_or_else(|| Unresolved::Symbol(name.clone()))?,
+            Expr::Pc => pc as i64,
+            Expr::Unary(op,expr) => {
+                let value = expr.eval(symbols,pc)?;
+                match op {
+                    '-' => -value,
+                    '~' => !value,
+                    '<' => value & 0xFF,
+                    _ => (value >> 8) & 0xFF,
+                }
+            },
+            Expr::Binary(op,left,right) => {
+                let (left,right) = (left.eval(symbols,pc)?,right.eval(symbols,pc)?);
+                match *op {
+                    "*" => left.wrapping_mul(right),
+                    "/" => left.checked_div(right).ok_or(Unresolved::DivisionByZero)?,
+                    "%" => left.checked_rem(right).ok_or(Unresolved::DivisionByZero)?,
+                    "+" => left.wrapping_add(right),
+                    "-" => left.wrapping_sub(right),
+                    "<<" => left.wrapping_shl(right as u32),
+                    ">>" => left.wrapping_shr(right as u32),
+                    "&" => left & right,
+                    "^" => left ^ right,
+                    _ => left | right,
+                }
+            },
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    Direct(Expr),
+    DirectX(Expr),
+    DirectY(Expr),
+    Indirect(Expr),
+    IndirectX(Expr),
+    IndirectY(Expr),
+}
+
+#[derive(Debug)]
+enum Data {
+    Expr(Expr),
+    Text(String),
+}
+
+#[derive(Debug)]
+enum Kind {
+    Empty,
+    Constant(String,Expr),
+    Org(Expr),
+    Byte(Vec<Data>),
+    Word(Vec<Expr>),
+    Instruction(String,Operand),
+}
+
+#[derive(Debug)]
+struct Statement {
+    line: usize,
+    label: Option<String>,
+    kind: Kind,
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn is_symbol(text: &str) -> bool {
+    text.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') && text.chars().all(is_symbol_char)
+}
+
+// the comment starts at the first ; outside of quotes
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    for (i,c) in text.char_indices() {
+        match (quote,c) {
+            (None,';') => return &text[..i],
+            (None,'"' | '\'') => quote = Some(c),
+            (Some(q),_) if q == c => quote = None,
+            _ => {},
+        }
+    }
+    text
+}
+
+// splits on commas outside of quotes and parentheses
+fn split_list(text: &str) -> Vec<&str> {
+    let (mut parts,mut start,mut depth,mut quote) = (Vec::new(),0,0,None);
+    for (i,c) in text.char_indices() {
+        match (quote,c) {
+            (None,'"' | '\'') => quote = Some(c),
+            (Some(q),_) if q == c => quote = None,
+            (None,'(') => depth += 1,
+            (None,')') => depth -= 1,
+            (None,',') if depth == 0 => {
+                parts.push(text[start..i].trim());
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    parts.push(text[start..].trim());
+    parts
+}
+
+fn parse_line(line: usize,text: &str) -> Result<Statement,AsmError> {
+    let syntax = |message: &str| AsmError::Syntax { line, message: message.to_string() };
+    let mut text = strip_comment(text).trim();
+    let mut label = None;
+    if let Some((name,rest)) = text.split_once(':') {
+        if is_symbol(name.trim()) {
+            label = Some(name.trim().to_string());
+            text = rest.trim();
+        }
+    }
+    let kind = if text.is_empty() {
+        Kind::Empty
+    }else if let Some((name,value)) = text.split_once('=').filter(|(name,_)| is_symbol(name.trim())) {
+        Kind::Constant(name.trim().to_string(),parse_expr(line,value)?)
+    }else if let Some(directive) = text.strip_prefix('.') {
+        let (name,args) = directive.split_once(char::is_whitespace).unwrap_or((directive,""));
+        match name.to_ascii_lowercase().as_str() {
+            "org" => Kind::Org(parse_expr(line,args)?),
+            "byte" => Kind::Byte(split_list(args).into_iter().map(|item| {
+                match item.strip_prefix('"').and_then(|item| item.strip_suffix('"')) {
+                    Some(text) => Ok(Data::Text(text.to_string())),
+                    None => parse_expr(line,item).map(Data::Expr),
+                }
+            }).collect::<Result<_,_>>()?),
+            "word" => Kind::Word(split_list(args).into_iter().map(|item| parse_expr(line,item)).collect::<Result<_,_>>()?),
+            _ => return Err(syntax(&format!("unknown directive .{}",name))),
+        }
+    }else {
+        let (mnemonic,operand) = text.split_once(char::is_whitespace).unwrap_or((text,""));
+        if !mnemonic.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(syntax(&format!("expected an instruction, found {}",mnemonic)));
+        }
+        Kind::Instruction(mnemonic.to_ascii_uppercase(),parse_operand(line,operand.trim())?)
+    };
+    Ok(Statement { line, label, kind })
+}
+
+fn parse_operand(line: usize,text: &str) -> Result<Operand,AsmError> {
+    let upper = text.to_ascii_uppercase().replace(' ',"");
+    if text.is_empty() {
+        return Ok(Operand::None);
+    }
+    if upper == "A" {
+        return Ok(Operand::Accumulator);
+    }
+    if let Some(expr) = text.strip_prefix('#') {
+        return Ok(Operand::Immediate(parse_expr(line,expr)?));
+    }
+    let strip_index = |suffix: &str| upper.ends_with(suffix).then(|| text[..text.to_ascii_uppercase().rfind(',').unwrap_or(0)].trim());
+    if text.starts_with('(') {
+        if let Some(inner) = strip_index(",Y").and_then(|inner| inner.strip_prefix('(')?.strip_suffix(')')) {
+            return Ok(Operand::IndirectY(parse_expr(line,inner)?));
+        }
+        if upper.ends_with(",X)") {
+            let inner = &text[1..text.to_ascii_uppercase().rfind(',').unwrap_or(1)];
+            return Ok(Operand::IndirectX(parse_expr(line,inner)?));
+        }
+        if let Some(inner) = text.strip_prefix('(').and_then(|inner| inner.strip_suffix(')')) {
+            return Ok(Operand::Indirect(parse_expr(line,inner)?));
+        }
+    }
+    if let Some(expr) = strip_index(",X") {
+        return Ok(Operand::DirectX(parse_expr(line,expr)?));
+    }
+    if let Some(expr) = strip_index(",Y") {
+        return Ok(Operand::DirectY(parse_expr(line,expr)?));
+    }
+    Ok(Operand::Direct(parse_expr(line,text)?))
+}
+
+struct ExprParser<'a> {
+    line: usize,
+    chars: Vec<char>,
+    pos: usize,
+    text: &'a str,
+}
+
+// binary operators from the loosest to the tightest binding
+static PRECEDENCE: [&[&str]; 6] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+
+fn parse_expr(line: usize,text: &str) -> Result<Expr,AsmError> {
+    let mut parser = ExprParser { line, chars: text.chars().collect(), pos: 0, text };
+    let expr = parser.binary(0)?;
+    parser.skip_spaces();
+    if parser.pos != parser.chars.len() {
+        return Err(parser.error());
+    }
+    Ok(expr)
+}
+
+impl ExprParser<'_> {
+    fn error(&self) -> AsmError {
+        AsmError::Syntax { line: self.line, message: format!("invalid expression {}",self.text.trim()) }
+    }
+    fn skip_spaces(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+    fn eat(&mut self,token: &str) -> bool {
+        self.skip_spaces();
+        let matches = token.chars().enumerate().all(|(i,c)| self.chars.get(self.pos + i) == Some(&c));
+        if matches {
+            self.pos += token.len();
+        }
+        matches
+    }
+    fn binary(&mut self,level: usize) -> Result<Expr,AsmError> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        'operators: loop {
+            for op in PRECEDENCE[level] {
+                if self.eat(op) {
+                    let right = self.binary(level + 1)?;
+                    left = Expr::Binary(op,Box::new(left),Box::new(right));
+                    continue 'operators;
+                }
+            }
+            return Ok(left);
+        }
+    }
+    fn unary(&mut self) -> Result<Expr,AsmError> {
+        for op in ['-','~','<','>'] {
+            if self.eat(&op.to_string()) {
+                return Ok(Expr::Unary(op,Box::new(self.unary()?)));
+            }
+        }
+        self.primary()
+    }
+    fn take_while<F: Fn(char) -> bool>(&mut self,accept: F) -> String {
+        let start = self.pos;
+        while self.chars.get(self.pos).is_some_and(|c| accept(*c)) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+    fn number(&mut self,radix: u32) -> Result<Expr,AsmError> {
+        let digits = self.take_while(|c| c.is_digit(radix));
+        i64::from_str_radix(&digits,radix).map(Expr::Number).map_err(|_| self.error())
+    }
+    fn primary(&mut self) -> Result<Expr,AsmError> {
+        if self.eat("(") {
+            let expr = self.binary(0)?;
+            return if self.eat(")") { Ok(expr) } else { Err(self.error()) };
+        }
+        if self.eat("*") {
+            return Ok(Expr::Pc);
+        }
+        if self.eat("$") {
+            return self.number(16);
+        }
+        if self.eat("%") {
+            return self.number(2);
+        }
+        if self.eat("'") {
+            let c = *self.chars.get(self.pos).ok_or(self.error())?;
+            self.pos += 1;
+            return if self.eat("'") { Ok(Expr::Number(c as i64)) } else { Err(self.error()) };
+        }
+        match self.chars.get(self.pos) {
+            Some(c) if c.is_ascii_digit() => self.number(10),
+            Some(c) if c.is_ascii_alphabetic() || *c == '_' => Ok(Expr::Symbol(self.take_while(is_symbol_char))),
+            _ => Err(self.error()),
+        }
+    }
+}
+
+struct Assembler {
+    variant: CpuVariant,
+    symbols: HashMap<String,i64>,
+    // addressing mode picked for every statement in the first pass, the second one must emit the same sizes
+    modes: Vec<Option<AddrMode>>,
+}
+
+impl Assembler {
+    // the official opcode wins when several encode the same instruction, then the lowest one
+    fn find(&self,mnemonic: &str,mode: AddrMode) -> Option<&'static OpCode> {
+        opcode_table(self.variant).values()
+            .filter(|ops_code| ops_code.assembler == mnemonic && ops_code.addressing_mode == mode)
+            .min_by_key(|ops_code| (!ops_code.official,ops_code.opc))
+    }
+    fn has(&self,mnemonic: &str,mode: AddrMode) -> bool {
+        self.find(mnemonic,mode).is_some()
+    }
+    // zero page when the value is already known to fit, absolute otherwise
+    fn pick_direct(&self,mnemonic: &str,value: Option<i64>,zero_page: AddrMode,absolute: AddrMode) -> AddrMode {
+        let fits = value.is_some_and(|value| (0..=0xFF).contains(&value));
+        if self.has(mnemonic,zero_page) && (fits || !self.has(mnemonic,absolute)) { zero_page } else { absolute }
+    }
+    fn pick_mode(&self,mnemonic: &str,operand: &Operand,pc: u16) -> AddrMode {
+        let value = |expr: &Expr| expr.eval(&self.symbols,pc).ok();
+        match operand {
+            Operand::None if self.has(mnemonic,AddrMode::Accumulator) && !self.has(mnemonic,AddrMode::Implied) => AddrMode::Accumulator,
+            Operand::None => AddrMode::Implied,
+            Operand::Accumulator => AddrMode::Accumulator,
+            Operand::Immediate(_) => AddrMode::Immediate,
+            Operand::Direct(_) if self.has(mnemonic,AddrMode::Relative) => AddrMode::Relative,
+            Operand::Direct(expr) => self.pick_direct(mnemonic,value(expr),AddrMode::ZeroPage,AddrMode::Absolute),
+            Operand::DirectX(expr) => self.pick_direct(mnemonic,value(expr),AddrMode::ZeroPageX,AddrMode::AbsoluteX),
+            Operand::DirectY(expr) => self.pick_direct(mnemonic,value(expr),AddrMode::ZeroPageY,AddrMode::AbsoluteY),
+            Operand::Indirect(_) if self.has(mnemonic,AddrMode::Indirect) => AddrMode::Indirect,
+            Operand::Indirect(_) => AddrMode::ZeroPageIndirect,
+            Operand::IndirectX(_) if self.has(mnemonic,AddrMode::IndirectX) => AddrMode::IndirectX,
+            Operand::IndirectX(_) => AddrMode::AbsoluteIndirectX,
+            Operand::IndirectY(_) => AddrMode::IndirectY,
+        }
+    }
+    fn define(&mut self,line: usize,name: &str,value: i64) -> Result<(),AsmError> {
+        if self.symbols.insert(name.to_string(),value).is_some() {
+            return Err(AsmError::DuplicateSymbol { line, name: name.to_string() });
+        }
+        Ok(())
+    }
+    // in the first pass undefined symbols evaluate to 0, the second pass reports them
+    fn eval(&self,line: usize,expr: &Expr,pc: u16,resolve: bool) -> Result<i64,AsmError> {
+        match expr.eval(&self.symbols,pc) {
+            Ok(value) => Ok(value),
+            Err(Unresolved::Symbol(_)) if !resolve => Ok(0),
+            Err(unresolved) => Err(unresolved.into_error(line)),
+        }
+    }
+    fn pass(&mut self,statements: &[Statement],resolve: bool) -> Result<Vec<Segment>,AsmError> {
+        let mut segments = vec![Segment { origin: DEFAULT_ORIGIN, data: Vec::new() }];
+        let mut pc = DEFAULT_ORIGIN;
+        for (index,statement) in statements.iter().enumerate() {
+            let line = statement.line;
+            if let Some(label) = &statement.label {
+                if !resolve {
+                    self.define(line,label,pc as i64)?;
+                }
+            }
+            let mut out = Vec::new();
+            match &statement.kind {
+                Kind::Empty => {},
+                Kind::Constant(name,expr) => {
+                    if !resolve {
+                        let value = expr.eval(&self.symbols,pc).map_err(|unresolved| unresolved.into_error(line))?;
+                        self.define(line,name,value)?;
+                    }
+                },
+                Kind::Org(expr) => {
+                    let origin = expr.eval(&self.symbols,pc).map_err(|unresolved| unresolved.into_error(line))?;
+                    pc = u16::try_from(origin).map_err(|_| AsmError::OutOfRange { line, value: origin })?;
+                    if segments.last().is_some_and(|segment| segment.data.is_empty()) {
+                        segments.pop();
+                    }
+                    segments.push(Segment { origin: pc, data: Vec::new() });
+                },
+                Kind::Byte(items) => {
+                    for item in items {
+                        match item {
+                            Data::Text(text) => out.extend(text.bytes()),
+                            Data::Expr(expr) => out.push(to_byte(line,self.eval(line,expr,pc,resolve)?)?),
+                        }
+                    }
+                },
+                Kind::Word(items) => {
+                    for expr in items {
+                        out.extend(to_word(line,self.eval(line,expr,pc,resolve)?)?.to_le_bytes());
+                    }
+                },
+                Kind::Instruction(mnemonic,operand) => {
+                    let mode = match self.modes[index] {
+                        Some(mode) => mode,
+                        None => self.pick_mode(mnemonic,operand,pc),
+                    };
+                    self.modes[index] = Some(mode);
+                    let ops_code = self.find(mnemonic,mode).ok_or_else(|| AsmError::InvalidInstruction {
+                        line,
+                        instruction: format!("{} {:?}",mnemonic,mode),
+                    })?;
+                    out.push(ops_code.opc);
+                    let expr = match operand {
+                        Operand::Immediate(expr) | Operand::Direct(expr) | Operand::DirectX(expr) | Operand::DirectY(expr)
+                            | Operand::Indirect(expr) | Operand::IndirectX(expr) | Operand::IndirectY(expr) => Some(expr),
+                        Operand::None | Operand::Accumulator => None,
+                    };
+                    let value = match expr {
+                        Some(expr) => self.eval(line,expr,pc,resolve)?,
+                        None => 0,
+                    };
+                    match (mode,ops_code.bytes) {
+                        (AddrMode::Relative,_) if resolve => {
+                            let offset = value - (pc as i64 + 2);
+                            if !(-128..=127).contains(&offset) {
+                                return Err(AsmError::OutOfRange { line, value: offset });
+                            }
+                            out.push(offset as u8);
+                        },
+                        (_,2) if resolve => out.push(to_byte(line,value)?),
+                        (_,3) => out.extend(to_word(line,value)?.to_le_bytes()),
+                        // the first pass only needs the size, the operand may still be unresolved
+                        (_,bytes) => out.extend(std::iter::repeat_n(0,bytes as usize - 1)),
+                    }
+                },
+            }
+            pc = pc.wrapping_add(out.len() as u16);
+            segments.last_mut().unwrap().data.extend(out);
+        }
+        segments.retain(|segment| !segment.data.is_empty());
+        Ok(segments)
+    }
+}
+
+// negative values are stored in two's complement
+fn to_byte(line: usize,value: i64) -> Result<u8,AsmError> {
+    if (-0x80..=0xFF).contains(&value) { Ok(value as u8) } else { Err(AsmError::OutOfRange { line, value }) }
+}
+
+fn to_word(line: usize,value: i64) -> Result<u16,AsmError> {
+    if (-0x8000..=0xFFFF).contains(&value) { Ok(value as u16) } else { Err(AsmError::OutOfRange { line, value }) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn asm(source: &str) -> Vec<u8> {
+        let segments = assemble(source,CpuVariant::Ricoh2A03).unwrap();
+        assert_eq!(segments.len(),1);
+        segments[0].data.clone()
+    }
+    #[test]
+    fn test_addressing_modes() {
+        assert_eq!(asm("
+            LDA #$10
+            lda $20
+            LDA $20,X
+            LDX $20,Y
+            LDA $1234
+            LDA $1234,X
+            LDA $20,Y      ; no zero page Y form for LDA
+            JMP ($FFFC)
+            LDA ($20,X)
+            LDA ($20),Y
+            ASL A
+            ASL
+            INX
+        "),vec![
+            0xA9, 0x10, 0xA5, 0x20, 0xB5, 0x20, 0xB6, 0x20, 0xAD, 0x34, 0x12, 0xBD, 0x34, 0x12, 0xB9, 0x20, 0x00,
+            0x6C, 0xFC, 0xFF, 0xA1, 0x20, 0xB1, 0x20, 0x0A, 0x0A, 0xE8,
+        ]);
+    }
+    #[test]
+    fn test_labels_branches_and_expressions() {
+        let source = "
+            ptr = $10
+            count = 8 * 2 - 1
+            .org $C000
+            start:  LDX #count
+            loop:   DEX
+                    STA ptr+1,X
+                    BNE loop
+                    LDA #<table
+                    LDA #>table
+                    BEQ done       ; forward branch
+                    JMP start
+            done:   LDA data       ; forward reference, absolute even though it would fit
+            table:  .word start, done+1
+                    .byte 1, -1, \"Hi\", 'a' | $80, %1010
+            data = $00F0
+            missing: .word nowhere
+        ";
+        assert_eq!(assemble(source,CpuVariant::Ricoh2A03),Err(AsmError::UndefinedSymbol { line: 17, name: "nowhere".to_string() }));
+        let segments = assemble(&source.replace("missing: .word nowhere",""),CpuVariant::Ricoh2A03).unwrap();
+        assert_eq!(segments,vec![Segment { origin: 0xC000, data: vec![
+            0xA2, 0x0F, 0xCA, 0x95, 0x11, 0xD0, 0xFB, 0xA9, 0x13, 0xA9, 0xC0, 0xF0, 0x03, 0x4C, 0x00, 0xC0,
+            0xAD, 0xF0, 0x00, 0x00, 0xC0, 0x11, 0xC0, 0x01, 0xFF, b'H', b'i', 0xE1, 0x0A,
+        ] }]);
+    }
+    #[test]
+    fn test_segments_and_variants() {
+        let segments = assemble(".org $0200\nBRA next\nnext: LDA ($20)\n.org $FFFC\n.word $0200",CpuVariant::Cmos65C02).unwrap();
+        assert_eq!(segments,vec![
+            Segment { origin: 0x0200, data: vec![0x80, 0x00, 0xB2, 0x20] },
+            Segment { origin: 0xFFFC, data: vec![0x00, 0x02] },
+        ]);
+        // BRA and ($LL) do not exist on the NMOS parts, unofficial opcodes do
+        assert!(matches!(assemble("BRA *",CpuVariant::Ricoh2A03),Err(AsmError::InvalidInstruction { line: 1, .. })));
+        assert_eq!(asm("LAX $10\nSBC #1\nNOP #1"),vec![0xA7, 0x10, 0xE9, 0x01, 0x80, 0x01]);
+    }
+    #[test]
+    fn test_errors() {
+        let error = |source: &str| assemble(source,CpuVariant::Ricoh2A03).unwrap_err();
+        assert_eq!(error("here: BNE here + 200"),AsmError::OutOfRange { line: 1, value: 198 });
+        assert_eq!(error("LDA #$100"),AsmError::OutOfRange { line: 1, value: 0x100 });
+        assert_eq!(error("a: NOP\na: NOP"),AsmError::DuplicateSymbol { line: 2, name: "a".to_string() });
+        assert!(matches!(error("LDA #(1"),AsmError::Syntax { line: 1, .. }));
+        assert!(matches!(error(".dw 1"),AsmError::Syntax { line: 1, .. }));
+        assert!(matches!(error("STA #1"),AsmError::InvalidInstruction { line: 1, .. }));
+        assert_eq!(error("size = 4\n.byte size/0"),AsmError::DivisionByZero { line: 2 });
+        assert_eq!(error("LDA #later % 0\nlater: NOP"),AsmError::DivisionByZero { line: 1 });
+    }
+}
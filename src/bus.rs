@@ -0,0 +1,447 @@
+//! Every memory access of the CPU goes through a Bus, which decides what device answers an address.
+//!
+//! NES CPU memory map:
+//! [0x0000 .. 0x07FF] 2 KiB internal RAM, mirrored up to 0x1FFF
+//! [0x2000 .. 0x2007] PPU registers, mirrored every 8 bytes up to 0x3FFF
+//! [0x4000 .. 0x401F] APU and I/O registers
+//! [0x4020 .. 0xFFFF] cartridge space: PRG ROM, PRG RAM and mapper registers
+//!
+//! Writing a page number XX to 0x4014 copies [0xXX00 .. 0xXXFF] into PPU OAM. The CPU is halted
+//! for 513 cycles, plus one more to align with the DMA unit when the transfer starts on an odd cycle.
+
+use crate::apu::{Apu, APU_STATUS, FETCH_STALL_CYCLES, FRAME_COUNTER};
+use crate::cartridge::Cartridge;
+use crate::controller::{Controller, CONTROLLER_1, CONTROLLER_2};
+use crate::mapper::{create_mapper, Mapper, MapperError, NoCartridge};
+use crate::ppu::NesPPU;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+pub trait Bus {
+    fn read(&mut self,addr: u16) -> u8;
+    fn write(&mut self,addr: u16,data: u8);
+    // called whenever the cpu spends cycles, so hardware clocked from the cpu can keep up with it
+    fn tick(&mut self,_cycles: u8) {}
+    // returns true once for every NMI raised by a device since the last poll
+    fn poll_nmi(&mut self) -> bool {
+        false
+    }
+    // level of the shared IRQ line
+    fn irq_line(&self) -> bool {
+        false
+    }
+    // cycles the CPU has to sit idle since the last call, while DMA owns the bus
+    fn take_stall(&mut self) -> u16 {
+        0
+    }
+    // reads without side effects for debuggers and traces, buses whose reads have side effects must override it
+    fn peek(&mut self,addr: u16) -> u8 {
+        self.read(addr)
+    }
+    // (scanline, dot) of the PPU for traces, buses without a PPU stay at 0,0
+    fn ppu_position(&self) -> (u16,u16) {
+        (0,0)
+    }
+    // frames the PPU has finished since power on, None for buses without a PPU
+    fn frame_count(&self) -> Option<u64> {
+        None
+    }
+    // the reset line of the devices, pulled together with the one of the CPU
+    fn reset(&mut self) {}
+}
+
+pub const RAM: u16 = 0x0000;
+pub const RAM_MIRRORS_END: u16 = 0x1FFF;
+pub const PPU_REGISTERS: u16 = 0x2000;
+pub const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+pub const APU_IO_REGISTERS: u16 = 0x4000;
+pub const APU_IO_REGISTERS_END: u16 = 0x401F;
+pub const OAM_DMA: u16 = 0x4014;
+pub const CARTRIDGE_SPACE: u16 = 0x4020;
+
+/// What the 2 KiB of internal RAM hold at power on. Real consoles power up with a mostly
+/// random, console specific pattern, games that read RAM before writing it behave differently
+/// depending on it. Random is driven by a seed, so a run can always be repeated.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum RamInit {
+    #[default]
+    Zeros,
+    Ones,
+    // 4 bytes of $00, then 4 bytes of $FF, the pattern FCEUX powers up with
+    Alternating,
+    Random(u64),
+}
+
+impl RamInit {
+    pub fn fill(&self,ram: &mut [u8]) {
+        match *self {
+            RamInit::Zeros => ram.fill(0x00),
+            RamInit::Ones => ram.fill(0xFF),
+            RamInit::Alternating => {
+                for (i,byte) in ram.iter_mut().enumerate() {
+                    *byte = if i & 0b100 == 0 { 0x00 } else { 0xFF };
+                }
+            },
+            RamInit::Random(seed) => {
+                // splitmix64, a fixed algorithm keeps the pattern of a seed the same across builds
+                let mut state = seed;
+                for chunk in ram.chunks_mut(8) {
+                    state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                    let mut z = state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
+            },
+        }
+    }
+}
+
+// 64 KiB of plain RAM and nothing else, for running bare 6502 programs such as the Klaus Dormann test suites
+pub struct FlatBus {
+    memory: Vec<u8>,
+}
+
+impl Default for FlatBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlatBus {
+    pub fn new() -> Self {
+        FlatBus {
+            memory: vec![0; 0x10000],
+        }
+    }
+    // copies an image into memory starting at origin
+    pub fn load(&mut self,origin: u16,data: &[u8]) {
+        assert!(origin as usize + data.len() <= 0x10000,"image does not fit in 64 KiB");
+        self.memory[origin as usize..origin as usize + data.len()].copy_from_slice(data);
+    }
+}
+
+impl Bus for FlatBus {
+    fn read(&mut self,addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+    fn write(&mut self,addr: u16,data: u8) {
+        self.memory[addr as usize] = data;
+    }
+}
+
+pub struct NesBus {
+    cpu_vram: [u8; 0x800],
+    pub ppu: NesPPU,
+    pub apu: Apu,
+    pub controllers: [Controller; 2],
+    // placeholder latches for the I/O registers nothing owns yet, they only remember what was written
+    apu_io_registers: [u8; 0x20],
+    stall: u16,
+    // OAM DMA requested by the current instruction, it runs once the instruction has finished
+    oam_dma_page: Option<u8>,
+    // CPU cycles seen by the bus, only the parity matters for DMA alignment
+    cycles: u64,
+    // the last value seen on the CPU data bus, undriven bits of a read return it
+    open_bus: u8,
+    // without a cartridge the NoCartridge board makes [0x4020 .. 0xFFFF] plain memory so raw programs can be loaded into it
+    mapper: Box<dyn Mapper>,
+}
+
+impl Default for NesBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NesBus {
+    pub fn new() -> Self {
+        NesBus {
+            cpu_vram: [0; 0x800],
+            ppu: NesPPU::new(),
+            apu: Apu::default(),
+            controllers: [Controller::new(),Controller::new()],
+            apu_io_registers: [0; 0x20],
+            stall: 0,
+            oam_dma_page: None,
+            cycles: 0,
+            open_bus: 0,
+            mapper: Box::new(NoCartridge::new()),
+        }
+    }
+    pub fn with_ram_init(ram_init: RamInit) -> Self {
+        let mut bus = NesBus::new();
+        ram_init.fill(&mut bus.cpu_vram);
+        bus
+    }
+    pub fn insert_cartridge(&mut self,cartridge: Cartridge) -> Result<(),MapperError> {
+        self.mapper = create_mapper(cartridge)?;
+        Ok(())
+    }
+    pub fn mapper(&mut self) -> &mut dyn Mapper {
+        self.mapper.as_mut()
+    }
+    fn oam_dma(&mut self,page: u8) {
+        let start = (page as u16) << 8;
+        for offset in 0..256 {
+            let data = self.read(start + offset);
+            self.ppu.write_oam(data);
+        }
+        self.stall += if self.cycles % 2 == 1 { 514 } else { 513 };
+    }
+}
+
+impl Bus for NesBus {
+    fn read(&mut self,addr: u16) -> u8 {
+        let data = match addr {
+            // only the lower 11 bits select a RAM cell
+            RAM ..= RAM_MIRRORS_END => self.cpu_vram[(addr & 0x07FF) as usize],
+            PPU_REGISTERS ..= PPU_REGISTERS_MIRRORS_END => self.ppu.read_register(addr,self.mapper.as_mut()),
+            APU_STATUS => self.apu.read_status(),
+            // joypads only drive the low bits
+            CONTROLLER_1 => (self.open_bus & 0b1110_0000) | self.controllers[0].read(),
+            CONTROLLER_2 => (self.open_bus & 0b1110_0000) | self.controllers[1].read(),
+            APU_IO_REGISTERS ..= APU_IO_REGISTERS_END => self.apu_io_registers[(addr - APU_IO_REGISTERS) as usize],
+            _ => self.mapper.cpu_read(addr),
+        };
+        self.open_bus = data;
+        data
+    }
+    fn write(&mut self,addr: u16,data: u8) {
+        self.open_bus = data;
+        match addr {
+            RAM ..= RAM_MIRRORS_END => self.cpu_vram[(addr & 0x07FF) as usize] = data,
+            PPU_REGISTERS ..= PPU_REGISTERS_MIRRORS_END => self.ppu.write_register(addr,data,self.mapper.as_mut()),
+            APU_IO_REGISTERS ..= 0x4013 | APU_STATUS | FRAME_COUNTER => self.apu.write_register(addr,data),
+            OAM_DMA => self.oam_dma_page = Some(data),
+            // the strobe is wired to both ports
+            CONTROLLER_1 => self.controllers.iter_mut().for_each(|controller| controller.write(data)),
+            APU_IO_REGISTERS ..= APU_IO_REGISTERS_END => self.apu_io_registers[(addr - APU_IO_REGISTERS) as usize] = data,
+            _ => self.mapper.cpu_write(addr,data),
+        }
+    }
+    // the APU runs at the CPU clock, the PPU 3 dots per CPU cycle
+    fn tick(&mut self,cycles: u8) {
+        for _ in 0..cycles {
+            self.apu.tick();
+            if let Some(addr) = self.apu.dmc_fetch_request() {
+                let data = self.read(addr);
+                self.apu.dmc_fill(data);
+                self.stall += FETCH_STALL_CYCLES as u16;
+            }
+            for _ in 0..3 {
+                self.ppu.tick(self.mapper.as_mut());
+            }
+        }
+        self.cycles += cycles as u64;
+        if let Some(page) = self.oam_dma_page.take() {
+            self.oam_dma(page);
+        }
+    }
+    fn poll_nmi(&mut self) -> bool {
+        self.ppu.poll_nmi()
+    }
+    fn irq_line(&self) -> bool {
+        self.mapper.irq() || self.apu.irq()
+    }
+    fn take_stall(&mut self) -> u16 {
+        std::mem::take(&mut self.stall)
+    }
+    // registers are not touched, they show up as 0xFF like in the reference nestest log
+    fn peek(&mut self,addr: u16) -> u8 {
+        match addr {
+            RAM ..= RAM_MIRRORS_END => self.cpu_vram[(addr & 0x07FF) as usize],
+            PPU_REGISTERS ..= APU_IO_REGISTERS_END => 0xFF,
+            _ => self.mapper.cpu_read(addr),
+        }
+    }
+    fn ppu_position(&self) -> (u16,u16) {
+        (self.ppu.scanline,self.ppu.dot)
+    }
+    fn frame_count(&self) -> Option<u64> {
+        Some(self.ppu.frame_count)
+    }
+    // RAM and the cartridge are not affected
+    fn reset(&mut self) {
+        self.ppu.reset();
+        self.apu.reset();
+        self.oam_dma_page = None;
+        self.stall = 0;
+    }
+}
+
+impl Snapshot for FlatBus {
+    fn save_state(&self,state: &mut StateWriter) {
+        state.section(b"MEM ",|state| state.bytes(&self.memory));
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        state.section(b"MEM ",|state| state.bytes_into(&mut self.memory))
+    }
+}
+
+// the bus section, then the devices in PPU, APU, controllers, cartridge order
+impl Snapshot for NesBus {
+    fn save_state(&self,state: &mut StateWriter) {
+        state.section(b"BUS ",|state| {
+            state.bytes(&self.cpu_vram);
+            state.bytes(&self.apu_io_registers);
+            state.u16(self.stall);
+            state.bool(self.oam_dma_page.is_some());
+            state.u8(self.oam_dma_page.unwrap_or(0));
+            state.u64(self.cycles);
+            state.u8(self.open_bus);
+        });
+        self.ppu.save_state(state);
+        self.apu.save_state(state);
+        self.controllers.iter().for_each(|controller| controller.save_state(state));
+        state.section(b"CART",|state| self.mapper.save_state(state));
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        state.section(b"BUS ",|state| {
+            state.bytes_into(&mut self.cpu_vram)?;
+            state.bytes_into(&mut self.apu_io_registers)?;
+            self.stall = state.u16()?;
+            let dma = state.bool()?;
+            let page = state.u8()?;
+            self.oam_dma_page = dma.then_some(page);
+            self.cycles = state.u64()?;
+            self.open_bus = state.u8()?;
+            Ok(())
+        })?;
+        self.ppu.load_state(state)?;
+        self.apu.load_state(state)?;
+        for controller in self.controllers.iter_mut() {
+            controller.load_state(state)?;
+        }
+        state.section(b"CART",|state| self.mapper.load_state(state))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_flat_bus_has_no_mirrors() {
+        let mut bus = FlatBus::new();
+        bus.load(0xFFFE,&[0x34,0x12]);
+        bus.write(0x0001,0x55);
+        assert_eq!(bus.read(0x0801),0x00);
+        assert_eq!(bus.read(0xFFFF),0x12);
+    }
+    #[test]
+    fn test_ram_is_mirrored() {
+        let mut bus = NesBus::new();
+        bus.write(0x0001,0x55);
+        assert_eq!(bus.read(0x0801),0x55);
+        assert_eq!(bus.read(0x1801),0x55);
+        bus.write(0x1FFF,0xAA);
+        assert_eq!(bus.read(0x07FF),0xAA);
+    }
+    #[test]
+    fn test_ram_init_patterns() {
+        let fill = |ram_init: RamInit| {
+            let mut ram = [0x55; 16];
+            ram_init.fill(&mut ram);
+            ram
+        };
+        assert_eq!(fill(RamInit::Zeros),[0x00; 16]);
+        assert_eq!(fill(RamInit::Ones),[0xFF; 16]);
+        assert_eq!(&fill(RamInit::Alternating)[..8],&[0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF]);
+        // the same seed always gives the same pattern, another seed another one
+        assert_eq!(fill(RamInit::Random(1)),fill(RamInit::Random(1)));
+        assert_ne!(fill(RamInit::Random(1)),fill(RamInit::Random(2)));
+        assert_eq!(&fill(RamInit::Random(0))[..8],&0xE220_A839_7B1D_CDAFu64.to_le_bytes());
+        let mut bus = NesBus::with_ram_init(RamInit::Alternating);
+        assert_eq!((bus.read(0x0003),bus.read(0x0804)),(0x00,0xFF));
+    }
+    #[test]
+    fn test_ppu_registers_are_mirrored() {
+        let mut bus = NesBus::new();
+        // 0x3FFE is PPUADDR, 0x2007 is PPUDATA
+        bus.write(0x3FFE,0x3F);
+        bus.write(0x2006,0x01);
+        bus.write(0x3FFF,0x12);
+        bus.write(0x2006,0x3F);
+        bus.write(0x3FFE,0x01);
+        assert_eq!(bus.read(0x2007),0x12);
+    }
+    #[test]
+    fn test_tick_runs_ppu_and_raises_nmi() {
+        let mut bus = NesBus::new();
+        bus.write(0x2000,0x80);
+        // vblank starts at dot 1 of scanline 241
+        let dots = 241 * 341 + 2;
+        for _ in 0..dots / 3 {
+            bus.tick(1);
+        }
+        assert!(!bus.poll_nmi());
+        bus.tick(1);
+        assert!(bus.poll_nmi());
+        assert_eq!(bus.read(0x2002) & 0x80,0x80);
+        assert_eq!(bus.read(0x2002) & 0x80,0);
+    }
+    #[test]
+    fn test_whole_address_space_is_addressable() {
+        let mut bus = NesBus::new();
+        bus.write(0xFFFF,0x80);
+        assert_eq!(bus.read(0xFFFF),0x80);
+        bus.write(0x4020,0x01);
+        assert_eq!(bus.read(0x4020),0x01);
+    }
+    #[test]
+    fn test_dmc_fetch_stalls_cpu() {
+        let mut bus = NesBus::new();
+        bus.write(0xC000,0x55);
+        bus.write(0x4010,0x0F);
+        bus.write(0x4013,0);
+        bus.write(0x4015,0b0001_0000);
+        assert_eq!(bus.read(0x4015) & 0b0001_0000,0b0001_0000);
+        bus.tick(1);
+        assert_eq!(bus.take_stall(),FETCH_STALL_CYCLES as u16);
+        assert_eq!(bus.take_stall(),0);
+        assert_eq!(bus.read(0x4015) & 0b0001_0000,0);
+    }
+    #[test]
+    fn test_controller_ports() {
+        let mut bus = NesBus::new();
+        bus.controllers[1].set_buttons(crate::controller::BUTTON_B);
+        bus.write(0x4016,1);
+        bus.write(0x4016,0);
+        // the high byte of an absolute operand is what the bus last carried
+        bus.read(0x40);
+        bus.write(0x0000,0x40);
+        bus.read(0x0000);
+        assert_eq!(bus.read(0x4017),0x40);
+        assert_eq!(bus.read(0x4017),0x41);
+        assert_eq!(bus.read(0x4016),0x40);
+    }
+    #[test]
+    fn test_oam_dma_copies_page_and_stalls() {
+        let mut bus = NesBus::new();
+        for i in 0..256 {
+            bus.write(0x0200 + i,i as u8);
+        }
+        bus.write(0x2003,0x10);
+        bus.write(OAM_DMA,0x02);
+        // nothing happens until the writing instruction has finished
+        assert_eq!(bus.take_stall(),0);
+        bus.tick(4);
+        assert_eq!(bus.take_stall(),513);
+        assert_eq!(bus.ppu.oam_data[0x10],0x00);
+        assert_eq!(bus.ppu.oam_data[0x0F],0xFF);
+        bus.write(OAM_DMA,0x02);
+        bus.tick(3);
+        assert_eq!(bus.take_stall(),514);
+    }
+    #[test]
+    fn test_cartridge_space_goes_to_mapper() {
+        let mut bus = NesBus::new();
+        bus.insert_cartridge(crate::mapper::test::test_cartridge(0,0x8000,0x2000)).unwrap();
+        assert_eq!(bus.read(0xE000),3);
+        // PRG ROM ignores writes
+        bus.write(0xE000,0xFF);
+        assert_eq!(bus.read(0xE000),3);
+        bus.write(0x6000,0x99);
+        assert_eq!(bus.read(0x6000),0x99);
+    }
+}
@@ -0,0 +1,360 @@
+//! iNES / NES 2.0 ROM file parsing.
+//!
+//! File layout:
+//! [0 .. 16]    header, starts with "NES" followed by MS-DOS end-of-file (0x1A)
+//! [16 .. 528]  optional trainer, present when bit 2 of flags 6 is set
+//! PRG ROM      in 16 KiB units
+//! CHR ROM      in 8 KiB units, a size of 0 means the board uses CHR RAM instead
+//!
+//! Header:
+//! 4      PRG ROM size LSB
+//! 5      CHR ROM size LSB
+//! 6      flags 6: mirroring, battery, trainer, four screen, mapper bits 0-3
+//! 7      flags 7: console type, NES 2.0 identifier (bits 2-3 == 2), mapper bits 4-7
+//! 8      iNES: PRG RAM size in 8 KiB units / NES 2.0: mapper bits 8-11 and submapper
+//! 9      iNES: TV system / NES 2.0: PRG and CHR ROM size MSB
+//! 10     NES 2.0: PRG RAM and PRG NVRAM shift counts
+//! 11     NES 2.0: CHR RAM and CHR NVRAM shift counts
+//! 12     NES 2.0: CPU/PPU timing
+//!
+//! spec: https://www.nesdev.org/wiki/NES_2.0
+
+use std::fmt;
+use std::path::Path;
+use crate::hash::{crc32, md5};
+
+pub const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+pub const HEADER_SIZE: usize = 16;
+pub const TRAINER_SIZE: usize = 512;
+pub const PRG_ROM_PAGE_SIZE: usize = 0x4000;
+pub const CHR_ROM_PAGE_SIZE: usize = 0x2000;
+// iNES 1.0 headers leave PRG RAM size at 0 for the common 8 KiB case
+pub const DEFAULT_PRG_RAM_SIZE: usize = 0x2000;
+pub const DEFAULT_CHR_RAM_SIZE: usize = 0x2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+    // one-screen layouts are only selectable by mapper registers, never by the header
+    SingleScreenLower,
+    SingleScreenUpper,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RomFormat {
+    INes,
+    Nes20,
+}
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(std::io::Error),
+    // the file is shorter than the 16 byte header
+    MissingHeader,
+    InvalidTag,
+    // the header promises more data than the file holds
+    Truncated { expected: usize, actual: usize },
+    // NES 2.0 exponent-multiplier sizes that do not fit in memory
+    InvalidSize,
+    // the header describes no PRG ROM, the CPU would have nothing to run
+    MissingPrgRom,
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self,f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::Io(err) => write!(f,"failed to read rom file: {}",err),
+            CartridgeError::MissingHeader => write!(f,"file is too short to hold an iNES header"),
+            CartridgeError::InvalidTag => write!(f,"file is not in iNES format"),
+            CartridgeError::Truncated { expected, actual } => {
+                write!(f,"rom file is truncated: header describes {} bytes but the file has {}",expected,actual)
+            },
+            CartridgeError::InvalidSize => write!(f,"rom size in the NES 2.0 header is out of range"),
+            CartridgeError::MissingPrgRom => write!(f,"the header describes no PRG ROM"),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<std::io::Error> for CartridgeError {
+    fn from(err: std::io::Error) -> Self {
+        CartridgeError::Io(err)
+    }
+}
+
+#[derive(Clone)]
+pub struct Cartridge {
+    // file name without its extension, empty for ROMs not loaded from a file, movies record it as romFilename
+    pub name: String,
+    pub format: RomFormat,
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub trainer: Option<Vec<u8>>,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    // battery backed PRG RAM, the game expects its save data to survive power off
+    pub battery: bool,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing,
+}
+
+impl Cartridge {
+    pub fn new(raw: &[u8]) -> Result<Cartridge,CartridgeError> {
+        if raw.len() < HEADER_SIZE {
+            return Err(CartridgeError::MissingHeader);
+        }
+        if raw[0..4] != NES_TAG {
+            return Err(CartridgeError::InvalidTag);
+        }
+        let flags_6 = raw[6];
+        let flags_7 = raw[7];
+        let format = if flags_7 & 0b0000_1100 == 0b0000_1000 {
+            RomFormat::Nes20
+        }else {
+            RomFormat::INes
+        };
+
+        let mirroring = if flags_6 & 0b1000 != 0 {
+            Mirroring::FourScreen
+        }else if flags_6 & 0b1 != 0 {
+            Mirroring::Vertical
+        }else {
+            Mirroring::Horizontal
+        };
+        let battery = flags_6 & 0b10 != 0;
+        let has_trainer = flags_6 & 0b100 != 0;
+
+        let (mapper,submapper,prg_rom_size,chr_rom_size,prg_ram_size,prg_nvram_size,chr_ram_size,chr_nvram_size,timing) = match format {
+            RomFormat::Nes20 => {
+                let mapper = (flags_6 >> 4) as u16 | (flags_7 & 0xF0) as u16 | ((raw[8] & 0x0F) as u16) << 8;
+                let submapper = raw[8] >> 4;
+                let prg_rom_size = rom_size(raw[4],raw[9] & 0x0F,PRG_ROM_PAGE_SIZE)?;
+                let chr_rom_size = rom_size(raw[5],raw[9] >> 4,CHR_ROM_PAGE_SIZE)?;
+                let timing = match raw[12] & 0b11 {
+                    0 => Timing::Ntsc,
+                    1 => Timing::Pal,
+                    2 => Timing::MultiRegion,
+                    _ => Timing::Dendy,
+                };
+                (
+                    mapper,submapper,prg_rom_size,chr_rom_size,
+                    ram_size(raw[10] & 0x0F),ram_size(raw[10] >> 4),
+                    ram_size(raw[11] & 0x0F),ram_size(raw[11] >> 4),
+                    timing,
+                )
+            },
+            RomFormat::INes => {
+                // old rippers left signatures such as "DiskDude!" in bytes 7-15,
+                // in that case the upper mapper nibble in flags 7 is garbage too
+                let dirty = raw[12..16].iter().any(|b| *b != 0);
+                let mapper = if dirty {
+                    (flags_6 >> 4) as u16
+                }else {
+                    (flags_6 >> 4) as u16 | (flags_7 & 0xF0) as u16
+                };
+                let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+                let prg_ram_size = match raw[8] {
+                    0 => DEFAULT_PRG_RAM_SIZE,
+                    banks => banks as usize * 0x2000,
+                };
+                let (prg_ram_size,prg_nvram_size) = if battery {
+                    (0,prg_ram_size)
+                }else {
+                    (prg_ram_size,0)
+                };
+                let chr_ram_size = if chr_rom_size == 0 {
+                    DEFAULT_CHR_RAM_SIZE
+                }else {
+                    0
+                };
+                let timing = if !dirty && raw[9] & 1 != 0 {
+                    Timing::Pal
+                }else {
+                    Timing::Ntsc
+                };
+                (
+                    mapper,0,raw[4] as usize * PRG_ROM_PAGE_SIZE,chr_rom_size,
+                    prg_ram_size,prg_nvram_size,chr_ram_size,0,
+                    timing,
+                )
+            },
+        };
+
+        if prg_rom_size == 0 {
+            return Err(CartridgeError::MissingPrgRom);
+        }
+        let trainer_start = HEADER_SIZE;
+        let prg_rom_start = trainer_start + if has_trainer { TRAINER_SIZE } else { 0 };
+        let chr_rom_start = prg_rom_start + prg_rom_size;
+        let expected = chr_rom_start + chr_rom_size;
+        if raw.len() < expected {
+            return Err(CartridgeError::Truncated { expected, actual: raw.len() });
+        }
+
+        Ok(Cartridge {
+            name: String::new(),
+            format,
+            prg_rom: raw[prg_rom_start..chr_rom_start].to_vec(),
+            chr_rom: raw[chr_rom_start..expected].to_vec(),
+            trainer: if has_trainer {
+                Some(raw[trainer_start..prg_rom_start].to_vec())
+            }else {
+                None
+            },
+            mapper,
+            submapper,
+            mirroring,
+            battery,
+            prg_ram_size,
+            prg_nvram_size,
+            chr_ram_size,
+            chr_nvram_size,
+            timing,
+        })
+    }
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Cartridge,CartridgeError> {
+        let raw = std::fs::read(&path)?;
+        let mut cartridge = Cartridge::new(&raw)?;
+        cartridge.name = path.as_ref().file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        Ok(cartridge)
+    }
+    /// CRC32 of PRG ROM followed by CHR ROM, identifies the game whatever its header says.
+    pub fn checksum(&self) -> u32 {
+        crc32(&[self.prg_rom.as_slice(),self.chr_rom.as_slice()].concat())
+    }
+    /// MD5 of PRG ROM followed by CHR ROM, the `romChecksum` of FCEUX movies.
+    pub fn md5(&self) -> [u8; 16] {
+        md5(&[self.prg_rom.as_slice(),self.chr_rom.as_slice()].concat())
+    }
+}
+
+// NES 2.0 ROM size: an MSB nibble of 0xF switches the LSB to exponent-multiplier notation, 2^E * (MM*2+1)
+fn rom_size(lsb: u8,msb: u8,page_size: usize) -> Result<usize,CartridgeError> {
+    if msb == 0x0F {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        1usize.checked_shl(exponent)
+            .and_then(|size| size.checked_mul(multiplier))
+            .filter(|size| *size <= u32::MAX as usize)
+            .ok_or(CartridgeError::InvalidSize)
+    }else {
+        Ok(((msb as usize) << 8 | lsb as usize) * page_size)
+    }
+}
+
+// NES 2.0 RAM size: 64 << shift bytes, a shift count of 0 means there is no RAM
+fn ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    }else {
+        64 << shift
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn build_rom(header: [u8; 16],trainer: bool,prg_rom_size: usize,chr_rom_size: usize) -> Vec<u8> {
+        let mut raw = header.to_vec();
+        if trainer {
+            raw.extend(std::iter::repeat_n(0xEE,TRAINER_SIZE));
+        }
+        raw.extend(std::iter::repeat_n(0x01,prg_rom_size));
+        raw.extend(std::iter::repeat_n(0x02,chr_rom_size));
+        raw
+    }
+    #[test]
+    fn test_ines_header() {
+        let raw = build_rom([0x4E, 0x45, 0x53, 0x1A, 2, 1, 0x31, 0x00, 0, 0, 0, 0, 0, 0, 0, 0],false,0x8000,0x2000);
+        let cartridge = Cartridge::new(&raw).unwrap();
+        assert_eq!(cartridge.format,RomFormat::INes);
+        assert_eq!(cartridge.mapper,3);
+        assert_eq!(cartridge.mirroring,Mirroring::Vertical);
+        assert_eq!(cartridge.prg_rom.len(),0x8000);
+        assert_eq!(cartridge.chr_rom.len(),0x2000);
+        assert!(cartridge.chr_rom.iter().all(|b| *b == 0x02));
+        assert_eq!(cartridge.prg_ram_size,DEFAULT_PRG_RAM_SIZE);
+        assert_eq!(cartridge.chr_ram_size,0);
+        assert_eq!(cartridge.timing,Timing::Ntsc);
+    }
+    #[test]
+    fn test_ines_trainer_battery_and_chr_ram() {
+        let raw = build_rom([0x4E, 0x45, 0x53, 0x1A, 1, 0, 0x16, 0x10, 0, 1, 0, 0, 0, 0, 0, 0],true,0x4000,0);
+        let cartridge = Cartridge::new(&raw).unwrap();
+        assert_eq!(cartridge.mapper,0x11);
+        assert!(cartridge.battery);
+        assert_eq!(cartridge.trainer.as_ref().map(|t| t.len()),Some(TRAINER_SIZE));
+        assert!(cartridge.prg_rom.iter().all(|b| *b == 0x01));
+        assert_eq!(cartridge.prg_nvram_size,DEFAULT_PRG_RAM_SIZE);
+        assert_eq!(cartridge.chr_ram_size,DEFAULT_CHR_RAM_SIZE);
+        assert_eq!(cartridge.timing,Timing::Pal);
+    }
+    #[test]
+    fn test_dirty_ines_header_ignores_upper_mapper_nibble() {
+        let mut header = [0x4E, 0x45, 0x53, 0x1A, 1, 1, 0x10, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+        header[7..16].copy_from_slice(b"DiskDude!");
+        let cartridge = Cartridge::new(&build_rom(header,false,0x4000,0x2000)).unwrap();
+        assert_eq!(cartridge.format,RomFormat::INes);
+        assert_eq!(cartridge.mapper,1);
+    }
+    #[test]
+    fn test_nes20_header() {
+        // mapper 0x104 submapper 3, PRG RAM 8 KiB, PRG NVRAM 32 KiB, CHR RAM 8 KiB, Dendy
+        let header = [0x4E, 0x45, 0x53, 0x1A, 0x02, 0x00, 0x42, 0x08, 0x31, 0x00, 0x97, 0x07, 0x03, 0, 0, 0];
+        let cartridge = Cartridge::new(&build_rom(header,false,0x8000,0)).unwrap();
+        assert_eq!(cartridge.format,RomFormat::Nes20);
+        assert_eq!(cartridge.mapper,0x104);
+        assert_eq!(cartridge.submapper,3);
+        assert!(cartridge.battery);
+        assert_eq!(cartridge.prg_ram_size,0x2000);
+        assert_eq!(cartridge.prg_nvram_size,0x8000);
+        assert_eq!(cartridge.chr_ram_size,0x2000);
+        assert_eq!(cartridge.chr_nvram_size,0);
+        assert_eq!(cartridge.timing,Timing::Dendy);
+    }
+    #[test]
+    fn test_nes20_exponent_multiplier_size() {
+        // PRG ROM = 2^14 * 3 = 48 KiB
+        let header = [0x4E, 0x45, 0x53, 0x1A, (14 << 2) | 1, 0x01, 0x00, 0x08, 0, 0x0F, 0, 0, 0, 0, 0, 0];
+        let cartridge = Cartridge::new(&build_rom(header,false,0xC000,0x2000)).unwrap();
+        assert_eq!(cartridge.prg_rom.len(),0xC000);
+        assert_eq!(cartridge.chr_rom.len(),0x2000);
+    }
+    #[test]
+    fn test_malformed_files() {
+        assert!(matches!(Cartridge::new(&[0x4E, 0x45, 0x53]),Err(CartridgeError::MissingHeader)));
+        assert!(matches!(Cartridge::new(&[0; 16]),Err(CartridgeError::InvalidTag)));
+        let raw = build_rom([0x4E, 0x45, 0x53, 0x1A, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],false,0x4000,0);
+        match Cartridge::new(&raw) {
+            Err(CartridgeError::Truncated { expected, actual }) => {
+                assert_eq!(expected,HEADER_SIZE + 0x8000 + 0x2000);
+                assert_eq!(actual,HEADER_SIZE + 0x4000);
+            },
+            _ => panic!("truncated rom was accepted"),
+        }
+        let header = [0x4E, 0x45, 0x53, 0x1A, 0xFF, 0, 0, 0x08, 0, 0x0F, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(Cartridge::new(&build_rom(header,false,0,0)),Err(CartridgeError::InvalidSize)));
+    }
+    #[test]
+    fn test_empty_prg_rom_is_rejected() {
+        let raw = build_rom([0x4E, 0x45, 0x53, 0x1A, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],false,0,0x2000);
+        assert!(matches!(Cartridge::new(&raw),Err(CartridgeError::MissingPrgRom)));
+        let header = [0x4E, 0x45, 0x53, 0x1A, 0, 1, 0, 0x08, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(Cartridge::new(&build_rom(header,false,0,0x2000)),Err(CartridgeError::MissingPrgRom)));
+    }
+}
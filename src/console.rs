@@ -0,0 +1,359 @@
+//! The whole machine: the CPU on an NesBus with a cartridge inserted.
+//!
+//! Hosts drive it a frame at a time, set the controllers before a frame
+//! and collect the picture and the audio samples after it.
+
+use std::path::Path;
+use crate::bus::{NesBus, RamInit};
+use crate::cartridge::Cartridge;
+use crate::cpu::CPU;
+use crate::error::EmuError;
+use crate::movie::{Movie, MovieError, MovieFrame, COMMAND_POWER, COMMAND_RESET};
+use crate::ppu::frame::Frame;
+use crate::rewind::Rewind;
+use crate::state::{self, StateError};
+
+/// Where a recorded movie starts.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RecordFrom {
+    // the console is power cycled first
+    PowerOn,
+    // the current state is saved into the movie
+    SaveState,
+}
+
+pub struct Console {
+    pub cpu: CPU<NesBus>,
+    // kept to build a fresh board on power cycles
+    cartridge: Cartridge,
+    // identifies the game in save states
+    rom_checksum: u32,
+    // what RAM holds after every power on
+    ram_init: RamInit,
+    rewind: Option<Rewind>,
+    recording: Option<Movie>,
+    // resets and power cycles since the last recorded frame
+    pending_commands: u8,
+}
+
+impl Console {
+    /// Inserts the cartridge and powers the console on with zeroed RAM.
+    pub fn new(cartridge: Cartridge) -> Result<Console,EmuError> {
+        Console::with_ram_init(cartridge,RamInit::default())
+    }
+    /// Like `new`, with RAM filled according to `ram_init` on this and every later power on.
+    pub fn with_ram_init(cartridge: Cartridge,ram_init: RamInit) -> Result<Console,EmuError> {
+        let cpu = power_on(&cartridge,ram_init)?;
+        let rom_checksum = cartridge.checksum();
+        Ok(Console { cpu, cartridge, rom_checksum, ram_init, rewind: None, recording: None, pending_commands: 0 })
+    }
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Console,EmuError> {
+        Console::new(Cartridge::from_file(path)?)
+    }
+    /// Presses the reset button: RAM and the CPU registers keep their values, see `CPU::reset`.
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.pending_commands |= COMMAND_RESET;
+    }
+    /// Turns the console off and on again, every chip and the cartridge RAM start over.
+    pub fn power_cycle(&mut self) -> Result<(),EmuError> {
+        self.cpu = power_on(&self.cartridge,self.ram_init)?;
+        self.clear_rewind();
+        self.pending_commands |= COMMAND_POWER;
+        Ok(())
+    }
+    /// Performs the resets recorded for a movie frame and holds its buttons, call it before `run_frame`.
+    pub fn apply_input(&mut self,input: &MovieFrame) -> Result<(),EmuError> {
+        if input.commands & COMMAND_POWER != 0 {
+            self.power_cycle()?;
+        }else if input.commands & COMMAND_RESET != 0 {
+            self.reset();
+        }
+        for (port,buttons) in input.buttons.iter().enumerate() {
+            self.set_buttons(port,*buttons);
+        }
+        Ok(())
+    }
+    /// Runs until the PPU has finished the next frame.
+    pub fn run_frame(&mut self) -> Result<(),EmuError> {
+        let commands = std::mem::take(&mut self.pending_commands);
+        if let Some(movie) = self.recording.as_mut() {
+            let buttons = [self.cpu.bus.controllers[0].buttons(),self.cpu.bus.controllers[1].buttons()];
+            movie.frames.push(MovieFrame { commands, buttons });
+        }
+        self.cpu.run_until_frame()?;
+        let frame = self.frame_count();
+        if self.rewind.as_ref().is_some_and(|rewind| frame.is_multiple_of(rewind.interval())) {
+            let state = self.save_state();
+            self.rewind.as_mut().unwrap().push(frame,state);
+        }
+        Ok(())
+    }
+    /// The last picture drawn by the PPU.
+    pub fn frame(&self) -> &Frame {
+        &self.cpu.bus.ppu.frame
+    }
+    /// Frames finished since power on.
+    pub fn frame_count(&self) -> u64 {
+        self.cpu.bus.ppu.frame_count
+    }
+    /// Sets the buttons held on controller `port` (0 or 1), one BUTTON_* bit per pressed button.
+    pub fn set_buttons(&mut self,port: usize,buttons: u8) {
+        self.cpu.bus.controllers[port].set_buttons(buttons);
+    }
+    /// Hands out the audio samples produced since the last call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus.apu.take_samples()
+    }
+    /// Serializes the whole machine, the format is described in `state`.
+    pub fn save_state(&self) -> Vec<u8> {
+        state::save(&self.cpu,self.rom_checksum)
+    }
+    /// Restores a state from `save_state`, states of another game or format version are rejected and change nothing.
+    pub fn load_state(&mut self,data: &[u8]) -> Result<(),StateError> {
+        self.restore(data)?;
+        self.clear_rewind();
+        Ok(())
+    }
+    /// Snapshots the machine every `interval` frames from now on, keeping the last `capacity` snapshots for `rewind`.
+    pub fn enable_rewind(&mut self,interval: u64,capacity: usize) {
+        self.rewind = Some(Rewind::new(interval,capacity));
+    }
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+    /// Goes back to the newest snapshot taken at least `frames` frames ago, or the oldest one kept.
+    /// Returns the number of frames actually rewound, 0 when rewind is off or nothing was recorded yet.
+    pub fn rewind(&mut self,frames: u64) -> Result<u64,StateError> {
+        let now = self.frame_count();
+        let Some((frame,state)) = self.rewind.as_mut().and_then(|rewind| rewind.seek(now.saturating_sub(frames))) else {
+            return Ok(0);
+        };
+        self.restore(&state)?;
+        Ok(now - frame)
+    }
+    /// Starts recording the buttons held and the resets done for every frame run from now on.
+    pub fn start_recording(&mut self,from: RecordFrom) -> Result<(),EmuError> {
+        let mut movie = Movie::new();
+        movie.set_rom(&self.cartridge);
+        match from {
+            RecordFrom::PowerOn => self.power_cycle()?,
+            RecordFrom::SaveState => movie.start_state = Some(self.save_state()),
+        }
+        self.pending_commands = 0;
+        self.recording = Some(movie);
+        Ok(())
+    }
+    /// Stops recording and hands out the movie, None when nothing was being recorded.
+    pub fn stop_recording(&mut self) -> Option<Movie> {
+        self.recording.take()
+    }
+    /// Puts the console where `movie` starts, its save state or a fresh power on.
+    /// Playing it back is then a matter of `apply_input` and `run_frame` for each of its frames.
+    /// Movies recorded on another ROM are refused.
+    pub fn start_movie(&mut self,movie: &Movie) -> Result<(),EmuError> {
+        if !movie.matches_rom(&self.cartridge) {
+            return Err(MovieError::RomMismatch.into());
+        }
+        match &movie.start_state {
+            Some(state) => self.load_state(state)?,
+            None => self.power_cycle()?,
+        }
+        self.pending_commands = 0;
+        Ok(())
+    }
+    fn restore(&mut self,data: &[u8]) -> Result<(),StateError> {
+        state::load(&mut self.cpu,self.rom_checksum,data)?;
+        // samples not collected yet belong to the timeline that was left
+        self.cpu.bus.apu.take_samples();
+        Ok(())
+    }
+    // snapshots from before a power cycle or a loaded state belong to another timeline
+    fn clear_rewind(&mut self) {
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.clear();
+        }
+    }
+}
+
+fn power_on(cartridge: &Cartridge,ram_init: RamInit) -> Result<CPU<NesBus>,EmuError> {
+    let mut bus = NesBus::with_ram_init(ram_init);
+    bus.insert_cartridge(cartridge.clone())?;
+    let mut cpu = CPU::new(bus);
+    cpu.power_on();
+    Ok(cpu)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::bus::Bus;
+    use crate::controller::BUTTON_START;
+    use crate::cpu::CpuVariant;
+    use crate::mapper::test::test_cartridge;
+    // NROM board whose program at $8000 is `LDA $4016; JMP $8000`
+    pub(crate) fn test_console() -> Console {
+        let mut cartridge = test_cartridge(0,0x8000,0x2000);
+        cartridge.prg_rom[..6].copy_from_slice(&[0xAD, 0x16, 0x40, 0x4C, 0x00, 0x80]);
+        cartridge.prg_rom[0x7FFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80]);
+        Console::new(cartridge).unwrap()
+    }
+    #[test]
+    fn test_console_runs_frames() {
+        let mut console = test_console();
+        console.set_buttons(0,BUTTON_START);
+        for frame in 1..=3 {
+            console.run_frame().unwrap();
+            assert_eq!(console.frame_count(),frame);
+        }
+        assert_eq!(console.cpu.bus.controllers[0].buttons(),BUTTON_START);
+        assert!(!console.take_samples().is_empty());
+        assert_eq!(console.frame().data.len(),Frame::WIDTH * Frame::HEIGHT * 3);
+    }
+    #[test]
+    fn test_movie_commands() {
+        let mut console = test_console();
+        console.run_frame().unwrap();
+        console.cpu.bus.write(0x0000,0x55);
+        console.apply_input(&MovieFrame { commands: COMMAND_RESET, buttons: [BUTTON_START,0] }).unwrap();
+        assert_eq!((console.cpu.program_counter,console.cpu.bus.read(0x0000)),(0x8000,0x55));
+        assert_eq!(console.cpu.bus.controllers[0].buttons(),BUTTON_START);
+        console.apply_input(&MovieFrame { commands: COMMAND_POWER, buttons: [0,0] }).unwrap();
+        assert_eq!((console.frame_count(),console.cpu.bus.read(0x0000)),(0,0x00));
+    }
+    #[test]
+    fn test_power_on_fills_ram() {
+        let mut cartridge = test_cartridge(0,0x8000,0x2000);
+        cartridge.prg_rom[0x7FFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80]);
+        let mut console = Console::with_ram_init(cartridge,RamInit::Ones).unwrap();
+        assert_eq!(console.cpu.bus.read(0x0123),0xFF);
+        console.cpu.bus.write(0x0123,0x00);
+        console.reset();
+        assert_eq!(console.cpu.bus.read(0x0123),0x00);
+        console.power_cycle().unwrap();
+        assert_eq!(console.cpu.bus.read(0x0123),0xFF);
+    }
+    #[test]
+    fn test_save_state_round_trip() {
+        // keeps the PPU, a pulse channel and the joypad busy so every section changes from frame to frame
+        let program = assemble("
+                    LDA #$1E
+                    STA $2001
+                    LDA #$01
+                    STA $4015
+                    LDA #$BF
+                    STA $4000
+                    LDA #$40
+                    STA $4002
+                    STA $4003
+            loop:   INC $10
+                    LDA $4016
+                    JMP loop
+        ",CpuVariant::Ricoh2A03).unwrap();
+        let mut cartridge = test_cartridge(0,0x8000,0x2000);
+        cartridge.prg_rom[..program[0].data.len()].copy_from_slice(&program[0].data);
+        cartridge.prg_rom[0x7FFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80]);
+        let mut console = Console::new(cartridge.clone()).unwrap();
+        console.set_buttons(0,BUTTON_START);
+        console.run_frame().unwrap();
+        console.cpu.run_for_cycles(1234).unwrap();
+        let saved = console.save_state();
+        let run = |console: &mut Console| {
+            console.take_samples();
+            for _ in 0..3 {
+                console.run_frame().unwrap();
+            }
+            (console.frame().data.clone(),console.take_samples(),console.save_state())
+        };
+        let expected = run(&mut console);
+        console.load_state(&saved).unwrap();
+        assert_eq!(console.save_state(),saved);
+        assert_eq!(run(&mut console),expected);
+        // the state also loads into a freshly powered console
+        let mut fresh = Console::new(cartridge.clone()).unwrap();
+        fresh.load_state(&saved).unwrap();
+        assert_eq!(run(&mut fresh),expected);
+        cartridge.prg_rom[0x100] ^= 0xFF;
+        let mut other_game = Console::new(cartridge).unwrap();
+        assert!(matches!(other_game.load_state(&saved),Err(StateError::RomMismatch { .. })));
+        assert!(matches!(console.load_state(&saved[..saved.len() / 2]),Err(StateError::Corrupt { .. })));
+        // a rejected state keeps the samples not collected yet, even when sections after the APU are the broken ones
+        console.load_state(&saved).unwrap();
+        console.run_frame().unwrap();
+        let pending = console.take_samples();
+        assert!(!pending.is_empty());
+        console.load_state(&saved).unwrap();
+        console.run_frame().unwrap();
+        assert!(matches!(console.load_state(&saved[..saved.len() - 1]),Err(StateError::Corrupt { section }) if section == "CART"));
+        assert_eq!(console.take_samples(),pending);
+        // a loaded one drops them
+        console.run_frame().unwrap();
+        console.load_state(&saved).unwrap();
+        assert!(console.take_samples().is_empty());
+    }
+    #[test]
+    fn test_rewind() {
+        let mut console = test_console();
+        assert_eq!(console.rewind(10).unwrap(),0);
+        console.enable_rewind(5,4);
+        let mut states = Vec::new();
+        for _ in 0..30 {
+            console.run_frame().unwrap();
+            states.push(console.save_state());
+        }
+        // snapshots of frames 15, 20, 25 and 30 are left
+        assert_eq!(console.rewind(3).unwrap(),5);
+        assert_eq!(console.save_state(),states[24]);
+        assert_eq!(console.rewind(1).unwrap(),5);
+        assert_eq!(console.save_state(),states[19]);
+        assert_eq!(console.rewind(100).unwrap(),5);
+        assert_eq!(console.save_state(),states[14]);
+        // the timeline continues from the rewound frame
+        console.run_frame().unwrap();
+        assert_eq!(console.save_state(),states[15]);
+        console.power_cycle().unwrap();
+        assert_eq!(console.rewind(1).unwrap(),0);
+    }
+    #[test]
+    fn test_record_and_play_back() {
+        let play = |console: &mut Console,movie: &Movie| {
+            console.start_movie(movie).unwrap();
+            for input in movie.frames.iter() {
+                console.apply_input(input).unwrap();
+                console.run_frame().unwrap();
+            }
+            console.save_state()
+        };
+        for from in [RecordFrom::PowerOn,RecordFrom::SaveState] {
+            let mut console = test_console();
+            console.run_frame().unwrap();
+            console.start_recording(from).unwrap();
+            for frame in 0..6u8 {
+                console.set_buttons(0,frame);
+                console.set_buttons(1,!frame);
+                if frame == 3 {
+                    console.reset();
+                }
+                console.run_frame().unwrap();
+            }
+            let movie = console.stop_recording().unwrap();
+            assert_eq!(movie.frames[3],MovieFrame { commands: COMMAND_RESET, buttons: [3,!3] });
+            assert_eq!(movie.start_state.is_some(),from == RecordFrom::SaveState);
+            let recorded = console.save_state();
+            let movie = Movie::parse(&movie.to_fm2()).unwrap();
+            assert_eq!(play(&mut console,&movie),recorded);
+            assert_eq!(play(&mut test_console(),&movie),recorded);
+        }
+        // movies only play back on the ROM they were recorded on
+        let mut console = test_console();
+        console.start_recording(RecordFrom::PowerOn).unwrap();
+        console.run_frame().unwrap();
+        let movie = console.stop_recording().unwrap();
+        assert_eq!(movie.header("romFilename"),Some("test"));
+        let mut cartridge = test_cartridge(0,0x8000,0x2000);
+        cartridge.prg_rom[0x7FFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80]);
+        let mut other_game = Console::new(cartridge).unwrap();
+        assert!(matches!(other_game.start_movie(&movie),Err(EmuError::Movie(MovieError::RomMismatch))));
+    }
+}
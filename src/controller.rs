@@ -0,0 +1,120 @@
+//! Standard joypad, read serially through 0x4016 (port 1) and 0x4017 (port 2).
+//!
+//! Writing 1 to bit 0 of 0x4016 holds the strobe: both controllers keep reloading their shift register
+//! with the current button state. Writing 0 freezes it, then every read returns the next button in bit 0,
+//! in the order A, B, Select, Start, Up, Down, Left, Right. Once all 8 are read the register returns 1.
+//! Only the low bits are driven, the upper bits of the read keep the CPU open bus value.
+
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+pub static BUTTON_A: u8 = 0b0000_0001;
+pub static BUTTON_B: u8 = 0b0000_0010;
+pub static BUTTON_SELECT: u8 = 0b0000_0100;
+pub static BUTTON_START: u8 = 0b0000_1000;
+pub static BUTTON_UP: u8 = 0b0001_0000;
+pub static BUTTON_DOWN: u8 = 0b0010_0000;
+pub static BUTTON_LEFT: u8 = 0b0100_0000;
+pub static BUTTON_RIGHT: u8 = 0b1000_0000;
+
+pub const CONTROLLER_1: u16 = 0x4016;
+pub const CONTROLLER_2: u16 = 0x4017;
+
+#[derive(Default)]
+pub struct Controller {
+    buttons: u8,
+    strobe: bool,
+    shift_register: u8,
+    // number of bits shifted out since the strobe was released
+    reads: u8,
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+    /// Replaces the whole button state, one BUTTON_* bit per pressed button.
+    pub fn set_buttons(&mut self,buttons: u8) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.reload();
+        }
+    }
+    pub fn set_button(&mut self,button: u8,pressed: bool) {
+        let buttons = if pressed { self.buttons | button } else { self.buttons & !button };
+        self.set_buttons(buttons);
+    }
+    fn reload(&mut self) {
+        self.shift_register = self.buttons;
+        self.reads = 0;
+    }
+    pub fn write(&mut self,data: u8) {
+        self.strobe = data & 1 != 0;
+        if self.strobe {
+            self.reload();
+        }
+    }
+    // returns the next button in bit 0, the caller merges in the open bus bits
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons & BUTTON_A;
+        }
+        if self.reads >= 8 {
+            return 1;
+        }
+        let bit = self.shift_register & 1;
+        self.shift_register >>= 1;
+        self.reads += 1;
+        bit
+    }
+}
+
+impl Snapshot for Controller {
+    fn save_state(&self,state: &mut StateWriter) {
+        state.section(b"JOY ",|state| {
+            state.u8(self.buttons);
+            state.bool(self.strobe);
+            state.u8(self.shift_register);
+            state.u8(self.reads);
+        });
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        state.section(b"JOY ",|state| {
+            self.buttons = state.u8()?;
+            self.strobe = state.bool()?;
+            self.shift_register = state.u8()?;
+            self.reads = state.u8()?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_serial_read_order() {
+        let mut controller = Controller::new();
+        controller.set_buttons(BUTTON_A | BUTTON_START | BUTTON_RIGHT);
+        controller.write(1);
+        controller.write(0);
+        let bits: Vec<u8> = (0..10).map(|_| controller.read()).collect();
+        assert_eq!(bits,vec![1,0,0,1,0,0,0,1,1,1]);
+    }
+    #[test]
+    fn test_strobe_keeps_returning_a() {
+        let mut controller = Controller::new();
+        controller.write(1);
+        assert_eq!(controller.read(),0);
+        controller.set_button(BUTTON_A,true);
+        assert_eq!(controller.read(),1);
+        assert_eq!(controller.read(),1);
+        // buttons changed after the strobe was released are not seen until the next strobe
+        controller.write(0);
+        controller.set_button(BUTTON_B,true);
+        assert_eq!(controller.read(),1);
+        assert_eq!(controller.read(),0);
+    }
+}
@@ -1,41 +1,82 @@
-/// Program Counter (program_counter) :
-/// holds the address for the next machine language instruction to be executed.
-///
-/// Stack Pointer (stack_ptr):
-/// Memory space [0x0100 .. 0x1FF] is used for stack. The stack pointer holds the address of the top of that space. NES Stack (as all stacks) grows from top to bottom: when a byte gets pushed to the stack, SP register decrements. When a byte is retrieved from the stack, SP register increments.
-///
-/// Accumulator (accumulator):
-/// stores the results of arithmetic, logic, and memory access operations. It used as an input parameter for some operations.
-///
-/// Index Register X (register_x):
-/// used as an offset in specific memory addressing modes (more on this later). Can be used for auxiliary storage needs (holding temp values, being used as a counter, etc.)
-///
-/// Index Register Y (register_y):
-/// similar use cases as register X.
-///
-/// Processor status (status):
-/// 8-bit register represents 7 status flags that can be set or unset depending on the result of the last executed instruction (for example Z flag is set (1) if the result of an operation is 0, and is unset/erased (0) otherwise)
-///
-/// 6502 cpu instructions book: http://49.212.183.201/6502/6502_report.htm
+//! Program Counter (program_counter) :
+//! holds the address for the next machine language instruction to be executed.
+//!
+//! Stack Pointer (stack_ptr):
+//! Memory space [0x0100 .. 0x1FF] is used for stack. The stack pointer holds the address of the top of that space. NES Stack (as all stacks) grows from top to bottom: when a byte gets pushed to the stack, SP register decrements. When a byte is retrieved from the stack, SP register increments.
+//!
+//! Accumulator (accumulator):
+//! stores the results of arithmetic, logic, and memory access operations. It used as an input parameter for some operations.
+//!
+//! Index Register X (register_x):
+//! used as an offset in specific memory addressing modes (more on this later). Can be used for auxiliary storage needs (holding temp values, being used as a counter, etc.)
+//!
+//! Index Register Y (register_y):
+//! similar use cases as register X.
+//!
+//! Processor status (status):
+//! 8-bit register represents 7 status flags that can be set or unset depending on the result of the last executed instruction (for example Z flag is set (1) if the result of an operation is 0, and is unset/erased (0) otherwise)
+//!
+//! 6502 cpu instructions book: http://49.212.183.201/6502/6502_report.htm
 
-use std::borrow::Borrow;
-use std::num::FpCategory::Zero;
-use std::ops::Add;
 use crate::addressing_modes::AddrMode;
+use crate::bus::{Bus, NesBus};
+use crate::error::EmuError;
 use crate::ops_codes::*;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
 
-pub struct CPU {
+/// The 6502 family members the core can behave as.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum CpuVariant {
+    // the NES CPU: an NMOS 6502 with the decimal mode cut out
+    #[default]
+    Ricoh2A03,
+    // NMOS 6502 with BCD arithmetic, N, V and Z reflect the binary result the way the real chip does
+    Nmos6502,
+    // CMOS 65C02: new opcodes, valid N and Z in decimal mode, no JMP indirect page bug,
+    // D cleared on interrupts and the unofficial NMOS opcodes replaced by NOPs
+    Cmos65C02,
+}
+
+/// What one call to `CPU::step` did.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Step {
+    // address and opcode of the instruction that ran
+    pub addr: u16,
+    pub opcode: u8,
+    // the address the instruction read, wrote or jumped to,
+    // None for implied and accumulator instructions and for branches that were not taken
+    pub effective_addr: Option<u16>,
+    // cycles spent, including an interrupt serviced before the instruction and DMA stalls
+    pub cycles: u64,
+    // vector of the NMI or IRQ that was serviced before the instruction
+    pub interrupt: Option<u16>,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct CPU<B: Bus = NesBus> {
     pub accumulator: u8,
     pub register_x: u8,
     pub register_y: u8,
     pub stack_ptr: u8,
     pub status: u8,
     pub program_counter: u16,
-    pub memory: [u8; 0xFFFF],
+    pub bus: B,
+    pub variant: CpuVariant,
+    // total number of cpu cycles elapsed since power on
+    pub cycles: u64,
+    // NMI is edge triggered: a rising edge on the line latches a pending request
+    nmi_line: bool,
+    nmi_pending: bool,
+    // IRQ is level triggered: it keeps firing as long as the line is held and I is clear
+    irq_line: bool,
+    // effective address of the instruction being executed, reported by step
+    operand_addr: Option<u16>,
 }
 
 pub static NEGATIVE: u8 = 0b1000_0000;
 pub static OVERFLOW: u8 = 0b0100_0000;
+// bit 5 has no flag behind it, it always reads back as 1 when the status is pushed
+pub static UNUSED: u8 = 0b0010_0000;
 pub static BREAK: u8 = 0b0001_0000;
 pub static DECIMAL: u8 = 0b0000_1000;
 pub static INTERRUPT: u8 = 0b0000_0100;
@@ -45,8 +86,27 @@ pub static CARRY: u8 = 0b0000_0001;
 pub static STACK_PTR_START:u16 = 0x01FF;
 pub static STACK_PTR_END:u16 = 0x0100;
 
-impl CPU {
-    pub fn new() -> Self {
+// the reset sequence spends 7 cycles before the first instruction is fetched
+pub static RESET_CYCLES: u8 = 7;
+// NMI, IRQ and BRK all take 7 cycles to push the return state and load the vector
+pub static INTERRUPT_CYCLES: u8 = 7;
+
+// the unstable XAA and LXA opcodes OR the accumulator with a chip dependent constant first
+pub static UNSTABLE_MAGIC: u8 = 0xEE;
+
+pub static NMI_VECTOR: u16 = 0xFFFA;
+pub static RESET_VECTOR: u16 = 0xFFFC;
+pub static IRQ_VECTOR: u16 = 0xFFFE;
+
+// an NTSC frame is 89342 PPU dots, a bit less than 29781 CPU cycles
+pub static CYCLES_PER_FRAME: u64 = 29781;
+
+fn page_cross(a: u16,b: u16) -> bool {
+    a & 0xFF00 != b & 0xFF00
+}
+
+impl<B: Bus> CPU<B> {
+    pub fn new(bus: B) -> Self {
         CPU {
             accumulator: 0,
             register_x: 0,
@@ -54,7 +114,13 @@ impl CPU {
             stack_ptr: 0x00FD,
             status: 0,
             program_counter: 0,
-            memory: [0; 0xFFFF],
+            bus,
+            variant: CpuVariant::default(),
+            cycles: 0,
+            nmi_line: false,
+            nmi_pending: false,
+            irq_line: false,
+            operand_addr: None,
         }
     }
     // status op
@@ -82,9 +148,6 @@ impl CPU {
     fn clear_status_decimal(&mut self) {
         self.status &= !DECIMAL;
     }
-    fn set_status_break(&mut self) {
-        self.status |= BREAK;
-    }
     fn clear_status_break(&mut self) {
         self.status &= !BREAK;
     }
@@ -100,35 +163,136 @@ impl CPU {
     fn clear_status_negative(&mut self) {
         self.status &= !NEGATIVE;
     }
+    fn update_status_carry(&mut self,carry: bool) {
+        if carry {
+            self.set_status_carry();
+        }else {
+            self.clear_status_carry();
+        }
+    }
+    fn update_status_negative(&mut self,negative: bool) {
+        if negative {
+            self.set_status_negative();
+        }else {
+            self.clear_status_negative();
+        }
+    }
+    fn update_status_overflow(&mut self,overflow: bool) {
+        if overflow {
+            self.set_status_overflow();
+        }else {
+            self.clear_status_overflow();
+        }
+    }
     // memory op
-    fn memory_read(&self, pos: u16) -> u8 {
-        self.memory[pos as usize]
+    fn memory_read(&mut self, pos: u16) -> u8 {
+        self.bus.read(pos)
+    }
+    fn memory_read_u16(&mut self, pos: u16) -> u16 {
+        u16::from_le_bytes([self.memory_read(pos), self.memory_read(pos.wrapping_add(1))])
     }
-    fn memory_read_u16(&self, pos: u16) -> u16 {
-        u16::from_ne_bytes([self.memory[pos as usize], self.memory[(pos+1) as usize]])
+    // reads a pointer stored in the zero page, the high byte wraps around to $00 instead of crossing into page 1
+    fn memory_read_u16_zero_page(&mut self, pos: u8) -> u16 {
+        u16::from_le_bytes([self.memory_read(pos as u16), self.memory_read(pos.wrapping_add(1) as u16)])
     }
     fn memory_write(&mut self,pos: u16,data: u8) {
-        self.memory[pos as usize] = data;
+        self.bus.write(pos,data);
     }
     fn memory_write_u16(&mut self,pos: u16,data: u16) {
         let low = (data & 0xff) as u8;
         let high = (data >> 8) as u8;
         self.memory_write(pos,low);
-        self.memory_write(pos+1,high);
+        self.memory_write(pos.wrapping_add(1),high);
     }
     // other op
-    fn load_program(&mut self,program: Vec<u8>) {
-        self.memory[0x8000..(0x8000+program.len())].copy_from_slice(&program);
-        self.memory_write_u16(0xFFFC,0x8000);
+    pub fn load_program(&mut self,program: Vec<u8>) -> Result<(),EmuError> {
+        // [0x8000 .. 0xFFFF]
+        if program.len() > 0x8000 {
+            return Err(EmuError::RomTooLarge { size: program.len(), max: 0x8000 });
+        }
+        for (i,data) in program.iter().enumerate() {
+            self.memory_write(0x8000 + i as u16,*data);
+        }
+        self.memory_write_u16(RESET_VECTOR,0x8000);
+        Ok(())
     }
-    fn reset(&mut self) {
-        self.status = 0;
+    /// Powers the CPU up: A, X and Y start at 0, SP at 0 and only I is set, then the reset sequence runs,
+    /// which leaves SP at $FD. Memory is the bus's business, see `RamInit` for the NES.
+    pub fn power_on(&mut self) {
+        self.accumulator = 0;
         self.register_x = 0;
         self.register_y = 0;
-        self.accumulator = 0;
-        self.stack_ptr = 0x00FD;
-
-        self.program_counter = self.memory_read_u16(0xFFFC);
+        self.stack_ptr = 0;
+        self.status = INTERRUPT | UNUSED;
+        self.cycles = 0;
+        self.nmi_line = false;
+        self.irq_line = false;
+        self.reset();
+    }
+    /// The reset button: the registers keep their values, I is set and SP drops by 3 because the
+    /// interrupt sequence runs with its three stack writes turned into reads. The devices on the bus are reset too.
+    pub fn reset(&mut self) {
+        self.status |= INTERRUPT;
+        self.stack_ptr = self.stack_ptr.wrapping_sub(3);
+        self.bus.reset();
+        self.program_counter = self.memory_read_u16(RESET_VECTOR);
+        self.nmi_pending = false;
+        self.tick(RESET_CYCLES);
+    }
+    fn tick(&mut self,cycles: u8) {
+        self.cycles += cycles as u64;
+        self.bus.tick(cycles);
+        // DMA halts the CPU, the stolen cycles are spent here and may in turn trigger more DMA
+        let mut stall = self.bus.take_stall();
+        while stall > 0 {
+            let cycles = stall.min(u8::MAX as u16) as u8;
+            self.cycles += cycles as u64;
+            self.bus.tick(cycles);
+            stall = stall - cycles as u16 + self.bus.take_stall();
+        }
+    }
+    // interrupt op
+    /// Drives the NMI line, a request is latched on the transition from inactive to active.
+    pub fn set_nmi(&mut self,active: bool) {
+        if active && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = active;
+    }
+    /// Latches an NMI request regardless of the line state, e.g. for a PPU entering vblank.
+    pub fn trigger_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+    /// Drives the IRQ line, the request stays active until the source acknowledges it.
+    pub fn set_irq(&mut self,active: bool) {
+        self.irq_line = active;
+    }
+    // pushes the return address and status, then jumps through the given vector
+    fn interrupt(&mut self,vector: u16,return_addr: u16,status: u8) {
+        self.stack_push_u16(return_addr);
+        self.stack_push(status);
+        self.set_status_interrupt();
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.clear_status_decimal();
+        }
+        self.program_counter = self.memory_read_u16(vector);
+        self.tick(INTERRUPT_CYCLES);
+    }
+    // services a pending NMI or an unmasked IRQ, returns the vector of the one that was taken
+    fn poll_interrupts(&mut self) -> Option<u16> {
+        if self.bus.poll_nmi() {
+            self.nmi_pending = true;
+        }
+        let vector = if self.nmi_pending {
+            self.nmi_pending = false;
+            NMI_VECTOR
+        }else if (self.irq_line || self.bus.irq_line()) && self.status & INTERRUPT == 0 {
+            IRQ_VECTOR
+        }else {
+            return None;
+        };
+        self.interrupt(vector,self.program_counter,(self.status & !BREAK) | UNUSED);
+        Some(vector)
     }
     fn calc_token(&mut self,result: u8) {
         // Token:Z -> This bit is set when the 7th binary bit of ops_code is 0. Otherwise it will be cleared.
@@ -144,46 +308,71 @@ impl CPU {
             self.clear_status_negative();
         }
     }
-    fn get_operand_addr(&mut self,mode: &AddrMode) -> u16 {
-        match mode {
-            AddrMode::Immediate => self.program_counter,
-            AddrMode::Absolute => self.memory_read_u16(self.program_counter),
+    // page_cross reports whether indexing moved the effective address into another page,
+    // reading instructions pay one extra cycle for that
+    fn get_operand_addr(&mut self,mode: &AddrMode) -> Result<(u16,bool),EmuError> {
+        let operand = match mode {
+            AddrMode::Immediate => (self.program_counter,false),
+            AddrMode::Absolute => (self.memory_read_u16(self.program_counter),false),
             AddrMode::AbsoluteX => {
                 let base = self.memory_read_u16(self.program_counter);
-                base.wrapping_add(self.register_x as u16)
+                let addr = base.wrapping_add(self.register_x as u16);
+                (addr,page_cross(base,addr))
             },
             AddrMode::AbsoluteY => {
                 let base = self.memory_read_u16(self.program_counter);
-                base.wrapping_add(self.register_y as u16)
+                let addr = base.wrapping_add(self.register_y as u16);
+                (addr,page_cross(base,addr))
             },
             AddrMode::Indirect => {
-                let base = self.memory_read(self.program_counter);
-                let ptr = base.wrapping_add(self.register_x) as u16;
-                self.memory_read_u16(ptr)
+                let addr = self.memory_read_u16(self.program_counter);
+                // if addr ends with FF, the high byte is fetched from the start of the same page, the 65C02 fixed that
+                let target = if addr & 0x00FF == 0x00FF && self.variant != CpuVariant::Cmos65C02 {
+                    let low_bits = self.memory_read(addr);
+                    let high_bits = self.memory_read(addr & 0xFF00);
+                    (high_bits as u16) << 8 | (low_bits as u16)
+                }else {
+                    self.memory_read_u16(addr)
+                };
+                (target,false)
             },
             AddrMode::IndirectX => {
                 let base = self.memory_read(self.program_counter);
-                let ptr = base.wrapping_add(self.register_x) as u16;
-                self.memory_read_u16(ptr)
+                let ptr = base.wrapping_add(self.register_x);
+                (self.memory_read_u16_zero_page(ptr),false)
             },
             AddrMode::IndirectY => {
                 let base = self.memory_read(self.program_counter);
-                let addr = base.wrapping_add(base) as u16;
-                addr.wrapping_add(self.register_y as u16)
+                let deref = self.memory_read_u16_zero_page(base);
+                let addr = deref.wrapping_add(self.register_y as u16);
+                (addr,page_cross(deref,addr))
             },
-            AddrMode::ZeroPage => self.memory_read(self.program_counter) as u16,
-            AddrMode::ZeroPageX => self.memory_read(self.program_counter).wrapping_add(self.register_x) as u16,
-            AddrMode::ZeroPageY => self.memory_read(self.program_counter).wrapping_add(self.register_y) as u16,
-            AddrMode::Accumulator => {
-                panic!("mode accumulator is not supported");
+            AddrMode::ZeroPageIndirect => {
+                let base = self.memory_read(self.program_counter);
+                (self.memory_read_u16_zero_page(base),false)
             },
-            AddrMode::Implied => {
-                panic!("mode implied is not supported");
+            AddrMode::AbsoluteIndirectX => {
+                let base = self.memory_read_u16(self.program_counter);
+                (self.memory_read_u16(base.wrapping_add(self.register_x as u16)),false)
             },
-            AddrMode::Relative => {
-                panic!("mode relative is not supported");
+            AddrMode::ZeroPage => (self.memory_read(self.program_counter) as u16,false),
+            AddrMode::ZeroPageX => (self.memory_read(self.program_counter).wrapping_add(self.register_x) as u16,false),
+            AddrMode::ZeroPageY => (self.memory_read(self.program_counter).wrapping_add(self.register_y) as u16,false),
+            // the program counter already moved past the opcode
+            AddrMode::Accumulator | AddrMode::Implied | AddrMode::Relative => {
+                return Err(EmuError::InvalidAddressingMode { mode: *mode, addr: self.program_counter.wrapping_sub(1) });
             },
+        };
+        self.operand_addr = Some(operand.0);
+        Ok(operand)
+    }
+    // fetches the operand of a reading instruction and charges the page crossing penalty
+    fn read_operand(&mut self,mode: &AddrMode) -> Result<u8,EmuError> {
+        let (addr,page_cross) = self.get_operand_addr(mode)?;
+        if page_cross {
+            self.tick(1);
         }
+        Ok(self.memory_read(addr))
     }
     fn set_accumulator(&mut self,data: u8) {
         self.accumulator = data;
@@ -196,43 +385,102 @@ impl CPU {
             0
         };
         let sum = self.accumulator as u16 + data as u16 + carry_out;
-        if sum > 255 {
-            self.set_status_carry();
-        }else {
-            self.clear_status_carry();
-        }
+        self.update_status_carry(sum > 255);
 
         let sum = sum as u8;
-        if (sum ^ data) & (sum ^ self.accumulator) & 0b1000_0000 != 0 {
-            self.set_status_overflow();
+        self.update_status_overflow((sum ^ data) & (sum ^ self.accumulator) & 0b1000_0000 != 0);
+        self.set_accumulator(sum);
+    }
+    fn decimal_mode(&self) -> bool {
+        self.status & DECIMAL != 0 && self.variant != CpuVariant::Ricoh2A03
+    }
+    // ADC and everything built on it, decimal mode follows Bruce Clark's "Decimal Mode" appendix A:
+    // http://www.6502.org/tutorials/decimal_mode.html
+    fn add_with_carry(&mut self,data: u8) {
+        if !self.decimal_mode() {
+            self.add_to_accumulator(data);
+            return;
+        }
+        let (a,b,carry) = (self.accumulator as i16,data as i16,(self.status & CARRY) as i16);
+        let mut low = (a & 0x0F) + (b & 0x0F) + carry;
+        if low >= 0x0A {
+            low = ((low + 0x06) & 0x0F) + 0x10;
+        }
+        // N and V come from the sum before the high nibble is adjusted, signed
+        let signed = (a as u8 as i8 as i16 & !0x0F) + (b as u8 as i8 as i16 & !0x0F) + low;
+        let mut sum = (a & 0xF0) + (b & 0xF0) + low;
+        if sum >= 0xA0 {
+            sum += 0x60;
+        }
+        let binary = self.accumulator.wrapping_add(data).wrapping_add(carry as u8);
+        self.accumulator = sum as u8;
+        self.update_status_carry(sum >= 0x100);
+        self.update_status_overflow(!(-128..=127).contains(&signed));
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.calc_token(self.accumulator);
+            self.tick(1);
         }else {
-            self.clear_status_overflow();
+            self.calc_token(binary);
+            self.update_status_negative(signed & 0x80 != 0);
+        }
+    }
+    // SBC and everything built on it, flags always come from the binary subtraction except N and Z on the 65C02
+    fn subtract_with_borrow(&mut self,data: u8) {
+        let decimal = self.decimal_mode();
+        let (a,b,borrow) = (self.accumulator as i16,data as i16,1 - (self.status & CARRY) as i16);
+        self.add_to_accumulator(!data);
+        if !decimal {
+            return;
+        }
+        let low = (a & 0x0F) - (b & 0x0F) - borrow;
+        let result = if self.variant == CpuVariant::Cmos65C02 {
+            let mut result = a - b - borrow;
+            if result < 0 {
+                result -= 0x60;
+            }
+            if low < 0 {
+                result -= 0x06;
+            }
+            result
+        }else {
+            let low = if low < 0 { ((low - 0x06) & 0x0F) - 0x10 } else { low };
+            let result = (a & 0xF0) - (b & 0xF0) + low;
+            if result < 0 { result - 0x60 } else { result }
+        };
+        self.accumulator = result as u8;
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.calc_token(self.accumulator);
+            self.tick(1);
         }
-        self.set_accumulator(data);
     }
     // branch specifies the target of conditional transfer.
     // The second byte of the instruction becomes an operand
     // and is added as an offset to the instruction pointer to the next instruction.
+    // A taken branch costs one more cycle, and another one if the target is on a different page.
     fn branch(&mut self) {
         let offset = self.memory_read(self.program_counter) as i8;
-        let next = self.program_counter.wrapping_add(1).wrapping_add(offset as u16);
+        let fallthrough = self.program_counter.wrapping_add(1);
+        let next = fallthrough.wrapping_add(offset as u16);
+        self.operand_addr = Some(next);
+        self.tick(1);
+        if page_cross(fallthrough,next) {
+            self.tick(1);
+        }
         self.program_counter = next;
     }
-    fn compare(&mut self,register: u8,mode: &AddrMode) {
-        let addr = self.get_operand_addr(mode);
-        let data = self.memory_read(addr);
-        if data <= register {
-            self.set_status_carry();
-        }else {
-            self.clear_status_carry();
-        }
+    fn compare(&mut self,register: u8,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = self.read_operand(mode)?;
+        self.compare_value(register,data);
+        Ok(())
+    }
+    fn compare_value(&mut self,register: u8,data: u8) {
+        self.update_status_carry(data <= register);
         self.calc_token(register.wrapping_sub(data));
     }
     // stack op
     fn stack_pop(&mut self) -> u8 {
         self.stack_ptr = self.stack_ptr.wrapping_add(1);
-        let res = self.memory_read(STACK_PTR_END + self.stack_ptr as u16);
-        res
+        self.memory_read(STACK_PTR_END + self.stack_ptr as u16)
     }
     fn stack_pop_u16(&mut self) -> u16 {
         let low_bits = self.stack_pop() as u16;
@@ -249,96 +497,100 @@ impl CPU {
         self.stack_push(high_bits);
         self.stack_push(low_bits);
     }
-    // 6502 instructions
-    fn adc(&mut self,mode: &AddrMode) {
-        let addr = self.get_operand_addr(mode);
-        let data = self.memory_read(addr);
-        self.add_to_accumulator(data);
-    }
-    fn and(&mut self,mode: &AddrMode) {
-        let addr = self.get_operand_addr(mode);
-        let data = self.memory_read(addr);
-        self.set_accumulator(self.accumulator & data);
-    }
-    fn asl(&mut self,mode: &AddrMode) {
+    // shift op
+    // reads the operand of a shift/rotate instruction, which is either the accumulator or a memory cell
+    fn read_shift_operand(&mut self,mode: &AddrMode) -> Result<(Option<u16>,u8),EmuError> {
         match mode {
-            AddrMode::Accumulator => {
-                if self.accumulator >> 7 & 1 != 0 {
-                    self.set_status_carry();
-                }else {
-                    self.clear_status_carry();
-                }
-                self.set_accumulator(self.accumulator << 1)
-            },
+            AddrMode::Accumulator => Ok((None,self.accumulator)),
             _ => {
-                let addr = self.get_operand_addr(mode);
-                let data = self.memory_read(addr);
-                if data >> 7 & 1 != 0 {
-                    self.set_status_carry();
-                }else {
-                    self.clear_status_carry();
-                }
+                let (addr,_) = self.get_operand_addr(mode)?;
+                Ok((Some(addr),self.memory_read(addr)))
+            }
+        }
+    }
+    // the unofficial read-modify-write combos only ever work on memory
+    fn read_memory_operand(&mut self,mode: &AddrMode) -> Result<(u16,u8),EmuError> {
+        let (addr,_) = self.get_operand_addr(mode)?;
+        Ok((addr,self.memory_read(addr)))
+    }
+    fn write_shift_result(&mut self,addr: Option<u16>,data: u8) {
+        match addr {
+            None => self.set_accumulator(data),
+            Some(addr) => {
                 self.memory_write(addr,data);
                 self.calc_token(data);
             }
         }
     }
+    // 6502 instructions
+    fn adc(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = self.read_operand(mode)?;
+        self.add_with_carry(data);
+        Ok(())
+    }
+    fn and(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = self.read_operand(mode)?;
+        self.set_accumulator(self.accumulator & data);
+        Ok(())
+    }
+    fn asl(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,data) = self.read_shift_operand(mode)?;
+        self.update_status_carry(data >> 7 & 1 != 0);
+        self.write_shift_result(addr,data << 1);
+        Ok(())
+    }
     fn bcc(&mut self) {
-        if self.status & 1 == 0 {
+        if self.status & CARRY == 0 {
             self.branch();
         }
     }
     fn bcs(&mut self) {
-        if self.status & 1 != 0 {
+        if self.status & CARRY != 0 {
             self.branch();
         }
     }
     fn beq(&mut self) {
-        if self.status >> 1 & 1 != 0 {
+        if self.status & ZERO != 0 {
             self.branch()
         }
     }
     fn bne(&mut self) {
-        if self.status >> 1 & 1 == 0 {
+        if self.status & ZERO == 0 {
             self.branch();
         }
     }
-    fn bit(&mut self,mode: &AddrMode) {
-        let addr = self.get_operand_addr(mode);
-        let data = self.memory_read(addr);
+    fn bit(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = self.read_operand(mode)?;
         if data >> 7 & 1 != 0 {
             self.set_status_negative();
         }else {
             self.clear_status_negative();
         }
-        if data >> 6 & 1 != 0 {
-            self.set_status_overflow();
+        self.update_status_overflow(data >> 6 & 1 != 0);
+        if self.accumulator & data == 0 {
+            self.set_status_zero();
         }else {
-            self.clear_status_overflow();
-        }
-        if !(self.accumulator & data) == 0 {
             self.clear_status_zero();
-        }else {
-            self.set_status_zero();
         }
+        Ok(())
     }
     fn bmi(&mut self) {
-        if self.status >> 7 & 1 != 0 {
+        if self.status & NEGATIVE != 0 {
             self.branch();
         }
     }
     fn bpl(&mut self) {
-        if self.status >> 7 & 1 == 0 {
+        if self.status & NEGATIVE == 0 {
             self.branch();
         }
     }
     fn bvc(&mut self) {
-        if self.status >> 6 & 1 == 0 {
+        if self.status & OVERFLOW == 0 {
             self.branch();
         }
     }
     fn bvs(&mut self) {
-        if self.status >> 6 & 1 != 0 {
+        if self.status & OVERFLOW != 0 {
             self.branch();
         }
     }
@@ -354,20 +606,21 @@ impl CPU {
     fn clv(&mut self) {
         self.clear_status_overflow();
     }
-    fn cmp(&mut self,mode: &AddrMode) {
-        self.compare(self.accumulator,mode);
+    fn cmp(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        self.compare(self.accumulator,mode)
     }
-    fn cpx(&mut self, mode: &AddrMode) {
-        self.compare(self.register_x,mode);
+    fn cpx(&mut self, mode: &AddrMode) -> Result<(),EmuError> {
+        self.compare(self.register_x,mode)
     }
-    fn cpy(&mut self,mode: &AddrMode) {
-        self.compare(self.register_y,mode);
+    fn cpy(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        self.compare(self.register_y,mode)
     }
-    fn dec(&mut self,mode: &AddrMode) {
-        let addr = self.get_operand_addr(mode);
+    fn dec(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,_) = self.get_operand_addr(mode)?;
         let data = self.memory_read(addr).wrapping_sub(1);
         self.memory_write(addr,data);
         self.calc_token(data);
+        Ok(())
     }
     fn dex(&mut self) {
         self.register_x = self.register_x.wrapping_sub(1);
@@ -377,50 +630,146 @@ impl CPU {
         self.register_y = self.register_y.wrapping_sub(1);
         self.calc_token(self.register_y);
     }
-    fn eor(&mut self,mode: &AddrMode) {
-        let addr = self.get_operand_addr(mode);
-        let data = self.memory_read(addr);
-        self.accumulator = self.accumulator ^ data;
-        self.calc_token(self.accumulator);
+    fn eor(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = self.read_operand(mode)?;
+        self.set_accumulator(self.accumulator ^ data);
+        Ok(())
     }
-    fn inc(&mut self,mode: &AddrMode) {
-        let addr = self.get_operand_addr(mode);
+    fn inc(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,_) = self.get_operand_addr(mode)?;
         let data = self.memory_read(addr).wrapping_add(1);
         self.memory_write(addr,data);
         self.calc_token(data);
+        Ok(())
     }
     fn inx(&mut self) {
-        self.register_x.wrapping_add(1);
+        self.register_x = self.register_x.wrapping_add(1);
         self.calc_token(self.register_x);
     }
     fn iny(&mut self) {
-        self.register_y.wrapping_add(1);
+        self.register_y = self.register_y.wrapping_add(1);
         self.calc_token(self.register_y);
     }
-    fn jmp(&mut self,mode :&AddrMode) {
-        match mode {
-            AddrMode::Absolute => self.program_counter = self.memory_read_u16(self.program_counter),
-            _ => {
-                let addr = self.memory_read_u16(self.program_counter);
-                // if addr ends with FF, the high and low bits are processed respectively
-                self.program_counter = if addr & 0x00FF == 0x00FF {
-                    let low_bits = self.memory_read(addr);
-                    let high_bits = self.memory_read(addr & 0xFF00);
-                    (high_bits as u16) << 8 | (low_bits as u16)
-                }else {
-                    self.memory_read_u16(addr)
-                };
-            }
-        }
+    fn jmp(&mut self,mode :&AddrMode) -> Result<(),EmuError> {
+        self.program_counter = match mode {
+            AddrMode::Absolute => self.memory_read_u16(self.program_counter),
+            _ => self.get_operand_addr(mode)?.0,
+        };
+        self.operand_addr = Some(self.program_counter);
+        Ok(())
+    }
+    // JSR pushes the address of its own last byte, RTS adds the missing 1 back
+    fn jsr(&mut self) {
+        let target = self.memory_read_u16(self.program_counter);
+        self.stack_push_u16(self.program_counter.wrapping_add(1));
+        self.program_counter = target;
+        self.operand_addr = Some(target);
+    }
+    fn lda(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = self.read_operand(mode)?;
+        self.set_accumulator(data);
+        Ok(())
     }
-    fn jsr(&mut self,mode: &AddrMode) {
-        let data = self.memory_read_u16(self.program_counter);
+    fn ldx(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        self.register_x = self.read_operand(mode)?;
+        self.calc_token(self.register_x);
+        Ok(())
     }
-    fn lda(&mut self,mode: &AddrMode) {
-        let addr = self.get_operand_addr(mode);
-        let data = self.memory_read(addr);
+    fn ldy(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        self.register_y = self.read_operand(mode)?;
+        self.calc_token(self.register_y);
+        Ok(())
+    }
+    fn lsr(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,data) = self.read_shift_operand(mode)?;
+        self.update_status_carry(data & 1 != 0);
+        self.write_shift_result(addr,data >> 1);
+        Ok(())
+    }
+    fn ora(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = self.read_operand(mode)?;
+        self.set_accumulator(self.accumulator | data);
+        Ok(())
+    }
+    fn pha(&mut self) {
+        self.stack_push(self.accumulator);
+    }
+    // PHP always pushes the status with the B flag and bit 5 set
+    fn php(&mut self) {
+        self.stack_push(self.status | BREAK | UNUSED);
+    }
+    fn pla(&mut self) {
+        let data = self.stack_pop();
         self.set_accumulator(data);
     }
+    // B is not a real flag, so it is dropped when the status comes back from the stack
+    fn plp(&mut self) {
+        self.status = self.stack_pop();
+        self.clear_status_break();
+        self.status |= UNUSED;
+    }
+    fn rol(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,data) = self.read_shift_operand(mode)?;
+        let carry_in = self.status & CARRY;
+        self.update_status_carry(data >> 7 & 1 != 0);
+        self.write_shift_result(addr,data << 1 | carry_in);
+        Ok(())
+    }
+    fn ror(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,data) = self.read_shift_operand(mode)?;
+        let carry_in = (self.status & CARRY) << 7;
+        self.update_status_carry(data & 1 != 0);
+        self.write_shift_result(addr,data >> 1 | carry_in);
+        Ok(())
+    }
+    // BRK skips a padding byte, so the handler returns to PC + 2 with B set in the pushed status
+    fn brk(&mut self) {
+        let return_addr = self.program_counter.wrapping_add(1);
+        self.stack_push_u16(return_addr);
+        self.stack_push(self.status | BREAK | UNUSED);
+        self.set_status_interrupt();
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.clear_status_decimal();
+        }
+        self.program_counter = self.memory_read_u16(IRQ_VECTOR);
+    }
+    fn rti(&mut self) {
+        self.plp();
+        self.program_counter = self.stack_pop_u16();
+    }
+    fn rts(&mut self) {
+        self.program_counter = self.stack_pop_u16().wrapping_add(1);
+    }
+    // A - M - (1 - C) is the same as A + !M + C
+    fn sbc(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = self.read_operand(mode)?;
+        self.subtract_with_borrow(data);
+        Ok(())
+    }
+    fn sec(&mut self) {
+        self.set_status_carry();
+    }
+    fn sed(&mut self) {
+        self.set_status_deciaml();
+    }
+    fn sei(&mut self) {
+        self.set_status_interrupt();
+    }
+    fn sta(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,_) = self.get_operand_addr(mode)?;
+        self.memory_write(addr,self.accumulator);
+        Ok(())
+    }
+    fn stx(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,_) = self.get_operand_addr(mode)?;
+        self.memory_write(addr,self.register_x);
+        Ok(())
+    }
+    fn sty(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,_) = self.get_operand_addr(mode)?;
+        self.memory_write(addr,self.register_y);
+        Ok(())
+    }
     fn tax(&mut self) {
         self.register_x = self.accumulator;
         self.calc_token(self.register_x);
@@ -429,54 +778,993 @@ impl CPU {
         self.register_y = self.accumulator;
         self.calc_token(self.register_y);
     }
-    fn sta(&mut self,mode: &AddrMode) {
-        let addr = self.get_operand_addr(mode);
-        self.memory_write(addr,self.accumulator);
+    fn tsx(&mut self) {
+        self.register_x = self.stack_ptr;
+        self.calc_token(self.register_x);
     }
-    pub fn run(&mut self) {
-        loop {
-            let ops_addr = self.memory_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_backup = self.program_counter;
-            let ops_code = OpCodesMap.get(&ops_addr).unwrap();
-            let mode = ops_code.addressing_mode.borrow();
-            match ops_code.opc {
-                // LDA: load data into accumulator
-                0xA9 | 0xA5 |0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1  => {
-                    self.lda(mode);
-                },
-                // STA: Store Accumulator in Memory
-                0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => {
-                    self.sta(mode);
-                }
-                // BRK: force break
-                0x00 => return,
-                // transfer accumulator to register_x index
-                0xAA => self.tax(),
-                // increment register_x index
-                0xE8 => self.inx(),
-                _ => todo!()
-            }
-            // If the program counter is modified in the opcode, it will not be processed separately
-            if self.program_counter == program_counter_backup {
-                self.program_counter += (ops_code.bytes - 1) as u16;
+    fn txa(&mut self) {
+        self.set_accumulator(self.register_x);
+    }
+    // TXS is the only transfer that leaves the flags alone
+    fn txs(&mut self) {
+        self.stack_ptr = self.register_x;
+    }
+    fn tya(&mut self) {
+        self.set_accumulator(self.register_y);
+    }
+    // unofficial instructions
+    fn alr(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = self.read_operand(mode)? & self.accumulator;
+        self.update_status_carry(data & 1 != 0);
+        self.set_accumulator(data >> 1);
+        Ok(())
+    }
+    fn anc(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = self.read_operand(mode)?;
+        self.set_accumulator(self.accumulator & data);
+        self.update_status_carry(self.status & NEGATIVE != 0);
+        Ok(())
+    }
+    fn arr(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = self.read_operand(mode)? & self.accumulator;
+        let result = data >> 1 | (self.status & CARRY) << 7;
+        self.set_accumulator(result);
+        self.update_status_carry(result & 0b0100_0000 != 0);
+        self.update_status_overflow(((result >> 6) ^ (result >> 5)) & 1 != 0);
+        Ok(())
+    }
+    fn axs(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = self.read_operand(mode)?;
+        let value = self.accumulator & self.register_x;
+        self.update_status_carry(data <= value);
+        self.register_x = value.wrapping_sub(data);
+        self.calc_token(self.register_x);
+        Ok(())
+    }
+    fn dcp(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,_) = self.get_operand_addr(mode)?;
+        let data = self.memory_read(addr).wrapping_sub(1);
+        self.memory_write(addr,data);
+        self.compare_value(self.accumulator,data);
+        Ok(())
+    }
+    fn isc(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,_) = self.get_operand_addr(mode)?;
+        let data = self.memory_read(addr).wrapping_add(1);
+        self.memory_write(addr,data);
+        self.subtract_with_borrow(data);
+        Ok(())
+    }
+    // the CPU keeps fetching the same opcode forever, only a reset gets it out
+    fn jam(&mut self,opcode: u8) -> Result<(),EmuError> {
+        self.program_counter = self.program_counter.wrapping_sub(1);
+        Err(EmuError::Jammed { opcode, addr: self.program_counter })
+    }
+    fn las(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = self.read_operand(mode)? & self.stack_ptr;
+        self.register_x = data;
+        self.stack_ptr = data;
+        self.set_accumulator(data);
+        Ok(())
+    }
+    fn lax(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = match mode {
+            AddrMode::Immediate => (self.accumulator | UNSTABLE_MAGIC) & self.read_operand(mode)?,
+            _ => self.read_operand(mode)?,
+        };
+        self.register_x = data;
+        self.set_accumulator(data);
+        Ok(())
+    }
+    // the extra NOPs still perform the read of their addressing mode, page crossing included
+    fn nop(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        if !matches!(mode,AddrMode::Implied) {
+            self.read_operand(mode)?;
+        }
+        Ok(())
+    }
+    fn rla(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,data) = self.read_memory_operand(mode)?;
+        let result = data << 1 | (self.status & CARRY);
+        self.update_status_carry(data >> 7 & 1 != 0);
+        self.memory_write(addr,result);
+        self.set_accumulator(self.accumulator & result);
+        Ok(())
+    }
+    fn rra(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,data) = self.read_memory_operand(mode)?;
+        let result = data >> 1 | (self.status & CARRY) << 7;
+        self.update_status_carry(data & 1 != 0);
+        self.memory_write(addr,result);
+        self.add_with_carry(result);
+        Ok(())
+    }
+    fn sax(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,_) = self.get_operand_addr(mode)?;
+        self.memory_write(addr,self.accumulator & self.register_x);
+        Ok(())
+    }
+    fn slo(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,data) = self.read_memory_operand(mode)?;
+        self.update_status_carry(data >> 7 & 1 != 0);
+        self.memory_write(addr,data << 1);
+        self.set_accumulator(self.accumulator | data << 1);
+        Ok(())
+    }
+    fn sre(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,data) = self.read_memory_operand(mode)?;
+        self.update_status_carry(data & 1 != 0);
+        self.memory_write(addr,data >> 1);
+        self.set_accumulator(self.accumulator ^ data >> 1);
+        Ok(())
+    }
+    // SHA, SHX, SHY and TAS store data & (high byte of the base address + 1), and when indexing crosses a page
+    // that value also replaces the high byte of the address written to
+    fn store_high_and(&mut self,mode: &AddrMode,index: u8,data: u8) -> Result<(),EmuError> {
+        let (addr,page_crossed) = self.get_operand_addr(mode)?;
+        let base = addr.wrapping_sub(index as u16);
+        let value = data & ((base >> 8) as u8).wrapping_add(1);
+        let addr = if page_crossed { (value as u16) << 8 | (addr & 0x00FF) } else { addr };
+        self.memory_write(addr,value);
+        Ok(())
+    }
+    fn sha(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        self.store_high_and(mode,self.register_y,self.accumulator & self.register_x)
+    }
+    fn shx(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        self.store_high_and(mode,self.register_y,self.register_x)
+    }
+    fn shy(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        self.store_high_and(mode,self.register_x,self.register_y)
+    }
+    fn tas(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        self.stack_ptr = self.accumulator & self.register_x;
+        self.store_high_and(mode,self.register_y,self.stack_ptr)
+    }
+    fn xaa(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = self.read_operand(mode)?;
+        self.set_accumulator((self.accumulator | UNSTABLE_MAGIC) & self.register_x & data);
+        Ok(())
+    }
+    // 65C02 instructions
+    fn bra(&mut self) {
+        self.branch();
+    }
+    // BIT #imm only has an accumulator to test against, N and V are left alone
+    fn bit_immediate(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let data = self.read_operand(mode)?;
+        if self.accumulator & data == 0 {
+            self.set_status_zero();
+        }else {
+            self.clear_status_zero();
+        }
+        Ok(())
+    }
+    fn dea(&mut self) {
+        self.set_accumulator(self.accumulator.wrapping_sub(1));
+    }
+    fn ina(&mut self) {
+        self.set_accumulator(self.accumulator.wrapping_add(1));
+    }
+    fn phx(&mut self) {
+        self.stack_push(self.register_x);
+    }
+    fn phy(&mut self) {
+        self.stack_push(self.register_y);
+    }
+    fn plx(&mut self) {
+        self.register_x = self.stack_pop();
+        self.calc_token(self.register_x);
+    }
+    fn ply(&mut self) {
+        self.register_y = self.stack_pop();
+        self.calc_token(self.register_y);
+    }
+    fn stz(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,_) = self.get_operand_addr(mode)?;
+        self.memory_write(addr,0);
+        Ok(())
+    }
+    // TRB and TSB set Z like BIT does, then clear or set the accumulator bits in memory
+    fn trb(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,_) = self.get_operand_addr(mode)?;
+        let data = self.memory_read(addr);
+        if self.accumulator & data == 0 { self.set_status_zero() } else { self.clear_status_zero() }
+        self.memory_write(addr,data & !self.accumulator);
+        Ok(())
+    }
+    fn tsb(&mut self,mode: &AddrMode) -> Result<(),EmuError> {
+        let (addr,_) = self.get_operand_addr(mode)?;
+        let data = self.memory_read(addr);
+        if self.accumulator & data == 0 { self.set_status_zero() } else { self.clear_status_zero() }
+        self.memory_write(addr,data | self.accumulator);
+        Ok(())
+    }
+    // runs the opcodes whose meaning differs on the 65C02, returns false for the ones shared with the NMOS core
+    fn execute_cmos(&mut self,ops_code: &OpCode) -> Result<bool,EmuError> {
+        let mode = &ops_code.addressing_mode;
+        match ops_code.opc {
+            0x80 => self.bra(),
+            0xDA => self.phx(),
+            0xFA => self.plx(),
+            0x5A => self.phy(),
+            0x7A => self.ply(),
+            0x64 | 0x74 | 0x9C | 0x9E => self.stz(mode)?,
+            0x14 | 0x1C => self.trb(mode)?,
+            0x04 | 0x0C => self.tsb(mode)?,
+            0x1A => self.ina(),
+            0x3A => self.dea(),
+            0x89 => self.bit_immediate(mode)?,
+            0x34 | 0x3C => self.bit(mode)?,
+            0x7C => self.jmp(mode)?,
+            0x12 => self.ora(mode)?,
+            0x32 => self.and(mode)?,
+            0x52 => self.eor(mode)?,
+            0x72 => self.adc(mode)?,
+            0x92 => self.sta(mode)?,
+            0xB2 => self.lda(mode)?,
+            0xD2 => self.cmp(mode)?,
+            0xF2 => self.sbc(mode)?,
+            _ if !ops_code.official => self.nop(mode)?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+    // runs one NMOS opcode, the program counter points at the byte after it
+    fn execute(&mut self,ops_code: &OpCode) -> Result<(),EmuError> {
+        let mode = &ops_code.addressing_mode;
+        match ops_code.opc {
+            // ADC: Add Memory to Accumulator with Carry
+            0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => self.adc(mode)?,
+            // AND: AND Memory with Accumulator
+            0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => self.and(mode)?,
+            // ASL: Shift Left One Bit (Memory or Accumulator)
+            0x0A | 0x06 | 0x16 | 0x0E | 0x1E => self.asl(mode)?,
+            // branches
+            0x90 => self.bcc(),
+            0xB0 => self.bcs(),
+            0xF0 => self.beq(),
+            0xD0 => self.bne(),
+            0x30 => self.bmi(),
+            0x10 => self.bpl(),
+            0x50 => self.bvc(),
+            0x70 => self.bvs(),
+            // BIT: Test Bits in Memory with Accumulator
+            0x24 | 0x2C => self.bit(mode)?,
+            // BRK: force break
+            0x00 => self.brk(),
+            // flag clear/set
+            0x18 => self.clc(),
+            0xD8 => self.cld(),
+            0x58 => self.cli(),
+            0xB8 => self.clv(),
+            0x38 => self.sec(),
+            0xF8 => self.sed(),
+            0x78 => self.sei(),
+            // CMP: Compare Memory with Accumulator
+            0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => self.cmp(mode)?,
+            // CPX: Compare Memory and Index X
+            0xE0 | 0xE4 | 0xEC => self.cpx(mode)?,
+            // CPY: Compare Memory and Index Y
+            0xC0 | 0xC4 | 0xCC => self.cpy(mode)?,
+            // DEC: Decrement Memory by One
+            0xC6 | 0xD6 | 0xCE | 0xDE => self.dec(mode)?,
+            // decrement register index
+            0xCA => self.dex(),
+            0x88 => self.dey(),
+            // EOR: Exclusive-OR Memory with Accumulator
+            0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => self.eor(mode)?,
+            // INC: Increment Memory by One
+            0xE6 | 0xF6 | 0xEE | 0xFE => self.inc(mode)?,
+            // increment register index
+            0xE8 => self.inx(),
+            0xC8 => self.iny(),
+            // JMP: Jump to New Location
+            0x4C | 0x6C => self.jmp(mode)?,
+            // JSR: Jump to New Location Saving Return Address
+            0x20 => self.jsr(),
+            // LDA: load data into accumulator
+            0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => self.lda(mode)?,
+            // LDX: Load Index X with Memory
+            0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => self.ldx(mode)?,
+            // LDY: Load Index Y with Memory
+            0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => self.ldy(mode)?,
+            // LSR: Shift One Bit Right (Memory or Accumulator)
+            0x4A | 0x46 | 0x56 | 0x4E | 0x5E => self.lsr(mode)?,
+            // NOP: No Operation
+            0xEA => {},
+            // ORA: OR Memory with Accumulator
+            0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => self.ora(mode)?,
+            // stack push/pull
+            0x48 => self.pha(),
+            0x08 => self.php(),
+            0x68 => self.pla(),
+            0x28 => self.plp(),
+            // ROL: Rotate One Bit Left (Memory or Accumulator)
+            0x2A | 0x26 | 0x36 | 0x2E | 0x3E => self.rol(mode)?,
+            // ROR: Rotate One Bit Right (Memory or Accumulator)
+            0x6A | 0x66 | 0x76 | 0x6E | 0x7E => self.ror(mode)?,
+            // RTI: Return from Interrupt
+            0x40 => self.rti(),
+            // RTS: Return from Subroutine
+            0x60 => self.rts(),
+            // SBC: Subtract Memory from Accumulator with Borrow
+            0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => self.sbc(mode)?,
+            // STA: Store Accumulator in Memory
+            0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => self.sta(mode)?,
+            // STX: Store Index X in Memory
+            0x86 | 0x96 | 0x8E => self.stx(mode)?,
+            // STY: Store Index Y in Memory
+            0x84 | 0x94 | 0x8C => self.sty(mode)?,
+            // register transfers
+            0xAA => self.tax(),
+            0xA8 => self.tay(),
+            0xBA => self.tsx(),
+            0x8A => self.txa(),
+            0x9A => self.txs(),
+            0x98 => self.tya(),
+            // unofficial opcodes
+            0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA | 0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 | 0x04 | 0x44 | 0x64
+                | 0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 | 0x0C | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => self.nop(mode)?,
+            0xAB | 0xA7 | 0xB7 | 0xAF | 0xBF | 0xA3 | 0xB3 => self.lax(mode)?,
+            0x87 | 0x97 | 0x8F | 0x83 => self.sax(mode)?,
+            0xEB => self.sbc(mode)?,
+            0xC7 | 0xD7 | 0xCF | 0xDF | 0xDB | 0xC3 | 0xD3 => self.dcp(mode)?,
+            0xE7 | 0xF7 | 0xEF | 0xFF | 0xFB | 0xE3 | 0xF3 => self.isc(mode)?,
+            0x07 | 0x17 | 0x0F | 0x1F | 0x1B | 0x03 | 0x13 => self.slo(mode)?,
+            0x27 | 0x37 | 0x2F | 0x3F | 0x3B | 0x23 | 0x33 => self.rla(mode)?,
+            0x47 | 0x57 | 0x4F | 0x5F | 0x5B | 0x43 | 0x53 => self.sre(mode)?,
+            0x67 | 0x77 | 0x6F | 0x7F | 0x7B | 0x63 | 0x73 => self.rra(mode)?,
+            0x0B | 0x2B => self.anc(mode)?,
+            0x4B => self.alr(mode)?,
+            0x6B => self.arr(mode)?,
+            0xCB => self.axs(mode)?,
+            0x8B => self.xaa(mode)?,
+            0x9F | 0x93 => self.sha(mode)?,
+            0x9E => self.shx(mode)?,
+            0x9C => self.shy(mode)?,
+            0x9B => self.tas(mode)?,
+            0xBB => self.las(mode)?,
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => self.jam(ops_code.opc)?,
+        }
+        Ok(())
+    }
+    /// Runs until an error stops the CPU, e.g. a JAM opcode.
+    pub fn run(&mut self) -> Result<(),EmuError> {
+        self.run_with_callback(|_| true)
+    }
+    /// Runs until an instruction branches or jumps to itself, the way test suites such as Klaus Dormann's
+    /// report their result, and returns that address. Gives up with None after `max_instructions`.
+    pub fn run_until_trap(&mut self,max_instructions: u64) -> Result<Option<u16>,EmuError> {
+        let mut previous = None;
+        let mut executed = 0;
+        self.run_with_callback(|cpu| {
+            if previous == Some(cpu.program_counter) || executed == max_instructions {
+                return false;
             }
+            previous = Some(cpu.program_counter);
+            executed += 1;
+            true
+        })?;
+        Ok(if previous == Some(self.program_counter) { previous } else { None })
+    }
+    /// Runs instructions until `callback` returns false or an instruction fails.
+    /// The callback sees the CPU before every instruction, so devices clocked by the CPU can
+    /// catch up to `cycles` in lockstep with it.
+    pub fn run_with_callback<F>(&mut self,mut callback: F) -> Result<(),EmuError> where F: FnMut(&mut CPU<B>) -> bool {
+        while callback(self) {
+            self.step()?;
+        }
+        Ok(())
+    }
+    /// Runs instructions until `condition` holds, it is checked before every instruction.
+    pub fn run_until<F>(&mut self,mut condition: F) -> Result<(),EmuError> where F: FnMut(&mut CPU<B>) -> bool {
+        self.run_with_callback(|cpu| !condition(cpu))
+    }
+    /// Runs whole instructions until at least `cycles` cycles have passed and returns how many did,
+    /// the last instruction may overshoot.
+    pub fn run_for_cycles(&mut self,cycles: u64) -> Result<u64,EmuError> {
+        let start = self.cycles;
+        let target = start + cycles;
+        self.run_with_callback(|cpu| cpu.cycles < target)?;
+        Ok(self.cycles - start)
+    }
+    /// Runs until the PPU finishes the frame it is drawing, that is until the next vblank starts.
+    /// Buses without a PPU run for `CYCLES_PER_FRAME` cycles instead.
+    pub fn run_until_frame(&mut self) -> Result<(),EmuError> {
+        match self.bus.frame_count() {
+            Some(frame) => self.run_with_callback(|cpu| cpu.bus.frame_count() == Some(frame)),
+            None => self.run_for_cycles(CYCLES_PER_FRAME).map(|_| ()),
         }
     }
-    fn load_and_run(&mut self,program: Vec<u8>) {
-        self.load_program(program);
-        self.reset();
-        self.run();
+    /// Executes exactly one instruction, after servicing a pending NMI or IRQ if there is one.
+    pub fn step(&mut self) -> Result<Step,EmuError> {
+        let start = self.cycles;
+        let interrupt = self.poll_interrupts();
+        let addr = self.program_counter;
+        let opcode = self.memory_read(addr);
+        let ops_code = opcode_table(self.variant).get(&opcode)
+            .ok_or(EmuError::UnknownOpcode { opcode, addr })?;
+        self.program_counter = self.program_counter.wrapping_add(1);
+        let program_counter_backup = self.program_counter;
+        self.operand_addr = None;
+        if self.variant != CpuVariant::Cmos65C02 || !self.execute_cmos(ops_code)? {
+            self.execute(ops_code)?;
+        }
+        // If the program counter is modified in the opcode, it will not be processed separately
+        if self.program_counter == program_counter_backup {
+            self.program_counter = self.program_counter.wrapping_add((ops_code.bytes - 1) as u16);
+        }
+        self.tick(ops_code.cycles);
+        Ok(Step {
+            addr,
+            opcode,
+            effective_addr: self.operand_addr,
+            cycles: self.cycles - start,
+            interrupt,
+        })
+    }
+    // runs the program until the next instruction is a BRK, which test programs use as their end marker
+    #[cfg(test)]
+    fn load_and_run(&mut self,program: Vec<u8>) -> Result<(),EmuError> {
+        self.load_program(program)?;
+        self.power_on();
+        self.run_with_callback(|cpu| cpu.memory_read(cpu.program_counter) != 0x00)
+    }
+}
+
+// the CPU section, followed by the sections of the bus
+impl<B: Bus + Snapshot> Snapshot for CPU<B> {
+    fn save_state(&self,state: &mut StateWriter) {
+        state.section(b"CPU ",|state| {
+            state.u8(self.variant as u8);
+            state.u8(self.accumulator);
+            state.u8(self.register_x);
+            state.u8(self.register_y);
+            state.u8(self.stack_ptr);
+            state.u8(self.status);
+            state.u16(self.program_counter);
+            state.u64(self.cycles);
+            state.bool(self.nmi_line);
+            state.bool(self.nmi_pending);
+            state.bool(self.irq_line);
+        });
+        self.bus.save_state(state);
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        state.section(b"CPU ",|state| {
+            self.variant = match state.u8()? {
+                0 => CpuVariant::Ricoh2A03,
+                1 => CpuVariant::Nmos6502,
+                variant => {
+                    state.ensure(variant == 2)?;
+                    CpuVariant::Cmos65C02
+                },
+            };
+            self.accumulator = state.u8()?;
+            self.register_x = state.u8()?;
+            self.register_y = state.u8()?;
+            self.stack_ptr = state.u8()?;
+            self.status = state.u8()?;
+            self.program_counter = state.u16()?;
+            self.cycles = state.u64()?;
+            self.nmi_line = state.bool()?;
+            self.nmi_pending = state.bool()?;
+            self.irq_line = state.bool()?;
+            Ok(())
+        })?;
+        self.bus.load_state(state)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::bus::FlatBus;
+    use crate::ppu::VBLANK_SCANLINE;
+    fn write_all(cpu: &mut CPU,addr: u16,data: &[u8]) {
+        for (i,byte) in data.iter().enumerate() {
+            cpu.memory_write(addr + i as u16,*byte);
+        }
+    }
+    fn asm(source: &str) -> Vec<u8> {
+        crate::assembler::assemble(source,CpuVariant::Ricoh2A03).unwrap().remove(0).data
+    }
     #[test]
     fn test_5_ops_working_together() {
-        let mut cpu = CPU::new();
-        cpu.load_and_run(vec![0xA9, 0xC0, 0xAA, 0xE8, 0x00]);
+        let mut cpu = CPU::new(NesBus::new());
+        cpu.load_and_run(asm("LDA #$C0\nTAX\nINX\nBRK")).unwrap();
         assert_eq!(cpu.register_x, 0xC1)
     }
-}
\ No newline at end of file
+    #[test]
+    fn test_every_official_opcode_is_dispatched() {
+        assert_eq!(OpCodesMap.values().filter(|ops_code| ops_code.official).count(), 151);
+        for opc in OpCodesMap.keys().filter(|opc| OpCodesMap[opc].official) {
+            let mut cpu = CPU::new(NesBus::new());
+            // each opcode runs from $8000 with zeroed operands and must reach the trailing BRK
+            // (JMP/JSR/RTS/RTI/branches may land elsewhere, but zeroed memory is a BRK too)
+            cpu.load_and_run(vec![*opc, 0x00, 0x00, 0x00]).unwrap();
+        }
+    }
+    #[test]
+    fn test_every_opcode_byte_is_dispatched() {
+        assert_eq!(OpCodesMap.len(), 256);
+        for opc in OpCodesMap.keys().filter(|opc| OpCodesMap[opc].assembler != "JAM") {
+            let mut cpu = CPU::new(NesBus::new());
+            cpu.load_and_run(vec![*opc, 0x00, 0x00, 0x00]).unwrap();
+        }
+    }
+    #[test]
+    fn test_lax_sax() {
+        let mut cpu = CPU::new(NesBus::new());
+        // LAX $10; LDA #$F0; SAX $11; BRK
+        write_all(&mut cpu,0x10,&[0x3C]);
+        cpu.load_and_run(vec![0xA7, 0x10, 0xA9, 0xF0, 0x87, 0x11, 0x00]).unwrap();
+        assert_eq!(cpu.register_x, 0x3C);
+        assert_eq!(cpu.memory_read(0x11), 0x30);
+    }
+    #[test]
+    fn test_read_modify_write_combos() {
+        let mut cpu = CPU::new(NesBus::new());
+        // LDA #$05; DCP $10 ($06 -> $05, equal); BRK
+        write_all(&mut cpu,0x10,&[0x06, 0x7F, 0x81, 0x02]);
+        cpu.load_and_run(vec![0xA9, 0x05, 0xC7, 0x10, 0x00]).unwrap();
+        assert_eq!(cpu.memory_read(0x10), 0x05);
+        assert_ne!(cpu.status & ZERO, 0);
+        assert_ne!(cpu.status & CARRY, 0);
+
+        // SEC; LDA #$10; ISC $11 ($7F -> $80, $10 - $80); BRK
+        cpu.load_and_run(vec![0x38, 0xA9, 0x10, 0xE7, 0x11, 0x00]).unwrap();
+        assert_eq!(cpu.memory_read(0x11), 0x80);
+        assert_eq!(cpu.accumulator, 0x90);
+        assert_ne!(cpu.status & OVERFLOW, 0);
+
+        // LDA #$01; SLO $12 ($81 -> $02, C=1); BRK
+        cpu.load_and_run(vec![0xA9, 0x01, 0x07, 0x12, 0x00]).unwrap();
+        assert_eq!(cpu.memory_read(0x12), 0x02);
+        assert_eq!(cpu.accumulator, 0x03);
+        assert_ne!(cpu.status & CARRY, 0);
+
+        // SEC; LDA #$10; RRA $13 ($02 -> $81, C=0, $10 + $81); BRK
+        cpu.load_and_run(vec![0x38, 0xA9, 0x10, 0x67, 0x13, 0x00]).unwrap();
+        assert_eq!(cpu.memory_read(0x13), 0x81);
+        assert_eq!(cpu.accumulator, 0x91);
+    }
+    #[test]
+    fn test_immediate_combos() {
+        let mut cpu = CPU::new(NesBus::new());
+        // LDA #$FF; ANC #$80; BRK
+        cpu.load_and_run(vec![0xA9, 0xFF, 0x0B, 0x80, 0x00]).unwrap();
+        assert_eq!(cpu.accumulator, 0x80);
+        assert_ne!(cpu.status & CARRY, 0);
+        // LDA #$FF; ALR #$03; BRK
+        cpu.load_and_run(vec![0xA9, 0xFF, 0x4B, 0x03, 0x00]).unwrap();
+        assert_eq!(cpu.accumulator, 0x01);
+        assert_ne!(cpu.status & CARRY, 0);
+        // SEC; LDA #$FF; ARR #$C0 -> $E0, C from bit 6, V from bit 6 ^ bit 5; BRK
+        cpu.load_and_run(vec![0x38, 0xA9, 0xFF, 0x6B, 0xC0, 0x00]).unwrap();
+        assert_eq!(cpu.accumulator, 0xE0);
+        assert_ne!(cpu.status & CARRY, 0);
+        assert_eq!(cpu.status & OVERFLOW, 0);
+        // LDA #$0F; LDX #$FC; AXS #$02 -> X = $0C - $02; BRK
+        cpu.load_and_run(vec![0xA9, 0x0F, 0xA2, 0xFC, 0xCB, 0x02, 0x00]).unwrap();
+        assert_eq!(cpu.register_x, 0x0A);
+        assert_ne!(cpu.status & CARRY, 0);
+    }
+    #[test]
+    fn test_unstable_stores() {
+        let mut cpu = CPU::new(NesBus::new());
+        // LDX #$FF; LDY #$01; SHX $0210,Y stores X & $03; BRK
+        cpu.load_and_run(vec![0xA2, 0xFF, 0xA0, 0x01, 0x9E, 0x10, 0x02, 0x00]).unwrap();
+        assert_eq!(cpu.memory_read(0x0211), 0x03);
+        // LDX #$01; LDY #$01; SHX $02FF,Y crosses a page, the value $01 becomes the high byte; BRK
+        cpu.load_and_run(vec![0xA2, 0x01, 0xA0, 0x01, 0x9E, 0xFF, 0x02, 0x00]).unwrap();
+        assert_eq!(cpu.memory_read(0x0100), 0x01);
+        // LDA #$F3; LDX #$3F; LDY #$00; TAS $0400,Y; BRK
+        cpu.load_and_run(vec![0xA9, 0xF3, 0xA2, 0x3F, 0xA0, 0x00, 0x9B, 0x00, 0x04, 0x00]).unwrap();
+        assert_eq!(cpu.stack_ptr, 0x33);
+        assert_eq!(cpu.memory_read(0x0400), 0x01);
+    }
+    #[test]
+    fn test_nop_page_cross_and_jam() {
+        let mut cpu = CPU::new(NesBus::new());
+        // LDX #$01 (2); NOP $80FF,X (4+1); NOP $10 (3); BRK
+        cpu.load_and_run(vec![0xA2, 0x01, 0x1C, 0xFF, 0x80, 0x04, 0x10, 0x00]).unwrap();
+        assert_eq!(cpu.cycles, RESET_CYCLES as u64 + 2 + 5 + 3);
+
+        let mut cpu = CPU::new(NesBus::new());
+        // INX; JAM
+        cpu.load_program(vec![0xE8, 0x02]).unwrap();
+        cpu.power_on();
+        assert!(matches!(cpu.run(),Err(EmuError::Jammed { opcode: 0x02, addr: 0x8001 })));
+        assert_eq!(cpu.program_counter, 0x8001);
+        assert_eq!(cpu.register_x, 1);
+        // it stays stuck until a reset
+        assert!(matches!(cpu.run(),Err(EmuError::Jammed { .. })));
+        assert_eq!(cpu.register_x, 1);
+    }
+    #[test]
+    fn test_errors_instead_of_panics() {
+        let mut cpu = CPU::new(NesBus::new());
+        assert!(matches!(cpu.load_program(vec![0xEA; 0x8001]),Err(EmuError::RomTooLarge { size: 0x8001, max: 0x8000 })));
+        cpu.program_counter = 0x0300;
+        assert!(matches!(
+            cpu.get_operand_addr(&AddrMode::Implied),
+            Err(EmuError::InvalidAddressingMode { mode: AddrMode::Implied, addr: 0x02FF })
+        ));
+    }
+    #[test]
+    fn test_adc_sbc_carry_and_overflow() {
+        let mut cpu = CPU::new(NesBus::new());
+        // CLC; LDA #$50; ADC #$50; BRK
+        cpu.load_and_run(vec![0x18, 0xA9, 0x50, 0x69, 0x50, 0x00]).unwrap();
+        assert_eq!(cpu.accumulator, 0xA0);
+        assert_ne!(cpu.status & OVERFLOW, 0);
+        assert_eq!(cpu.status & CARRY, 0);
+
+        let mut cpu = CPU::new(NesBus::new());
+        // SEC; LDA #$10; SBC #$20; BRK
+        cpu.load_and_run(vec![0x38, 0xA9, 0x10, 0xE9, 0x20, 0x00]).unwrap();
+        assert_eq!(cpu.accumulator, 0xF0);
+        assert_eq!(cpu.status & CARRY, 0);
+        assert_ne!(cpu.status & NEGATIVE, 0);
+    }
+    #[test]
+    fn test_jsr_rts_and_stack() {
+        let mut cpu = CPU::new(NesBus::new());
+        cpu.load_and_run(asm("
+                    JSR sub
+                    LDX #$05
+                    BRK
+            sub:    LDA #$AA
+                    PHA
+                    LDA #$00
+                    PLA
+                    RTS
+        ")).unwrap();
+        assert_eq!(cpu.accumulator, 0xAA);
+        assert_eq!(cpu.register_x, 0x05);
+        assert_eq!(cpu.stack_ptr, 0xFD);
+    }
+    #[test]
+    fn test_loop_with_branch() {
+        let mut cpu = CPU::new(NesBus::new());
+        cpu.load_and_run(asm("
+                    LDX #$08
+            loop:   DEX
+                    TXA
+                    STA $10,X
+                    BNE loop
+                    BRK
+        ")).unwrap();
+        assert_eq!(cpu.register_x, 0x00);
+        assert_eq!(cpu.memory_read(0x10 + 0x07), 0x07);
+        assert_ne!(cpu.status & ZERO, 0);
+    }
+    #[test]
+    fn test_rotate_and_shift_memory() {
+        let mut cpu = CPU::new(NesBus::new());
+        // LDA #$81; STA $20; SEC; ROR $20; ASL $20; BRK
+        cpu.load_and_run(vec![0xA9, 0x81, 0x85, 0x20, 0x38, 0x66, 0x20, 0x06, 0x20, 0x00]).unwrap();
+        assert_eq!(cpu.memory_read(0x20), 0x80);
+        assert_ne!(cpu.status & CARRY, 0);
+    }
+    #[test]
+    fn test_cycles_with_page_cross_penalty() {
+        let mut cpu = CPU::new(NesBus::new());
+        // LDX #$01 (2); LDA $80FF,X (4+1); STA $80FF,X (5); LDA $8000,X (4); BRK
+        cpu.load_and_run(vec![0xA2, 0x01, 0xBD, 0xFF, 0x80, 0x9D, 0xFF, 0x80, 0xBD, 0x00, 0x80, 0x00]).unwrap();
+        assert_eq!(cpu.cycles, RESET_CYCLES as u64 + 2 + 5 + 5 + 4);
+    }
+    #[test]
+    fn test_cycles_with_branch_penalty() {
+        let mut cpu = CPU::new(NesBus::new());
+        // LDX #$00 (2); BNE +0 not taken (2); BEQ +0 taken (3); BRK
+        cpu.load_and_run(vec![0xA2, 0x00, 0xD0, 0x00, 0xF0, 0x00, 0x00]).unwrap();
+        assert_eq!(cpu.cycles, RESET_CYCLES as u64 + 2 + 2 + 3);
+
+        let mut cpu = CPU::new(NesBus::new());
+        // NOPs up to $80FB: LDX #$00; BEQ +2 taken from $80FD lands on $8101 in the next page (4)
+        let mut program = vec![0xEA; 0xFB];
+        program.extend_from_slice(&[0xA2, 0x00, 0xF0, 0x02, 0xEA, 0xEA, 0x00]);
+        cpu.load_and_run(program).unwrap();
+        assert_eq!(cpu.program_counter, 0x8101);
+        assert_eq!(cpu.cycles, RESET_CYCLES as u64 + 0xFB * 2 + 2 + 4);
+    }
+    #[test]
+    fn test_oam_dma_stalls_cpu() {
+        let mut cpu = CPU::new(NesBus::new());
+        // LDA #$02 (2); STA $4014 (4 + 514, it ends on an odd cycle); BRK
+        cpu.load_and_run(vec![0xA9, 0x02, 0x8D, 0x14, 0x40, 0x00]).unwrap();
+        assert_eq!(cpu.cycles, RESET_CYCLES as u64 + 2 + 4 + 514);
+
+        let mut cpu = CPU::new(NesBus::new());
+        // LDA #$02 (2); PHA (3); STA $4014 (4 + 513); BRK
+        cpu.load_and_run(vec![0xA9, 0x02, 0x48, 0x8D, 0x14, 0x40, 0x00]).unwrap();
+        assert_eq!(cpu.cycles, RESET_CYCLES as u64 + 2 + 3 + 4 + 513);
+    }
+    #[test]
+    fn test_run_with_callback_stops_when_asked() {
+        let mut cpu = CPU::new(NesBus::new());
+        // INX; JMP $8000
+        cpu.load_program(vec![0xE8, 0x4C, 0x00, 0x80]).unwrap();
+        cpu.power_on();
+        cpu.run_with_callback(|cpu| cpu.cycles < 1000).unwrap();
+        assert!(cpu.cycles >= 1000 && cpu.cycles < 1003);
+    }
+    #[test]
+    fn test_step_reports_instruction() {
+        let mut cpu = CPU::new(NesBus::new());
+        // LDX #$01; LDA $80FF,X; BNE +2 (not taken); JSR $9000
+        cpu.load_program(vec![0xA2, 0x01, 0xBD, 0xFF, 0x80, 0xD0, 0x02, 0x20, 0x00, 0x90]).unwrap();
+        cpu.power_on();
+        let step = cpu.step().unwrap();
+        assert_eq!(step, Step { addr: 0x8000, opcode: 0xA2, effective_addr: Some(0x8001), cycles: 2, interrupt: None });
+        let step = cpu.step().unwrap();
+        assert_eq!((step.opcode,step.effective_addr,step.cycles), (0xBD,Some(0x8100),5));
+        assert_eq!(cpu.step().unwrap().effective_addr, None);
+        let step = cpu.step().unwrap();
+        assert_eq!((step.addr,step.effective_addr,step.cycles), (0x8007,Some(0x9000),6));
+        assert_eq!(cpu.program_counter, 0x9000);
+        // the NMI is serviced first, the step then runs the first instruction of the handler
+        cpu.memory_write_u16(NMI_VECTOR,0x8002);
+        cpu.trigger_nmi();
+        let step = cpu.step().unwrap();
+        assert_eq!((step.addr,step.interrupt,step.cycles), (0x8002,Some(NMI_VECTOR),7 + 5));
+    }
+    #[test]
+    fn test_run_for_cycles_and_until() {
+        let mut cpu = CPU::new(NesBus::new());
+        // loop: INX; JMP loop
+        cpu.load_program(vec![0xE8, 0x4C, 0x00, 0x80]).unwrap();
+        cpu.power_on();
+        // INX takes 2 cycles and JMP 3, so asking for 6 stops after the second INX
+        assert_eq!(cpu.run_for_cycles(6).unwrap(), 7);
+        assert_eq!(cpu.register_x, 2);
+        cpu.run_until(|cpu| cpu.register_x == 0x10).unwrap();
+        assert_eq!(cpu.register_x, 0x10);
+        assert_eq!(cpu.program_counter, 0x8001);
+    }
+    #[test]
+    fn test_run_until_frame() {
+        let mut cpu = CPU::new(NesBus::new());
+        cpu.load_program(vec![0x4C, 0x00, 0x80]).unwrap();
+        cpu.power_on();
+        cpu.run_until_frame().unwrap();
+        assert_eq!(cpu.bus.ppu.frame_count, 1);
+        assert_eq!(cpu.bus.ppu_position().0, VBLANK_SCANLINE);
+        let cycles = cpu.cycles;
+        cpu.run_until_frame().unwrap();
+        assert_eq!(cpu.bus.ppu.frame_count, 2);
+        assert!((cpu.cycles - cycles).abs_diff(CYCLES_PER_FRAME) <= 3);
+
+        // a bus without a PPU runs a frame's worth of cycles
+        let mut cpu = CPU::new(FlatBus::new());
+        cpu.bus.load(0x0200,&[0x4C, 0x00, 0x02]);
+        cpu.program_counter = 0x0200;
+        cpu.run_until_frame().unwrap();
+        assert!(cpu.cycles >= CYCLES_PER_FRAME && cpu.cycles < CYCLES_PER_FRAME + 3);
+    }
+    #[test]
+    fn test_brk_and_rti() {
+        let mut cpu = CPU::new(NesBus::new());
+        // LDA #$01; BRK; (padding); LDX #$42; BRK
+        cpu.load_program(vec![0xA9, 0x01, 0x00, 0xEA, 0xA2, 0x42, 0x00]).unwrap();
+        // handler: LDY #$07; RTI
+        write_all(&mut cpu,0x9000,&[0xA0, 0x07, 0x40]);
+        cpu.memory_write_u16(IRQ_VECTOR,0x9000);
+        cpu.power_on();
+        cpu.run_with_callback(|cpu| cpu.program_counter != 0x8006).unwrap();
+        assert_eq!(cpu.register_y, 0x07);
+        assert_eq!(cpu.register_x, 0x42);
+        assert_eq!(cpu.stack_ptr, 0xFD);
+        // return address $8004 and the status pushed with B set
+        assert_eq!(cpu.memory_read(0x01FC), 0x04);
+        assert_ne!(cpu.memory_read(0x01FB) & BREAK, 0);
+        assert_eq!(cpu.status & BREAK, 0);
+    }
+    #[test]
+    fn test_nmi_is_edge_triggered() {
+        let mut cpu = CPU::new(NesBus::new());
+        // JMP $8000
+        cpu.load_program(vec![0x4C, 0x00, 0x80]).unwrap();
+        // handler: INX; RTI
+        write_all(&mut cpu,0x9000,&[0xE8, 0x40]);
+        cpu.memory_write_u16(NMI_VECTOR,0x9000);
+        cpu.power_on();
+        cpu.set_nmi(true);
+        cpu.set_nmi(true);
+        cpu.run_with_callback(|cpu| cpu.cycles < 100).unwrap();
+        assert_eq!(cpu.register_x, 1);
+        // the NMI also fires with I set
+        cpu.status |= INTERRUPT;
+        cpu.set_nmi(false);
+        cpu.set_nmi(true);
+        cpu.run_with_callback(|cpu| cpu.cycles < 200).unwrap();
+        assert_eq!(cpu.register_x, 2);
+        assert_ne!(cpu.status & INTERRUPT, 0);
+    }
+    #[test]
+    fn test_irq_is_masked_and_level_triggered() {
+        let mut cpu = CPU::new(NesBus::new());
+        // JMP $8000
+        cpu.load_program(vec![0x4C, 0x00, 0x80]).unwrap();
+        // handler: INX; RTI
+        write_all(&mut cpu,0x9000,&[0xE8, 0x40]);
+        cpu.memory_write_u16(IRQ_VECTOR,0x9000);
+        // I is set at power on
+        cpu.power_on();
+        cpu.set_irq(true);
+        cpu.run_with_callback(|cpu| cpu.cycles < 100).unwrap();
+        assert_eq!(cpu.register_x, 0);
+        // once unmasked the handler keeps being re-entered until the line is released
+        cpu.status &= !INTERRUPT;
+        cpu.run_with_callback(|cpu| cpu.register_x < 3).unwrap();
+        cpu.set_irq(false);
+        cpu.run_with_callback(|cpu| cpu.cycles < 1000).unwrap();
+        assert_eq!(cpu.register_x, 3);
+    }
+    #[test]
+    fn test_power_on_and_reset() {
+        let mut cpu = CPU::new(NesBus::new());
+        cpu.load_program(vec![0xEA]).unwrap();
+        cpu.power_on();
+        assert_eq!((cpu.stack_ptr,cpu.status,cpu.cycles), (0xFD,INTERRUPT | UNUSED,RESET_CYCLES as u64));
+        cpu.accumulator = 0x12;
+        cpu.register_x = 0x34;
+        cpu.status = CARRY;
+        cpu.stack_ptr = 0xF0;
+        cpu.memory_write(0x01F0,0x56);
+        cpu.program_counter = 0x1234;
+        // the registers survive, only I is set and SP moves down 3 without anything written to the stack
+        cpu.reset();
+        assert_eq!((cpu.accumulator,cpu.register_x,cpu.status), (0x12,0x34,CARRY | INTERRUPT));
+        assert_eq!((cpu.stack_ptr,cpu.memory_read(0x01F0),cpu.program_counter), (0xED,0x56,0x8000));
+        assert_eq!(cpu.cycles, 2 * RESET_CYCLES as u64);
+    }
+    #[test]
+    fn test_run_until_trap() {
+        let mut cpu = CPU::new(FlatBus::new());
+        // LDX #$03; loop: DEX; BNE loop; trap: JMP trap
+        cpu.bus.load(0x0200,&[0xA2, 0x03, 0xCA, 0xD0, 0xFD, 0x4C, 0x05, 0x02]);
+        cpu.program_counter = 0x0200;
+        assert_eq!(cpu.run_until_trap(100).unwrap(),Some(0x0205));
+        cpu.program_counter = 0x0200;
+        assert_eq!(cpu.run_until_trap(3).unwrap(),None);
+    }
+    // loads a Klaus Dormann test binary from test_roms/, the binaries are not in the repo,
+    // see "Test ROMs" in the README, then run `cargo test -- --ignored`
+    fn klaus_cpu(file: &str,origin: u16,start: u16) -> CPU<FlatBus> {
+        let path = format!("{}/test_roms/{}",env!("CARGO_MANIFEST_DIR"),file);
+        let image = std::fs::read(&path).unwrap_or_else(|err| panic!("{}: {}",path,err));
+        // both suites exercise decimal mode, so they need the NMOS core rather than the 2A03
+        let mut cpu = CPU::new(FlatBus::new());
+        cpu.variant = CpuVariant::Nmos6502;
+        cpu.bus.load(origin,&image);
+        cpu.program_counter = start;
+        cpu
+    }
+    // 6502_functional_test.bin assembled with the default settings: loaded at $0000, started at $0400,
+    // it traps at $3469 when every test passed
+    #[test]
+    #[ignore = "needs test_roms/6502_functional_test.bin"]
+    fn test_klaus_dormann_functional() {
+        let mut cpu = klaus_cpu("6502_functional_test.bin",0x0000,0x0400);
+        match cpu.run_until_trap(100_000_000).unwrap() {
+            Some(0x3469) => {},
+            Some(addr) => panic!("functional test trapped at ${:04X}, test case ${:02X}",addr,cpu.bus.read(0x0200)),
+            None => panic!("functional test did not finish, PC=${:04X}",cpu.program_counter),
+        }
+    }
+    // 6502_decimal_test.bin: loaded and started at $0200, it traps on completion with ERROR ($000B) cleared on success
+    #[test]
+    #[ignore = "needs test_roms/6502_decimal_test.bin"]
+    fn test_klaus_dormann_decimal() {
+        let mut cpu = klaus_cpu("6502_decimal_test.bin",0x0200,0x0200);
+        let Some(addr) = cpu.run_until_trap(100_000_000).unwrap() else {
+            panic!("decimal test did not finish, PC=${:04X}",cpu.program_counter);
+        };
+        assert_eq!(cpu.bus.read(0x000B),0,"decimal test failed, trapped at ${:04X}",addr);
+    }
+    fn run_variant(variant: CpuVariant,program: &[u8]) -> CPU<FlatBus> {
+        let mut cpu = CPU::new(FlatBus::new());
+        cpu.variant = variant;
+        cpu.bus.load(0x0200,program);
+        cpu.program_counter = 0x0200;
+        cpu.run_with_callback(|cpu| cpu.bus.read(cpu.program_counter) != 0x00).unwrap();
+        cpu
+    }
+    #[test]
+    fn test_decimal_mode_per_variant() {
+        // SED; CLC; LDA #$09; ADC #$01; BRK
+        let program = [0xF8, 0x18, 0xA9, 0x09, 0x69, 0x01, 0x00];
+        assert_eq!(run_variant(CpuVariant::Ricoh2A03,&program).accumulator, 0x0A);
+        assert_eq!(run_variant(CpuVariant::Nmos6502,&program).accumulator, 0x10);
+        assert_eq!(run_variant(CpuVariant::Cmos65C02,&program).accumulator, 0x10);
+
+        // SED; CLC; LDA #$99; ADC #$01; BRK: Z follows the binary sum on NMOS only
+        let program = [0xF8, 0x18, 0xA9, 0x99, 0x69, 0x01, 0x00];
+        let nmos = run_variant(CpuVariant::Nmos6502,&program);
+        assert_eq!(nmos.accumulator, 0x00);
+        assert_ne!(nmos.status & CARRY, 0);
+        assert_eq!(nmos.status & ZERO, 0);
+        let cmos = run_variant(CpuVariant::Cmos65C02,&program);
+        assert_eq!(cmos.accumulator, 0x00);
+        assert_ne!(cmos.status & ZERO, 0);
+
+        // SED; SEC; LDA #$00; SBC #$01; BRK
+        let program = [0xF8, 0x38, 0xA9, 0x00, 0xE9, 0x01, 0x00];
+        for variant in [CpuVariant::Nmos6502,CpuVariant::Cmos65C02] {
+            let cpu = run_variant(variant,&program);
+            assert_eq!(cpu.accumulator, 0x99);
+            assert_eq!(cpu.status & CARRY, 0);
+        }
+        // SED; SEC; LDA #$46; SBC #$12; BRK
+        let program = [0xF8, 0x38, 0xA9, 0x46, 0xE9, 0x12, 0x00];
+        assert_eq!(run_variant(CpuVariant::Nmos6502,&program).accumulator, 0x34);
+        assert_eq!(run_variant(CpuVariant::Ricoh2A03,&program).accumulator, 0x34);
+    }
+    #[test]
+    fn test_decimal_mode_valid_bcd() {
+        let bcd = |value: u8| ((value / 10) << 4) | (value % 10);
+        for variant in [CpuVariant::Nmos6502,CpuVariant::Cmos65C02] {
+            let mut cpu = CPU::new(FlatBus::new());
+            cpu.variant = variant;
+            for (a,b,carry) in (0..100).flat_map(|a| (0..100).flat_map(move |b| [(a,b,0),(a,b,1)])) {
+                cpu.status = DECIMAL | carry;
+                cpu.accumulator = bcd(a);
+                cpu.add_with_carry(bcd(b));
+                let sum = a + b + carry;
+                assert_eq!((cpu.accumulator,cpu.status & CARRY),(bcd(sum % 100),(sum >= 100) as u8));
+
+                cpu.status = DECIMAL | carry;
+                cpu.accumulator = bcd(a);
+                cpu.subtract_with_borrow(bcd(b));
+                let difference = a as i16 - b as i16 - (1 - carry as i16);
+                assert_eq!((cpu.accumulator,cpu.status & CARRY),(bcd(difference.rem_euclid(100) as u8),(difference >= 0) as u8));
+            }
+        }
+    }
+    #[test]
+    fn test_65c02_opcodes() {
+        // LDA #$0F; STA $10; LDA #$F3; TSB $10; LDA #$03; TRB $10; STZ $11; LDX #$AA; PHX; PLY;
+        // LDA #$12; STA $20; LDA #$00; STA $21; INC A; LDA ($20); BRA +1; (skipped) INX; BRK
+        let mut cpu = run_variant(CpuVariant::Cmos65C02,&[
+            0xA9, 0x0F, 0x85, 0x10, 0xA9, 0xF3, 0x04, 0x10, 0xA9, 0x03, 0x14, 0x10, 0x64, 0x11,
+            0xA2, 0xAA, 0xDA, 0x7A, 0xA9, 0x12, 0x85, 0x20, 0xA9, 0x00, 0x85, 0x21, 0x1A,
+            0xB2, 0x20, 0x80, 0x01, 0xE8, 0x00,
+        ]);
+        assert_eq!(cpu.bus.read(0x10), 0xFC);
+        assert_eq!(cpu.register_y, 0xAA);
+        assert_eq!(cpu.register_x, 0xAA);
+        // $0012 is zero, so LDA ($20) loaded 0
+        assert_eq!(cpu.accumulator, 0x00);
+        assert_ne!(cpu.status & ZERO, 0);
+    }
+    #[test]
+    fn test_65c02_fixes() {
+        // JMP ($02FF) takes its high byte from $0300 on the 65C02, NMOS wraps to $0200 where the JMP opcode sits
+        for (variant,target) in [(CpuVariant::Nmos6502,0x6C34),(CpuVariant::Cmos65C02,0x5634)] {
+            let mut cpu = CPU::new(FlatBus::new());
+            cpu.variant = variant;
+            cpu.bus.load(0x0200,&[0x6C, 0xFF, 0x02]);
+            cpu.bus.load(0x02FF,&[0x34, 0x56]);
+            cpu.program_counter = 0x0200;
+            cpu.run_with_callback(|cpu| cpu.program_counter == 0x0200).unwrap();
+            assert_eq!(cpu.program_counter, target);
+        }
+        // SED; BRK clears D on the 65C02 only; the handler at $0300 is another BRK
+        for (variant,decimal) in [(CpuVariant::Nmos6502,DECIMAL),(CpuVariant::Cmos65C02,0)] {
+            let mut cpu = CPU::new(FlatBus::new());
+            cpu.variant = variant;
+            cpu.bus.load(0x0200,&[0xF8, 0x00]);
+            cpu.bus.load(IRQ_VECTOR,&[0x00, 0x03]);
+            cpu.program_counter = 0x0200;
+            cpu.run_with_callback(|cpu| cpu.program_counter != 0x0300).unwrap();
+            assert_eq!(cpu.status & DECIMAL, decimal);
+        }
+        // unofficial NMOS opcodes are NOPs: $DC takes an absolute operand, column 3 is a single byte
+        let cpu = run_variant(CpuVariant::Cmos65C02,&[0xDC, 0x00, 0x10, 0x03, 0xE8, 0x00]);
+        assert_eq!((cpu.accumulator,cpu.register_x), (0x00,0x01));
+    }
+}
@@ -0,0 +1,138 @@
+//! Determinism check: the same ROM and the same input must always produce the same machine.
+//!
+//! A movie is played twice on freshly built consoles. After every frame each save state section is
+//! hashed, together with the audio of the frame, and the two runs are compared as they go.
+//!
+//! What the core does to stay deterministic: opcode tables are ordered maps, nothing that reaches
+//! the machine reads the clock or the OS random source (movie guids do, they only go into files),
+//! and RAM starts from a `RamInit` pattern, random only from an explicit seed.
+
+use std::fmt;
+use crate::bus::RamInit;
+use crate::cartridge::Cartridge;
+use crate::console::Console;
+use crate::error::EmuError;
+use crate::movie::{Movie, MovieFrame};
+use crate::state::{self, StateError};
+
+/// Where two runs of the same movie first went apart.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Divergence {
+    // frames played when the difference showed up, 1 based
+    pub frame: usize,
+    // save state section that differs, or "AUDIO"
+    pub component: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self,f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,"runs diverge at frame {} in {}",self.frame,self.component)
+    }
+}
+
+// FNV-1a, stable across runs and platforms unlike the std hashers
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xCBF2_9CE4_8422_2325,|hash,byte| (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01B3))
+}
+
+/// Hash of every section of a save state, sections that appear more than once are numbered from the second one on, e.g. "JOY#2".
+pub fn state_hashes(data: &[u8]) -> Result<Vec<(String,u64)>,StateError> {
+    let sections = state::sections(data)?;
+    let mut hashes: Vec<(String,u64)> = Vec::new();
+    for (tag,payload) in sections {
+        let tag = tag.trim_end().to_string();
+        let count = hashes.iter().filter(|(name,_)| name.split('#').next() == Some(tag.as_str())).count();
+        let name = if count == 0 { tag } else { format!("{}#{}",tag,count + 1) };
+        hashes.push((name,fnv1a(payload)));
+    }
+    Ok(hashes)
+}
+
+// runs one frame and hashes what it left behind
+fn frame_hashes(console: &mut Console,input: Option<&MovieFrame>) -> Result<Vec<(String,u64)>,EmuError> {
+    if let Some(input) = input {
+        console.apply_input(input)?;
+    }
+    console.run_frame()?;
+    let audio: Vec<u8> = console.take_samples().iter().flat_map(|sample| sample.to_bits().to_le_bytes()).collect();
+    let mut hashes = state_hashes(&console.save_state())?;
+    hashes.push(("AUDIO".to_string(),fnv1a(&audio)));
+    Ok(hashes)
+}
+
+/// Plays `frames` frames of `movie` twice and returns the first difference, None when both runs match.
+/// Frames past the end of the movie run with the last buttons held. Both consoles power on with `ram_init`.
+pub fn verify_movie(cartridge: &Cartridge,movie: &Movie,frames: usize,ram_init: RamInit) -> Result<Option<Divergence>,EmuError> {
+    compare_runs(cartridge,movie,frames,ram_init,|_,_| {})
+}
+
+// `disturb` gets the second console before each frame (0 based), the tests use it to force a divergence
+fn compare_runs<F: FnMut(usize,&mut Console)>(
+    cartridge: &Cartridge,movie: &Movie,frames: usize,ram_init: RamInit,mut disturb: F,
+) -> Result<Option<Divergence>,EmuError> {
+    let mut runs = [Console::with_ram_init(cartridge.clone(),ram_init)?,Console::with_ram_init(cartridge.clone(),ram_init)?];
+    for console in runs.iter_mut() {
+        console.start_movie(movie)?;
+    }
+    for frame in 0..frames {
+        let input = movie.frames.get(frame);
+        let first = frame_hashes(&mut runs[0],input)?;
+        disturb(frame,&mut runs[1]);
+        let second = frame_hashes(&mut runs[1],input)?;
+        if let Some(((component,_),_)) = first.iter().zip(second.iter()).find(|(a,b)| a != b) {
+            return Ok(Some(Divergence { frame: frame + 1, component: component.clone() }));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::console::test::test_console;
+    use crate::mapper::test::test_cartridge;
+    #[test]
+    fn test_state_hashes() {
+        let mut console = test_console();
+        let before = state_hashes(&console.save_state()).unwrap();
+        let names: Vec<&str> = before.iter().map(|(name,_)| name.as_str()).collect();
+        assert_eq!(names,["CPU","BUS","PPU","APU","JOY","JOY#2","CART"]);
+        console.set_buttons(1,0x80);
+        let after = state_hashes(&console.save_state()).unwrap();
+        let changed: Vec<&str> = before.iter().zip(after.iter()).filter(|(a,b)| a != b).map(|(a,_)| a.0.as_str()).collect();
+        assert_eq!(changed,["JOY#2"]);
+    }
+    // the test_console program, `LDA $4016; JMP $8000`, and a movie with a reset in it
+    fn test_movie() -> (Cartridge,Movie) {
+        let mut cartridge = test_cartridge(0,0x8000,0x2000);
+        cartridge.prg_rom[..6].copy_from_slice(&[0xAD, 0x16, 0x40, 0x4C, 0x00, 0x80]);
+        cartridge.prg_rom[0x7FFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80]);
+        let mut movie = Movie::new();
+        movie.frames = (0..10).map(|frame| MovieFrame { commands: (frame == 5) as u8, buttons: [frame,0] }).collect();
+        (cartridge,movie)
+    }
+    #[test]
+    fn test_movie_is_deterministic() {
+        let (cartridge,movie) = test_movie();
+        assert_eq!(verify_movie(&cartridge,&movie,12,RamInit::Random(7)).unwrap(),None);
+    }
+    #[test]
+    fn test_reports_first_divergence() {
+        let (cartridge,movie) = test_movie();
+        // the program never touches $0300, so the poked byte stays different from frame 4 on
+        let divergence = compare_runs(&cartridge,&movie,12,RamInit::Zeros,|frame,console| {
+            if frame == 3 {
+                console.cpu.bus.write(0x0300,0x42);
+            }
+        }).unwrap().unwrap();
+        assert_eq!(divergence,Divergence { frame: 4, component: "BUS".to_string() });
+        assert_eq!(divergence.to_string(),"runs diverge at frame 4 in BUS");
+        let divergence = compare_runs(&cartridge,&movie,12,RamInit::Zeros,|frame,console| {
+            if frame == 6 {
+                console.cpu.bus.apu.write_register(0x4000,0xBF);
+            }
+        }).unwrap().unwrap();
+        assert_eq!(divergence,Divergence { frame: 7, component: "APU".to_string() });
+    }
+}
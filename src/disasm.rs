@@ -0,0 +1,157 @@
+//! Turns machine code back into 6502 assembly, using the opcode table of the selected CPU variant.
+//!
+//! Operands are written the way assemblers expect them:
+//! #$BB  $LL  $LL,X  $LL,Y  $HHLL  $HHLL,X  $HHLL,Y  ($HHLL)  ($LL,X)  ($LL),Y  ($LL)  ($HHLL,X)
+//! and branch offsets are resolved to the absolute address of their target.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+use crate::addressing_modes::AddrMode;
+use crate::bus::Bus;
+use crate::cpu::CpuVariant;
+use crate::ops_codes::{opcode_table, OpCode};
+
+/// One decoded instruction.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    pub operand: String,
+    pub official: bool,
+}
+
+impl Instruction {
+    // the instruction text without address and bytes, e.g. `LDA ($20),Y`
+    pub fn text(&self) -> String {
+        if self.operand.is_empty() {
+            self.mnemonic.to_string()
+        }else {
+            format!("{} {}",self.mnemonic,self.operand)
+        }
+    }
+}
+
+// same columns as the trace: address, up to 3 bytes, then the instruction with unofficial opcodes marked by *
+impl fmt::Display for Instruction {
+    fn fmt(&self,f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex: Vec<String> = self.bytes.iter().map(|byte| format!("{:02X}",byte)).collect();
+        let marker = if self.official { ' ' } else { '*' };
+        write!(f,"{:04X}  {:<8} {}{}",self.addr,hex.join(" "),marker,self.text())
+    }
+}
+
+/// Formats the operand of an instruction at `addr`, `bytes` holds the opcode followed by its operand bytes.
+pub fn format_operand(mode: &AddrMode,addr: u16,bytes: &[u8]) -> String {
+    let byte = bytes.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte,bytes.get(2).copied().unwrap_or(0)]);
+    match mode {
+        AddrMode::Implied => String::new(),
+        AddrMode::Accumulator => "A".to_string(),
+        AddrMode::Immediate => format!("#${:02X}",byte),
+        AddrMode::ZeroPage => format!("${:02X}",byte),
+        AddrMode::ZeroPageX => format!("${:02X},X",byte),
+        AddrMode::ZeroPageY => format!("${:02X},Y",byte),
+        AddrMode::Absolute => format!("${:04X}",word),
+        AddrMode::AbsoluteX => format!("${:04X},X",word),
+        AddrMode::AbsoluteY => format!("${:04X},Y",word),
+        AddrMode::Indirect => format!("(${:04X})",word),
+        AddrMode::IndirectX => format!("(${:02X},X)",byte),
+        AddrMode::IndirectY => format!("(${:02X}),Y",byte),
+        AddrMode::ZeroPageIndirect => format!("(${:02X})",byte),
+        AddrMode::AbsoluteIndirectX => format!("(${:04X},X)",word),
+        AddrMode::Relative => format!("${:04X}",addr.wrapping_add(2).wrapping_add(byte as i8 as u16)),
+    }
+}
+
+fn decode(ops_code: &OpCode,addr: u16,bytes: Vec<u8>) -> Instruction {
+    Instruction {
+        addr,
+        operand: format_operand(&ops_code.addressing_mode,addr,&bytes),
+        bytes,
+        mnemonic: ops_code.assembler,
+        official: ops_code.official,
+    }
+}
+
+/// Decodes the instruction at `addr`, reading memory through `read`.
+pub fn disassemble_one<F: FnMut(u16) -> u8>(variant: CpuVariant,addr: u16,mut read: F) -> Instruction {
+    let ops_code = &opcode_table(variant)[&read(addr)];
+    let bytes = (0..ops_code.bytes as u16).map(|i| read(addr.wrapping_add(i))).collect();
+    decode(ops_code,addr,bytes)
+}
+
+/// Disassembles the instructions starting inside `range`, memory is only peeked.
+/// The last one may run past the end of the range.
+pub fn disassemble<B: Bus>(bus: &mut B,variant: CpuVariant,range: RangeInclusive<u16>) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut addr = *range.start() as u32;
+    while addr <= *range.end() as u32 {
+        let instruction = disassemble_one(variant,addr as u16,|addr| bus.peek(addr));
+        addr += instruction.bytes.len() as u32;
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+/// Disassembles a code image loaded at `origin`. An instruction cut off by the end of the image
+/// is shown as `.byte` data.
+pub fn disassemble_bytes(data: &[u8],origin: u16,variant: CpuVariant) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let addr = origin.wrapping_add(offset as u16);
+        let ops_code = &opcode_table(variant)[&data[offset]];
+        let end = offset + ops_code.bytes as usize;
+        let instruction = if end <= data.len() {
+            decode(ops_code,addr,data[offset..end].to_vec())
+        }else {
+            let bytes = data[offset..].to_vec();
+            let operand = bytes.iter().map(|byte| format!("${:02X}",byte)).collect::<Vec<_>>().join(",");
+            Instruction { addr, bytes, mnemonic: ".byte", operand, official: true }
+        };
+        offset += instruction.bytes.len();
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::FlatBus;
+    fn texts(instructions: &[Instruction]) -> Vec<String> {
+        instructions.iter().map(|instruction| instruction.text()).collect()
+    }
+    #[test]
+    fn test_operand_syntax() {
+        let code = [
+            0xA9, 0x10, 0xA5, 0x20, 0xB5, 0x20, 0xB6, 0x20, 0xAD, 0x34, 0x12, 0xBD, 0x34, 0x12, 0xB9, 0x34, 0x12,
+            0x6C, 0xFC, 0xFF, 0xA1, 0x20, 0xB1, 0x20, 0x0A, 0xE8, 0xD0, 0xFC, 0x10, 0x02,
+        ];
+        assert_eq!(texts(&disassemble_bytes(&code,0xC000,CpuVariant::Ricoh2A03)),vec![
+            "LDA #$10", "LDA $20", "LDA $20,X", "LDX $20,Y", "LDA $1234", "LDA $1234,X", "LDA $1234,Y",
+            "JMP ($FFFC)", "LDA ($20,X)", "LDA ($20),Y", "ASL A", "INX", "BNE $C018", "BPL $C020",
+        ]);
+    }
+    #[test]
+    fn test_variants_and_display() {
+        let code = [0xB2, 0x20, 0x7C, 0x00, 0x90, 0x04, 0x10];
+        assert_eq!(texts(&disassemble_bytes(&code,0x0200,CpuVariant::Cmos65C02)),vec!["LDA ($20)", "JMP ($9000,X)", "TSB $10"]);
+        let instructions = disassemble_bytes(&code,0x0200,CpuVariant::Nmos6502);
+        assert_eq!(instructions[0].to_string(),"0200  B2       *JAM");
+        assert_eq!(instructions[1].to_string(),"0201  20 7C 00  JSR $007C");
+        // the last NOP $10 is cut off
+        assert_eq!(instructions.last().unwrap().to_string(),"0206  10        .byte $10");
+    }
+    #[test]
+    fn test_disassemble_bus_range() {
+        let mut bus = FlatBus::new();
+        bus.load(0xFFFC,&[0x4C, 0x00, 0x80, 0xEA]);
+        let instructions = disassemble(&mut bus,CpuVariant::Ricoh2A03,0xFFFC..=0xFFFF);
+        assert_eq!(texts(&instructions),vec!["JMP $8000", "NOP"]);
+        // an instruction that starts inside the range is shown completely
+        let instructions = disassemble(&mut bus,CpuVariant::Ricoh2A03,0xFFFC..=0xFFFC);
+        assert_eq!(instructions[0].bytes,vec![0x4C, 0x00, 0x80]);
+    }
+}
@@ -0,0 +1,76 @@
+//! Errors the emulator core reports instead of aborting the host process.
+
+use std::fmt;
+use crate::addressing_modes::AddrMode;
+use crate::cartridge::CartridgeError;
+use crate::mapper::MapperError;
+use crate::movie::MovieError;
+use crate::state::StateError;
+
+#[derive(Debug)]
+pub enum EmuError {
+    // the byte at addr has no entry in the opcode table of the selected CPU variant
+    UnknownOpcode { opcode: u8, addr: u16 },
+    // an instruction asked for the operand address of a mode that has none, such as Implied
+    InvalidAddressingMode { mode: AddrMode, addr: u16 },
+    RomTooLarge { size: usize, max: usize },
+    // a JAM/KIL opcode locked the CPU up, only a reset gets it going again
+    Jammed { opcode: u8, addr: u16 },
+    Cartridge(CartridgeError),
+    Mapper(MapperError),
+    State(StateError),
+    Movie(MovieError),
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self,f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmuError::UnknownOpcode { opcode, addr } => write!(f,"unknown opcode ${:02X} at ${:04X}",opcode,addr),
+            EmuError::InvalidAddressingMode { mode, addr } => {
+                write!(f,"addressing mode {:?} has no operand address (instruction at ${:04X})",mode,addr)
+            },
+            EmuError::RomTooLarge { size, max } => write!(f,"program is {} bytes but only {} fit",size,max),
+            EmuError::Jammed { opcode, addr } => write!(f,"CPU jammed by opcode ${:02X} at ${:04X}",opcode,addr),
+            EmuError::Cartridge(err) => write!(f,"{}",err),
+            EmuError::Mapper(err) => write!(f,"{}",err),
+            EmuError::State(err) => write!(f,"{}",err),
+            EmuError::Movie(err) => write!(f,"{}",err),
+        }
+    }
+}
+
+impl std::error::Error for EmuError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmuError::Cartridge(err) => Some(err),
+            EmuError::Mapper(err) => Some(err),
+            EmuError::State(err) => Some(err),
+            EmuError::Movie(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<CartridgeError> for EmuError {
+    fn from(err: CartridgeError) -> Self {
+        EmuError::Cartridge(err)
+    }
+}
+
+impl From<MapperError> for EmuError {
+    fn from(err: MapperError) -> Self {
+        EmuError::Mapper(err)
+    }
+}
+
+impl From<StateError> for EmuError {
+    fn from(err: StateError) -> Self {
+        EmuError::State(err)
+    }
+}
+
+impl From<MovieError> for EmuError {
+    fn from(err: MovieError) -> Self {
+        EmuError::Movie(err)
+    }
+}
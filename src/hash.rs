@@ -0,0 +1,80 @@
+//! Checksums shared by the file formats, the save states and the movies.
+
+/// CRC-32 as used by PNG, zip and the ROM databases (reflected, polynomial 0xEDB88320).
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// per round left rotations and the sine derived constants of RFC 1321
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+const MD5_K: [u32; 64] = [
+    0xD76A_A478, 0xE8C7_B756, 0x2420_70DB, 0xC1BD_CEEE, 0xF57C_0FAF, 0x4787_C62A, 0xA830_4613, 0xFD46_9501,
+    0x6980_98D8, 0x8B44_F7AF, 0xFFFF_5BB1, 0x895C_D7BE, 0x6B90_1122, 0xFD98_7193, 0xA679_438E, 0x49B4_0821,
+    0xF61E_2562, 0xC040_B340, 0x265E_5A51, 0xE9B6_C7AA, 0xD62F_105D, 0x0244_1453, 0xD8A1_E681, 0xE7D3_FBC8,
+    0x21E1_CDE6, 0xC337_07D6, 0xF4D5_0D87, 0x455A_14ED, 0xA9E3_E905, 0xFCEF_A3F8, 0x676F_02D9, 0x8D2A_4C8A,
+    0xFFFA_3942, 0x8771_F681, 0x6D9D_6122, 0xFDE5_380C, 0xA4BE_EA44, 0x4BDE_CFA9, 0xF6BB_4B60, 0xBEBF_BC70,
+    0x289B_7EC6, 0xEAA1_27FA, 0xD4EF_3085, 0x0488_1D05, 0xD9D4_D039, 0xE6DB_99E5, 0x1FA2_7CF8, 0xC4AC_5665,
+    0xF429_2244, 0x432A_FF97, 0xAB94_23A7, 0xFC93_A039, 0x655B_59C3, 0x8F0C_CC92, 0xFFEF_F47D, 0x8584_5DD1,
+    0x6FA8_7E4F, 0xFE2C_E6E0, 0xA301_4314, 0x4E08_11A1, 0xF753_7E82, 0xBD3A_F235, 0x2AD7_D2BB, 0xEB86_D391,
+];
+
+/// MD5 (RFC 1321), FCEUX identifies the ROM of a movie by the MD5 of its PRG and CHR ROM.
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend(((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+    let mut hash = [0x6745_2301u32, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476];
+    for chunk in message.chunks(64) {
+        let words: Vec<u32> = chunk.chunks(4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect();
+        let [mut a,mut b,mut c,mut d] = hash;
+        for i in 0..64 {
+            let (f,g) = match i / 16 {
+                0 => ((b & c) | (!b & d),i),
+                1 => ((d & b) | (!d & c),(5 * i + 1) % 16),
+                2 => (b ^ c ^ d,(3 * i + 5) % 16),
+                _ => (c ^ (b | !d),(7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(MD5_K[i]).wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(MD5_SHIFTS[i / 16 * 4 + i % 4]));
+        }
+        for (word,value) in hash.iter_mut().zip([a,b,c,d]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+    let mut digest = [0; 16];
+    for (bytes,word) in digest.chunks_mut(4).zip(hash) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"IEND"),0xAE42_6082);
+        assert_eq!(crc32(b"123456789"),0xCBF4_3926);
+    }
+    #[test]
+    fn test_md5() {
+        let hex = |digest: [u8; 16]| digest.iter().map(|byte| format!("{:02x}",byte)).collect::<String>();
+        assert_eq!(hex(md5(b"")),"d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(md5(b"abc")),"900150983cd24fb0d6963f7d28e17f72");
+        // two blocks of padding
+        assert_eq!(hex(md5(&[b'a'; 56])),"3b0c8ac703f828b04c6c197006d17218");
+    }
+}
@@ -0,0 +1,38 @@
+//! RNesEmu, a NES emulator core.
+//!
+//! `Console` is the whole machine: a 2A03 CPU on an `NesBus` with PPU, APU, controllers and a cartridge.
+//! The CPU also runs on any other `Bus`, e.g. `FlatBus` for bare 6502 programs.
+
+pub mod addressing_modes;
+pub mod apu;
+pub mod assembler;
+pub mod bus;
+pub mod cartridge;
+pub mod console;
+pub mod controller;
+pub mod cpu;
+pub mod determinism;
+pub mod disasm;
+pub mod error;
+pub mod hash;
+pub mod mapper;
+pub mod movie;
+pub mod ops_codes;
+pub mod ppu;
+pub mod rewind;
+pub mod state;
+pub mod trace;
+
+#[macro_use]
+extern crate lazy_static;
+
+pub use addressing_modes::AddrMode;
+pub use assembler::{assemble, AsmError, Segment};
+pub use bus::{Bus, FlatBus, NesBus, RamInit};
+pub use cartridge::Cartridge;
+pub use console::{Console, RecordFrom};
+pub use cpu::{CpuVariant, Step, CPU};
+pub use error::EmuError;
+pub use ops_codes::{opcode_table, OpCode, OpCodesMap};
+pub use ppu::frame::Frame;
+pub use state::{Snapshot, StateError};
@@ -1,12 +1,251 @@
-mod cpu;
-mod ops_codes;
-mod addressing_modes;
+//! Headless command line front end, it never opens a window or an audio device.
+//!
+//! rnesemu run <rom.nes> [--frames N] [--input movie.fm2] [--record out.fm2] [--screenshot out.png] [--wav out.wav] [--ram-init POLICY]
+//! rnesemu verify-determinism <rom.nes> [--input movie.fm2] [--frames N] [--ram-init POLICY]
+//! rnesemu disasm <rom.nes | code.bin> [--start ADDR] [--end ADDR] [--origin ADDR] [--variant 2a03|6502|65c02]
 
-#[macro_use]
-extern crate lazy_static;
+use std::error::Error;
+use std::process::ExitCode;
+use rnesemu::apu::encode_wav;
+use rnesemu::cartridge::NES_TAG;
+use rnesemu::determinism::verify_movie;
+use rnesemu::disasm::disassemble;
+use rnesemu::movie::Movie;
+use rnesemu::{Cartridge, Console, CpuVariant, FlatBus, NesBus, RamInit, RecordFrom};
 
-use cpu::CPU;
+const USAGE: &str = "usage:
+  rnesemu run <rom.nes> [options]
+    --frames N           number of frames to run, defaults to the movie length or 60
+    --input FILE         play back the controller input of an FM2 movie
+    --record FILE        save the controller input of the run as an FM2 movie
+    --screenshot FILE    save the last frame as PNG
+    --wav FILE           save the audio of the whole run as WAV
+    --ram-init POLICY    RAM contents at power on: zeros (default), ff, pattern or random:SEED
+  rnesemu verify-determinism <rom.nes> [options]
+    --input FILE         FM2 movie to play twice, without one no buttons are pressed
+    --frames N           number of frames to compare, defaults to the movie length or 60
+    --ram-init POLICY    RAM contents at power on, as for run
+  rnesemu disasm <rom.nes | code.bin> [options]
+    --start ADDR         first address to disassemble, defaults to $8000 for ROMs and the origin for raw code
+    --end ADDR           last address, defaults to $FFFF for ROMs and the end of raw code
+    --origin ADDR        where raw code is loaded, defaults to $0000
+    --variant NAME       2a03 (default), 6502 or 65c02
+  addresses are hexadecimal, with or without a $ or 0x prefix";
 
-fn main() {
-    println!("Hello, NES");
+const DEFAULT_FRAMES: usize = 60;
+
+#[derive(Default)]
+struct RunOptions {
+    rom: String,
+    frames: Option<usize>,
+    input: Option<String>,
+    record: Option<String>,
+    screenshot: Option<String>,
+    wav: Option<String>,
+    ram_init: RamInit,
+}
+
+#[derive(Default)]
+struct VerifyOptions {
+    rom: String,
+    input: Option<String>,
+    frames: Option<usize>,
+    ram_init: RamInit,
+}
+
+#[derive(Default)]
+struct DisasmOptions {
+    file: String,
+    start: Option<u16>,
+    end: Option<u16>,
+    origin: u16,
+    variant: CpuVariant,
+}
+
+fn parse_addr(text: &str) -> Result<u16,String> {
+    let digits = text.strip_prefix('$').or(text.strip_prefix("0x")).unwrap_or(text);
+    u16::from_str_radix(digits,16).map_err(|_| format!("invalid address {}",text))
+}
+
+fn parse_variant(text: &str) -> Result<CpuVariant,String> {
+    match text.to_ascii_lowercase().as_str() {
+        "2a03" => Ok(CpuVariant::Ricoh2A03),
+        "6502" => Ok(CpuVariant::Nmos6502),
+        "65c02" => Ok(CpuVariant::Cmos65C02),
+        _ => Err(format!("unknown cpu variant {}",text)),
+    }
+}
+
+fn parse_ram_init(text: &str) -> Result<RamInit,String> {
+    match text.to_ascii_lowercase().as_str() {
+        "zeros" => Ok(RamInit::Zeros),
+        "ff" => Ok(RamInit::Ones),
+        "pattern" => Ok(RamInit::Alternating),
+        policy => match policy.strip_prefix("random:").map(str::parse) {
+            Some(Ok(seed)) => Ok(RamInit::Random(seed)),
+            _ => Err(format!("unknown ram init policy {}",text)),
+        },
+    }
+}
+
+fn parse_disasm(args: &[String]) -> Result<DisasmOptions,String> {
+    let mut options = DisasmOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or(format!("{} needs a value",arg));
+        match arg.as_str() {
+            "--start" => options.start = Some(parse_addr(&value()?)?),
+            "--end" => options.end = Some(parse_addr(&value()?)?),
+            "--origin" => options.origin = parse_addr(&value()?)?,
+            "--variant" => options.variant = parse_variant(&value()?)?,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}",arg)),
+            _ if options.file.is_empty() => options.file = arg.clone(),
+            _ => return Err(format!("unexpected argument {}",arg)),
+        }
+    }
+    if options.file.is_empty() {
+        return Err("missing file to disassemble".to_string());
+    }
+    Ok(options)
+}
+
+fn parse_verify(args: &[String]) -> Result<VerifyOptions,String> {
+    let mut options = VerifyOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or(format!("{} needs a value",arg));
+        match arg.as_str() {
+            "--input" => options.input = Some(value()?),
+            "--frames" => options.frames = Some(value()?.parse().map_err(|_| "--frames needs a number".to_string())?),
+            "--ram-init" => options.ram_init = parse_ram_init(&value()?)?,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}",arg)),
+            _ if options.rom.is_empty() => options.rom = arg.clone(),
+            _ => return Err(format!("unexpected argument {}",arg)),
+        }
+    }
+    if options.rom.is_empty() {
+        return Err("missing rom file".to_string());
+    }
+    Ok(options)
+}
+
+fn parse_run(args: &[String]) -> Result<RunOptions,String> {
+    let mut options = RunOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or(format!("{} needs a value",arg));
+        match arg.as_str() {
+            "--frames" => options.frames = Some(value()?.parse().map_err(|_| "--frames needs a number".to_string())?),
+            "--input" => options.input = Some(value()?),
+            "--record" => options.record = Some(value()?),
+            "--screenshot" => options.screenshot = Some(value()?),
+            "--wav" => options.wav = Some(value()?),
+            "--ram-init" => options.ram_init = parse_ram_init(&value()?)?,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}",arg)),
+            _ if options.rom.is_empty() => options.rom = arg.clone(),
+            _ => return Err(format!("unexpected argument {}",arg)),
+        }
+    }
+    if options.rom.is_empty() {
+        return Err("missing rom file".to_string());
+    }
+    Ok(options)
+}
+
+fn run(options: &RunOptions) -> Result<(),Box<dyn Error>> {
+    let mut console = Console::with_ram_init(Cartridge::from_file(&options.rom)?,options.ram_init)?;
+    let movie = options.input.as_ref().map(Movie::from_file).transpose()?;
+    let frames = options.frames.or(movie.as_ref().map(|movie| movie.frames.len())).unwrap_or(DEFAULT_FRAMES);
+    if let Some(movie) = &movie {
+        console.start_movie(movie)?;
+    }
+    // a movie that starts from a save state is re-recorded from that same state
+    if options.record.is_some() {
+        let from = if movie.as_ref().is_some_and(|movie| movie.start_state.is_some()) { RecordFrom::SaveState } else { RecordFrom::PowerOn };
+        console.start_recording(from)?;
+    }
+    let mut samples = Vec::new();
+    for frame in 0..frames {
+        if let Some(input) = movie.as_ref().and_then(|movie| movie.frames.get(frame)) {
+            console.apply_input(input)?;
+        }
+        console.run_frame()?;
+        // drained every frame, so they do not pile up when nobody wants them
+        let frame_samples = console.take_samples();
+        if options.wav.is_some() {
+            samples.extend(frame_samples);
+        }
+    }
+    if let (Some(path),Some(recording)) = (&options.record,console.stop_recording()) {
+        recording.save(path)?;
+    }
+    if let Some(path) = &options.screenshot {
+        std::fs::write(path,console.frame().to_png())?;
+    }
+    if let Some(path) = &options.wav {
+        std::fs::write(path,encode_wav(&samples,console.cpu.bus.apu.sample_rate()))?;
+    }
+    Ok(())
+}
+
+fn verify_determinism(options: &VerifyOptions) -> Result<(),Box<dyn Error>> {
+    let cartridge = Cartridge::from_file(&options.rom)?;
+    let movie = options.input.as_ref().map(Movie::from_file).transpose()?.unwrap_or_default();
+    let frames = options.frames.or(options.input.as_ref().map(|_| movie.frames.len())).unwrap_or(DEFAULT_FRAMES);
+    match verify_movie(&cartridge,&movie,frames,options.ram_init)? {
+        Some(divergence) => Err(divergence.to_string().into()),
+        None => {
+            println!("deterministic over {} frames",frames);
+            Ok(())
+        },
+    }
+}
+
+// iNES files are disassembled as the CPU sees them through their mapper, anything else is raw code
+fn disasm(options: &DisasmOptions) -> Result<(),Box<dyn Error>> {
+    let raw = std::fs::read(&options.file)?;
+    let instructions = if raw.starts_with(&NES_TAG) {
+        let mut bus = NesBus::new();
+        bus.insert_cartridge(Cartridge::new(&raw)?)?;
+        disassemble(&mut bus,options.variant,options.start.unwrap_or(0x8000)..=options.end.unwrap_or(0xFFFF))
+    }else {
+        if raw.is_empty() || options.origin as usize + raw.len() > 0x10000 {
+            return Err(format!("{} bytes do not fit in memory at ${:04X}",raw.len(),options.origin).into());
+        }
+        let mut bus = FlatBus::new();
+        bus.load(options.origin,&raw);
+        let end = options.origin + (raw.len() - 1) as u16;
+        disassemble(&mut bus,options.variant,options.start.unwrap_or(options.origin)..=options.end.unwrap_or(end))
+    };
+    for instruction in instructions {
+        println!("{}",instruction);
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("run") => parse_run(&args[1..]).map(|options| run(&options)),
+        Some("verify-determinism") => parse_verify(&args[1..]).map(|options| verify_determinism(&options)),
+        Some("disasm") => parse_disasm(&args[1..]).map(|options| disasm(&options)),
+        _ => Err(String::new()),
+    };
+    let result = match result {
+        Ok(result) => result,
+        Err(err) => {
+            if !err.is_empty() {
+                eprintln!("{}",err);
+            }
+            eprintln!("{}",USAGE);
+            return ExitCode::from(2);
+        },
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}",err);
+            ExitCode::FAILURE
+        },
+    }
 }
@@ -0,0 +1,80 @@
+//! AxROM (mapper 7): a switchable 32 KiB PRG bank and one-screen mirroring.
+//! Writes to [0x8000 .. 0xFFFF]: bits 0-2 select the PRG bank, bit 4 selects the nametable.
+
+use crate::cartridge::Mirroring;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use super::{load_mirroring, CartridgeMemory, Mapper, PRG_ROM_START};
+
+pub struct AxRom {
+    memory: CartridgeMemory,
+    prg_bank: usize,
+    mirroring: Mirroring,
+}
+
+impl AxRom {
+    pub fn new(memory: CartridgeMemory) -> Self {
+        AxRom {
+            memory,
+            prg_bank: 0,
+            mirroring: Mirroring::SingleScreenLower,
+        }
+    }
+}
+
+impl Snapshot for AxRom {
+    fn save_state(&self,state: &mut StateWriter) {
+        self.memory.save_state(state);
+        state.u32(self.prg_bank as u32);
+        state.u8(self.mirroring as u8);
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        self.memory.load_state(state)?;
+        self.prg_bank = state.u32()? as usize;
+        self.mirroring = load_mirroring(state)?;
+        Ok(())
+    }
+}
+
+impl Mapper for AxRom {
+    fn cpu_read(&mut self,addr: u16) -> u8 {
+        match addr {
+            PRG_ROM_START ..= 0xFFFF => self.memory.read_prg(0x8000,self.prg_bank,(addr - PRG_ROM_START) as usize),
+            _ => 0,
+        }
+    }
+    fn cpu_write(&mut self,addr: u16,data: u8) {
+        if let PRG_ROM_START ..= 0xFFFF = addr {
+            self.prg_bank = (data & 0b111) as usize;
+            self.mirroring = if data & 0b1_0000 != 0 {
+                Mirroring::SingleScreenUpper
+            }else {
+                Mirroring::SingleScreenLower
+            };
+        }
+    }
+    fn ppu_read(&mut self,addr: u16) -> u8 {
+        self.memory.read_chr(0x2000,0,addr as usize)
+    }
+    fn ppu_write(&mut self,addr: u16,data: u8) {
+        self.memory.write_chr(0x2000,0,addr as usize,data);
+    }
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cartridge::Mirroring;
+    use crate::mapper::create_mapper;
+    use crate::mapper::test::test_cartridge;
+    #[test]
+    fn test_prg_bank_and_single_screen() {
+        let mut mapper = create_mapper(test_cartridge(7,0x20000,0)).unwrap();
+        assert_eq!(mapper.mirroring(),Mirroring::SingleScreenLower);
+        mapper.cpu_write(0x8000,0b1_0010);
+        assert_eq!(mapper.cpu_read(0x8000),8);
+        assert_eq!(mapper.cpu_read(0xE000),11);
+        assert_eq!(mapper.mirroring(),Mirroring::SingleScreenUpper);
+    }
+}
@@ -0,0 +1,76 @@
+//! CNROM (mapper 3): fixed 16/32 KiB PRG ROM and a switchable 8 KiB CHR ROM bank.
+//! Any write to [0x8000 .. 0xFFFF] selects the CHR bank.
+
+use crate::cartridge::Mirroring;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use super::{CartridgeMemory, Mapper, PRG_RAM_END, PRG_RAM_START, PRG_ROM_START};
+
+pub struct CnRom {
+    memory: CartridgeMemory,
+    mirroring: Mirroring,
+    chr_bank: usize,
+}
+
+impl CnRom {
+    pub fn new(memory: CartridgeMemory,mirroring: Mirroring) -> Self {
+        CnRom {
+            memory,
+            mirroring,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Snapshot for CnRom {
+    fn save_state(&self,state: &mut StateWriter) {
+        self.memory.save_state(state);
+        state.u32(self.chr_bank as u32);
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        self.memory.load_state(state)?;
+        self.chr_bank = state.u32()? as usize;
+        Ok(())
+    }
+}
+
+impl Mapper for CnRom {
+    fn cpu_read(&mut self,addr: u16) -> u8 {
+        match addr {
+            PRG_RAM_START ..= PRG_RAM_END => self.memory.read_prg_ram(addr),
+            PRG_ROM_START ..= 0xFFFF => self.memory.read_prg(0x8000,0,(addr - PRG_ROM_START) as usize),
+            _ => 0,
+        }
+    }
+    fn cpu_write(&mut self,addr: u16,data: u8) {
+        match addr {
+            PRG_RAM_START ..= PRG_RAM_END => self.memory.write_prg_ram(addr,data),
+            PRG_ROM_START ..= 0xFFFF => self.chr_bank = data as usize,
+            _ => {},
+        }
+    }
+    fn ppu_read(&mut self,addr: u16) -> u8 {
+        self.memory.read_chr(0x2000,self.chr_bank,addr as usize)
+    }
+    fn ppu_write(&mut self,addr: u16,data: u8) {
+        self.memory.write_chr(0x2000,self.chr_bank,addr as usize,data);
+    }
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mapper::create_mapper;
+    use crate::mapper::test::test_cartridge;
+    #[test]
+    fn test_chr_bank_switch() {
+        let mut mapper = create_mapper(test_cartridge(3,0x8000,0x8000)).unwrap();
+        assert_eq!(mapper.ppu_read(0x0000),0);
+        mapper.cpu_write(0xFFFF,2);
+        // bank 2 starts at 1 KiB page 16
+        assert_eq!(mapper.ppu_read(0x0000),16);
+        assert_eq!(mapper.ppu_read(0x1FFF),23);
+        assert_eq!(mapper.cpu_read(0xE000),3);
+    }
+}
@@ -0,0 +1,193 @@
+//! MMC1 / SxROM (mapper 1).
+//!
+//! Registers are loaded serially: every write to [0x8000 .. 0xFFFF] shifts bit 0 into a 5 bit shift register,
+//! the fifth write copies it into the register selected by address bits 13-14. A write with bit 7 set resets the shift register.
+//! [0x8000 .. 0x9FFF] control: mirroring (bits 0-1), PRG bank mode (bits 2-3), CHR bank mode (bit 4)
+//! [0xA000 .. 0xBFFF] CHR bank 0
+//! [0xC000 .. 0xDFFF] CHR bank 1
+//! [0xE000 .. 0xFFFF] PRG bank (bits 0-3), PRG RAM disable (bit 4)
+//!
+//! On 512 KiB boards (SUROM) bit 4 of the CHR bank registers selects the 256 KiB half of PRG ROM.
+
+use crate::cartridge::Mirroring;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use super::{CartridgeMemory, Mapper, PRG_RAM_END, PRG_RAM_START, PRG_ROM_START};
+
+// the marker bit reaches bit 0 after 4 shifts, so the next write completes the register
+const SHIFT_REGISTER_RESET: u8 = 0b1_0000;
+
+pub struct Mmc1 {
+    memory: CartridgeMemory,
+    shift_register: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    pub fn new(memory: CartridgeMemory) -> Self {
+        Mmc1 {
+            memory,
+            shift_register: SHIFT_REGISTER_RESET,
+            // power on in PRG mode 3 so the reset vector comes from the last bank
+            control: 0b0_1100,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        }
+    }
+    fn write_register(&mut self,addr: u16,data: u8) {
+        match addr {
+            0x8000 ..= 0x9FFF => self.control = data,
+            0xA000 ..= 0xBFFF => self.chr_bank_0 = data,
+            0xC000 ..= 0xDFFF => self.chr_bank_1 = data,
+            _ => self.prg_bank = data,
+        }
+    }
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0b1_0000 == 0
+    }
+    // 256 KiB window selected by SUROM boards, always 0 on smaller boards
+    fn prg_outer_bank(&self) -> usize {
+        if self.memory.prg_rom.len() > 0x40000 {
+            (self.chr_bank_0 & 0b1_0000) as usize
+        }else {
+            0
+        }
+    }
+    fn prg_bank_for(&self,addr: u16) -> usize {
+        let bank = (self.prg_bank & 0b1111) as usize;
+        let last = (self.memory.prg_banks(0x4000) - 1).min(0b1111);
+        let bank = match (self.control >> 2) & 0b11 {
+            // 32 KiB mode ignores the low bit of the bank number
+            0 | 1 => (bank & !1) | ((addr >= 0xC000) as usize),
+            2 => if addr < 0xC000 { 0 } else { bank },
+            _ => if addr < 0xC000 { bank } else { last },
+        };
+        self.prg_outer_bank() | bank
+    }
+    fn chr_bank_for(&self,addr: u16) -> usize {
+        if self.control & 0b1_0000 == 0 {
+            // 8 KiB mode ignores the low bit of CHR bank 0
+            ((self.chr_bank_0 & !1) as usize) | ((addr >= 0x1000) as usize)
+        }else if addr < 0x1000 {
+            self.chr_bank_0 as usize
+        }else {
+            self.chr_bank_1 as usize
+        }
+    }
+}
+
+impl Snapshot for Mmc1 {
+    fn save_state(&self,state: &mut StateWriter) {
+        self.memory.save_state(state);
+        state.u8(self.shift_register);
+        state.u8(self.control);
+        state.u8(self.chr_bank_0);
+        state.u8(self.chr_bank_1);
+        state.u8(self.prg_bank);
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        self.memory.load_state(state)?;
+        self.shift_register = state.u8()?;
+        self.control = state.u8()?;
+        self.chr_bank_0 = state.u8()?;
+        self.chr_bank_1 = state.u8()?;
+        self.prg_bank = state.u8()?;
+        Ok(())
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_read(&mut self,addr: u16) -> u8 {
+        match addr {
+            PRG_RAM_START ..= PRG_RAM_END if self.prg_ram_enabled() => self.memory.read_prg_ram(addr),
+            PRG_ROM_START ..= 0xFFFF => {
+                let bank = self.prg_bank_for(addr);
+                self.memory.read_prg(0x4000,bank,(addr & 0x3FFF) as usize)
+            },
+            _ => 0,
+        }
+    }
+    fn cpu_write(&mut self,addr: u16,data: u8) {
+        match addr {
+            PRG_RAM_START ..= PRG_RAM_END if self.prg_ram_enabled() => self.memory.write_prg_ram(addr,data),
+            PRG_ROM_START ..= 0xFFFF => {
+                if data & 0b1000_0000 != 0 {
+                    self.shift_register = SHIFT_REGISTER_RESET;
+                    self.control |= 0b0_1100;
+                    return;
+                }
+                let complete = self.shift_register & 1 != 0;
+                self.shift_register = (self.shift_register >> 1) | ((data & 1) << 4);
+                if complete {
+                    self.write_register(addr,self.shift_register);
+                    self.shift_register = SHIFT_REGISTER_RESET;
+                }
+            },
+            _ => {},
+        }
+    }
+    fn ppu_read(&mut self,addr: u16) -> u8 {
+        let bank = self.chr_bank_for(addr);
+        self.memory.read_chr(0x1000,bank,(addr & 0x0FFF) as usize)
+    }
+    fn ppu_write(&mut self,addr: u16,data: u8) {
+        let bank = self.chr_bank_for(addr);
+        self.memory.write_chr(0x1000,bank,(addr & 0x0FFF) as usize,data);
+    }
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cartridge::Mirroring;
+    use crate::mapper::{create_mapper, Mapper};
+    use crate::mapper::test::test_cartridge;
+    fn write_serial(mapper: &mut dyn Mapper,addr: u16,data: u8) {
+        for bit in 0..5 {
+            mapper.cpu_write(addr,(data >> bit) & 1);
+        }
+    }
+    #[test]
+    fn test_power_on_fixes_last_bank() {
+        let mut mapper = create_mapper(test_cartridge(1,0x40000,0x20000)).unwrap();
+        assert_eq!(mapper.cpu_read(0xC000),30);
+        assert_eq!(mapper.cpu_read(0xE000),31);
+    }
+    #[test]
+    fn test_serial_registers() {
+        let mut mapper = create_mapper(test_cartridge(1,0x40000,0x20000)).unwrap();
+        // vertical mirroring, PRG mode 3, 4 KiB CHR banks
+        write_serial(mapper.as_mut(),0x8000,0b1_1110);
+        assert_eq!(mapper.mirroring(),Mirroring::Vertical);
+        write_serial(mapper.as_mut(),0xE000,5);
+        assert_eq!(mapper.cpu_read(0x8000),10);
+        assert_eq!(mapper.cpu_read(0xE000),31);
+        write_serial(mapper.as_mut(),0xA000,3);
+        write_serial(mapper.as_mut(),0xC000,7);
+        assert_eq!(mapper.ppu_read(0x0000),12);
+        assert_eq!(mapper.ppu_read(0x1000),28);
+        // a reset write in the middle of a sequence discards the partial value
+        mapper.cpu_write(0x8000,1);
+        mapper.cpu_write(0x8000,0x80);
+        write_serial(mapper.as_mut(),0x8000,0b0_0011);
+        assert_eq!(mapper.mirroring(),Mirroring::Horizontal);
+    }
+    #[test]
+    fn test_prg_ram_disable() {
+        let mut mapper = create_mapper(test_cartridge(1,0x40000,0)).unwrap();
+        mapper.cpu_write(0x6000,0x77);
+        assert_eq!(mapper.cpu_read(0x6000),0x77);
+        write_serial(mapper.as_mut(),0xE000,0b1_0000);
+        assert_eq!(mapper.cpu_read(0x6000),0);
+    }
+}
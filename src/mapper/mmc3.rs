@@ -0,0 +1,241 @@
+//! MMC3 / TxROM (mapper 4).
+//!
+//! [0x8000 .. 0x9FFF] even: bank select, odd: bank data
+//! [0xA000 .. 0xBFFF] even: mirroring, odd: PRG RAM protect
+//! [0xC000 .. 0xDFFF] even: IRQ latch, odd: IRQ reload
+//! [0xE000 .. 0xFFFF] even: IRQ disable, odd: IRQ enable
+//!
+//! PRG is banked in 8 KiB pages, the second-to-last page sits at 0x8000 or 0xC000 depending on bit 6 of bank select.
+//! CHR is banked as two 2 KiB and four 1 KiB pages, bit 7 of bank select swaps the two pattern tables.
+//! The scanline counter reloads from the latch when it reaches 0 and raises an IRQ when it decrements to 0.
+
+use crate::cartridge::Mirroring;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use super::{load_mirroring, CartridgeMemory, Mapper, PRG_RAM_END, PRG_RAM_START, PRG_ROM_START};
+
+pub struct Mmc3 {
+    memory: CartridgeMemory,
+    // headers asking for four screen mirroring override the mirroring register
+    four_screen: bool,
+    mirroring: Mirroring,
+    bank_select: u8,
+    registers: [u8; 8],
+    prg_ram_enabled: bool,
+    prg_ram_write_protect: bool,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Mmc3 {
+    pub fn new(memory: CartridgeMemory,mirroring: Mirroring) -> Self {
+        Mmc3 {
+            memory,
+            four_screen: mirroring == Mirroring::FourScreen,
+            mirroring,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            prg_ram_enabled: true,
+            prg_ram_write_protect: false,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+    fn prg_bank_for(&self,addr: u16) -> usize {
+        // NES 2.0 sizes can describe PRG ROM below 16 KiB, `banked` wraps the bank numbers into what there is
+        let second_last = self.memory.prg_banks(0x2000).saturating_sub(2);
+        let swapped = self.bank_select & 0b0100_0000 != 0;
+        match (addr - PRG_ROM_START) / 0x2000 {
+            0 => if swapped { second_last } else { (self.registers[6] & 0x3F) as usize },
+            1 => (self.registers[7] & 0x3F) as usize,
+            2 => if swapped { (self.registers[6] & 0x3F) as usize } else { second_last },
+            _ => second_last + 1,
+        }
+    }
+    // returns the 1 KiB CHR page mapped at addr
+    fn chr_bank_for(&self,addr: u16) -> usize {
+        // with A12 inversion the 2 KiB pages move to 0x1000
+        let addr = if self.bank_select & 0b1000_0000 != 0 { addr ^ 0x1000 } else { addr };
+        match addr / 0x400 {
+            0 => (self.registers[0] & !1) as usize,
+            1 => (self.registers[0] | 1) as usize,
+            2 => (self.registers[1] & !1) as usize,
+            3 => (self.registers[1] | 1) as usize,
+            page => self.registers[page as usize - 2] as usize,
+        }
+    }
+}
+
+impl Snapshot for Mmc3 {
+    fn save_state(&self,state: &mut StateWriter) {
+        self.memory.save_state(state);
+        state.u8(self.mirroring as u8);
+        state.u8(self.bank_select);
+        state.bytes(&self.registers);
+        state.bool(self.prg_ram_enabled);
+        state.bool(self.prg_ram_write_protect);
+        state.u8(self.irq_latch);
+        state.u8(self.irq_counter);
+        state.bool(self.irq_reload);
+        state.bool(self.irq_enabled);
+        state.bool(self.irq_pending);
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        self.memory.load_state(state)?;
+        self.mirroring = load_mirroring(state)?;
+        self.bank_select = state.u8()?;
+        state.bytes_into(&mut self.registers)?;
+        self.prg_ram_enabled = state.bool()?;
+        self.prg_ram_write_protect = state.bool()?;
+        self.irq_latch = state.u8()?;
+        self.irq_counter = state.u8()?;
+        self.irq_reload = state.bool()?;
+        self.irq_enabled = state.bool()?;
+        self.irq_pending = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn cpu_read(&mut self,addr: u16) -> u8 {
+        match addr {
+            PRG_RAM_START ..= PRG_RAM_END if self.prg_ram_enabled => self.memory.read_prg_ram(addr),
+            PRG_ROM_START ..= 0xFFFF => {
+                let bank = self.prg_bank_for(addr);
+                self.memory.read_prg(0x2000,bank,(addr & 0x1FFF) as usize)
+            },
+            _ => 0,
+        }
+    }
+    fn cpu_write(&mut self,addr: u16,data: u8) {
+        let even = addr & 1 == 0;
+        match addr {
+            PRG_RAM_START ..= PRG_RAM_END if self.prg_ram_enabled && !self.prg_ram_write_protect => self.memory.write_prg_ram(addr,data),
+            0x8000 ..= 0x9FFF => {
+                if even {
+                    self.bank_select = data;
+                }else {
+                    self.registers[(self.bank_select & 0b111) as usize] = data;
+                }
+            },
+            0xA000 ..= 0xBFFF => {
+                if even {
+                    if !self.four_screen {
+                        self.mirroring = if data & 1 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+                    }
+                }else {
+                    self.prg_ram_enabled = data & 0b1000_0000 != 0;
+                    self.prg_ram_write_protect = data & 0b0100_0000 != 0;
+                }
+            },
+            0xC000 ..= 0xDFFF => {
+                if even {
+                    self.irq_latch = data;
+                }else {
+                    self.irq_counter = 0;
+                    self.irq_reload = true;
+                }
+            },
+            0xE000 ..= 0xFFFF => {
+                if even {
+                    self.irq_enabled = false;
+                    self.irq_pending = false;
+                }else {
+                    self.irq_enabled = true;
+                }
+            },
+            _ => {},
+        }
+    }
+    fn ppu_read(&mut self,addr: u16) -> u8 {
+        let bank = self.chr_bank_for(addr);
+        self.memory.read_chr(0x400,bank,(addr & 0x03FF) as usize)
+    }
+    fn ppu_write(&mut self,addr: u16,data: u8) {
+        let bank = self.chr_bank_for(addr);
+        self.memory.write_chr(0x400,bank,(addr & 0x03FF) as usize,data);
+    }
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+    fn scanline(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        }else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cartridge::Mirroring;
+    use crate::mapper::create_mapper;
+    use crate::mapper::test::test_cartridge;
+    #[test]
+    fn test_prg_banking_modes() {
+        let mut mapper = create_mapper(test_cartridge(4,0x20000,0x20000)).unwrap();
+        mapper.cpu_write(0x8000,6);
+        mapper.cpu_write(0x8001,3);
+        mapper.cpu_write(0x8000,7);
+        mapper.cpu_write(0x8001,5);
+        assert_eq!(mapper.cpu_read(0x8000),3);
+        assert_eq!(mapper.cpu_read(0xA000),5);
+        assert_eq!(mapper.cpu_read(0xC000),14);
+        assert_eq!(mapper.cpu_read(0xE000),15);
+        mapper.cpu_write(0x8000,0b0100_0111);
+        assert_eq!(mapper.cpu_read(0x8000),14);
+        assert_eq!(mapper.cpu_read(0xC000),3);
+    }
+    #[test]
+    fn test_prg_smaller_than_16k() {
+        let mut mapper = create_mapper(test_cartridge(4,0x2000,0x2000)).unwrap();
+        for addr in [0x8000,0xA000,0xC000,0xE000] {
+            assert_eq!(mapper.cpu_read(addr),0);
+        }
+    }
+    #[test]
+    fn test_chr_banking_and_inversion() {
+        let mut mapper = create_mapper(test_cartridge(4,0x20000,0x20000)).unwrap();
+        mapper.cpu_write(0x8000,0);
+        mapper.cpu_write(0x8001,9);
+        mapper.cpu_write(0x8000,5);
+        mapper.cpu_write(0x8001,33);
+        // 2 KiB banks ignore the low bit
+        assert_eq!(mapper.ppu_read(0x0000),8);
+        assert_eq!(mapper.ppu_read(0x0400),9);
+        assert_eq!(mapper.ppu_read(0x1C00),33);
+        mapper.cpu_write(0x8000,0b1000_0000);
+        assert_eq!(mapper.ppu_read(0x1000),8);
+        assert_eq!(mapper.ppu_read(0x0C00),33);
+        mapper.cpu_write(0xA000,1);
+        assert_eq!(mapper.mirroring(),Mirroring::Horizontal);
+    }
+    #[test]
+    fn test_scanline_irq() {
+        let mut mapper = create_mapper(test_cartridge(4,0x20000,0x20000)).unwrap();
+        mapper.cpu_write(0xC000,2);
+        mapper.cpu_write(0xC001,0);
+        mapper.cpu_write(0xE001,0);
+        // reload to 2, then 1, then 0 fires
+        mapper.scanline();
+        mapper.scanline();
+        assert!(!mapper.irq());
+        mapper.scanline();
+        assert!(mapper.irq());
+        mapper.cpu_write(0xE000,0);
+        assert!(!mapper.irq());
+    }
+}
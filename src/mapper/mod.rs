@@ -0,0 +1,208 @@
+//! Mappers are the bank switching hardware on the cartridge board.
+//! They decide which part of PRG ROM the CPU sees in [0x8000 .. 0xFFFF],
+//! which part of CHR the PPU sees in [0x0000 .. 0x1FFF], and may also
+//! provide PRG RAM at [0x6000 .. 0x7FFF], control nametable mirroring and raise IRQs.
+//!
+//! supported boards: https://www.nesdev.org/wiki/Mapper
+//! 0 NROM, 1 MMC1 (SxROM), 2 UxROM, 3 CNROM, 4 MMC3 (TxROM), 7 AxROM
+
+mod axrom;
+mod cnrom;
+mod mmc1;
+mod mmc3;
+mod no_cartridge;
+mod nrom;
+mod uxrom;
+
+use std::fmt;
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+pub use axrom::AxRom;
+pub use cnrom::CnRom;
+pub use mmc1::Mmc1;
+pub use mmc3::Mmc3;
+pub use no_cartridge::NoCartridge;
+pub use nrom::NRom;
+pub use uxrom::UxRom;
+
+pub const PRG_RAM_START: u16 = 0x6000;
+pub const PRG_RAM_END: u16 = 0x7FFF;
+pub const PRG_ROM_START: u16 = 0x8000;
+
+// the state of a board is its registers and RAM, ROM is never saved
+pub trait Mapper: Snapshot {
+    // CPU side, [0x4020 .. 0xFFFF]
+    fn cpu_read(&mut self,addr: u16) -> u8;
+    fn cpu_write(&mut self,addr: u16,data: u8);
+    // PPU side, pattern tables in [0x0000 .. 0x1FFF]
+    fn ppu_read(&mut self,addr: u16) -> u8;
+    fn ppu_write(&mut self,addr: u16,data: u8);
+    fn mirroring(&self) -> Mirroring;
+    // clocked by the PPU once per rendered scanline, drives scanline counters such as the MMC3 one
+    fn scanline(&mut self) {}
+    // level of the cartridge IRQ line
+    fn irq(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug)]
+pub enum MapperError {
+    UnsupportedMapper(u16),
+}
+
+impl fmt::Display for MapperError {
+    fn fmt(&self,f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapperError::UnsupportedMapper(id) => write!(f,"mapper {} is not supported",id),
+        }
+    }
+}
+
+impl std::error::Error for MapperError {}
+
+pub fn create_mapper(cartridge: Cartridge) -> Result<Box<dyn Mapper>,MapperError> {
+    let memory = CartridgeMemory::new(&cartridge);
+    let mirroring = cartridge.mirroring;
+    match cartridge.mapper {
+        0 => Ok(Box::new(NRom::new(memory,mirroring))),
+        1 => Ok(Box::new(Mmc1::new(memory))),
+        2 => Ok(Box::new(UxRom::new(memory,mirroring))),
+        3 => Ok(Box::new(CnRom::new(memory,mirroring))),
+        4 => Ok(Box::new(Mmc3::new(memory,mirroring))),
+        7 => Ok(Box::new(AxRom::new(memory))),
+        id => Err(MapperError::UnsupportedMapper(id)),
+    }
+}
+
+// the memories every board carries, the mappers only differ in how they bank them
+pub struct CartridgeMemory {
+    pub prg_rom: Vec<u8>,
+    // CHR ROM, or CHR RAM for boards that ship without CHR ROM
+    pub chr: Vec<u8>,
+    pub chr_is_ram: bool,
+    pub prg_ram: Vec<u8>,
+}
+
+impl CartridgeMemory {
+    pub fn new(cartridge: &Cartridge) -> Self {
+        let (chr,chr_is_ram) = if cartridge.chr_rom.is_empty() {
+            let size = (cartridge.chr_ram_size + cartridge.chr_nvram_size).max(0x2000);
+            (vec![0; size],true)
+        }else {
+            (cartridge.chr_rom.clone(),false)
+        };
+        // boards with PRG RAM almost always carry 8 KiB, even when the header does not say so
+        let prg_ram_size = (cartridge.prg_ram_size + cartridge.prg_nvram_size).max(0x2000);
+        CartridgeMemory {
+            prg_rom: cartridge.prg_rom.clone(),
+            chr,
+            chr_is_ram,
+            prg_ram: vec![0; prg_ram_size],
+        }
+    }
+    // reads `offset` inside bank number `bank` of `bank_size` bytes, bank numbers wrap around the PRG size
+    pub fn read_prg(&self,bank_size: usize,bank: usize,offset: usize) -> u8 {
+        self.prg_rom[banked(self.prg_rom.len(),bank_size,bank,offset)]
+    }
+    pub fn read_chr(&self,bank_size: usize,bank: usize,offset: usize) -> u8 {
+        self.chr[banked(self.chr.len(),bank_size,bank,offset)]
+    }
+    pub fn write_chr(&mut self,bank_size: usize,bank: usize,offset: usize,data: u8) {
+        if self.chr_is_ram {
+            let index = banked(self.chr.len(),bank_size,bank,offset);
+            self.chr[index] = data;
+        }
+    }
+    pub fn read_prg_ram(&self,addr: u16) -> u8 {
+        self.prg_ram[(addr - PRG_RAM_START) as usize % self.prg_ram.len()]
+    }
+    pub fn write_prg_ram(&mut self,addr: u16,data: u8) {
+        let len = self.prg_ram.len();
+        self.prg_ram[(addr - PRG_RAM_START) as usize % len] = data;
+    }
+    pub fn prg_banks(&self,bank_size: usize) -> usize {
+        (self.prg_rom.len() / bank_size).max(1)
+    }
+}
+
+// PRG RAM and CHR RAM are the only memories a board can change
+impl Snapshot for CartridgeMemory {
+    fn save_state(&self,state: &mut StateWriter) {
+        state.bytes(&self.prg_ram);
+        if self.chr_is_ram {
+            state.bytes(&self.chr);
+        }
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        state.bytes_into(&mut self.prg_ram)?;
+        if self.chr_is_ram {
+            state.bytes_into(&mut self.chr)?;
+        }
+        Ok(())
+    }
+}
+
+// mirroring registers are saved as the index of the variant
+fn load_mirroring(state: &mut StateReader) -> Result<Mirroring,StateError> {
+    let mirroring = match state.u8()? {
+        0 => Mirroring::Horizontal,
+        1 => Mirroring::Vertical,
+        2 => Mirroring::FourScreen,
+        3 => Mirroring::SingleScreenLower,
+        index => {
+            state.ensure(index == 4)?;
+            Mirroring::SingleScreenUpper
+        },
+    };
+    Ok(mirroring)
+}
+
+fn banked(len: usize,bank_size: usize,bank: usize,offset: usize) -> usize {
+    let banks = (len / bank_size).max(1);
+    ((bank % banks) * bank_size + offset) % len
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::cartridge::{RomFormat, Timing};
+    // builds a cartridge whose every 8 KiB PRG page and 1 KiB CHR page is filled with its own page number
+    pub fn test_cartridge(mapper: u16,prg_rom_size: usize,chr_rom_size: usize) -> Cartridge {
+        Cartridge {
+            name: "test".to_string(),
+            format: RomFormat::INes,
+            prg_rom: (0..prg_rom_size).map(|i| (i / 0x2000) as u8).collect(),
+            chr_rom: (0..chr_rom_size).map(|i| (i / 0x400) as u8).collect(),
+            trainer: None,
+            mapper,
+            submapper: 0,
+            mirroring: Mirroring::Vertical,
+            battery: false,
+            prg_ram_size: 0x2000,
+            prg_nvram_size: 0,
+            chr_ram_size: if chr_rom_size == 0 { 0x2000 } else { 0 },
+            chr_nvram_size: 0,
+            timing: Timing::Ntsc,
+        }
+    }
+    #[test]
+    fn test_unsupported_mapper() {
+        assert!(matches!(create_mapper(test_cartridge(5,0x8000,0x2000)),Err(MapperError::UnsupportedMapper(5))));
+    }
+    #[test]
+    fn test_nrom_mirrors_16k_prg() {
+        let mut mapper = create_mapper(test_cartridge(0,0x4000,0x2000)).unwrap();
+        assert_eq!(mapper.cpu_read(0x8000),0);
+        assert_eq!(mapper.cpu_read(0xA000),1);
+        assert_eq!(mapper.cpu_read(0xC000),0);
+        assert_eq!(mapper.cpu_read(0xE000),1);
+        assert_eq!(mapper.ppu_read(0x1C00),7);
+        // CHR ROM is read only
+        mapper.ppu_write(0x1C00,0xFF);
+        assert_eq!(mapper.ppu_read(0x1C00),7);
+        mapper.cpu_write(0x6000,0x42);
+        assert_eq!(mapper.cpu_read(0x6000),0x42);
+    }
+}
@@ -0,0 +1,56 @@
+//! The empty cartridge slot. [0x4020 .. 0xFFFF] behaves as plain RAM and the pattern tables are 8 KiB of CHR RAM,
+//! so raw programs loaded with CPU::load_program can run without a ROM file.
+
+use crate::bus::CARTRIDGE_SPACE;
+use crate::cartridge::Mirroring;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use super::Mapper;
+
+pub struct NoCartridge {
+    ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+}
+
+impl Default for NoCartridge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NoCartridge {
+    pub fn new() -> Self {
+        NoCartridge {
+            ram: vec![0; 0x10000 - CARTRIDGE_SPACE as usize],
+            chr_ram: vec![0; 0x2000],
+        }
+    }
+}
+
+impl Snapshot for NoCartridge {
+    fn save_state(&self,state: &mut StateWriter) {
+        state.bytes(&self.ram);
+        state.bytes(&self.chr_ram);
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        state.bytes_into(&mut self.ram)?;
+        state.bytes_into(&mut self.chr_ram)
+    }
+}
+
+impl Mapper for NoCartridge {
+    fn cpu_read(&mut self,addr: u16) -> u8 {
+        self.ram[(addr - CARTRIDGE_SPACE) as usize]
+    }
+    fn cpu_write(&mut self,addr: u16,data: u8) {
+        self.ram[(addr - CARTRIDGE_SPACE) as usize] = data;
+    }
+    fn ppu_read(&mut self,addr: u16) -> u8 {
+        self.chr_ram[(addr & 0x1FFF) as usize]
+    }
+    fn ppu_write(&mut self,addr: u16,data: u8) {
+        self.chr_ram[(addr & 0x1FFF) as usize] = data;
+    }
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+}
@@ -0,0 +1,53 @@
+//! NROM (mapper 0): no bank switching.
+//! 16 KiB PRG ROM is mirrored into [0xC000 .. 0xFFFF], 32 KiB fills the whole window.
+
+use crate::cartridge::Mirroring;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use super::{CartridgeMemory, Mapper, PRG_RAM_END, PRG_RAM_START, PRG_ROM_START};
+
+pub struct NRom {
+    memory: CartridgeMemory,
+    mirroring: Mirroring,
+}
+
+impl NRom {
+    pub fn new(memory: CartridgeMemory,mirroring: Mirroring) -> Self {
+        NRom {
+            memory,
+            mirroring,
+        }
+    }
+}
+
+impl Snapshot for NRom {
+    fn save_state(&self,state: &mut StateWriter) {
+        self.memory.save_state(state);
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        self.memory.load_state(state)
+    }
+}
+
+impl Mapper for NRom {
+    fn cpu_read(&mut self,addr: u16) -> u8 {
+        match addr {
+            PRG_RAM_START ..= PRG_RAM_END => self.memory.read_prg_ram(addr),
+            PRG_ROM_START ..= 0xFFFF => self.memory.read_prg(0x8000,0,(addr - PRG_ROM_START) as usize),
+            _ => 0,
+        }
+    }
+    fn cpu_write(&mut self,addr: u16,data: u8) {
+        if let PRG_RAM_START ..= PRG_RAM_END = addr {
+            self.memory.write_prg_ram(addr,data);
+        }
+    }
+    fn ppu_read(&mut self,addr: u16) -> u8 {
+        self.memory.read_chr(0x2000,0,addr as usize)
+    }
+    fn ppu_write(&mut self,addr: u16,data: u8) {
+        self.memory.write_chr(0x2000,0,addr as usize,data);
+    }
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
@@ -0,0 +1,85 @@
+//! UxROM (mapper 2): a switchable 16 KiB PRG bank at [0x8000 .. 0xBFFF],
+//! the last 16 KiB bank is fixed at [0xC000 .. 0xFFFF]. CHR is 8 KiB of RAM.
+//! Any write to [0x8000 .. 0xFFFF] selects the bank.
+
+use crate::cartridge::Mirroring;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use super::{CartridgeMemory, Mapper, PRG_RAM_END, PRG_RAM_START, PRG_ROM_START};
+
+pub struct UxRom {
+    memory: CartridgeMemory,
+    mirroring: Mirroring,
+    prg_bank: usize,
+}
+
+impl UxRom {
+    pub fn new(memory: CartridgeMemory,mirroring: Mirroring) -> Self {
+        UxRom {
+            memory,
+            mirroring,
+            prg_bank: 0,
+        }
+    }
+}
+
+impl Snapshot for UxRom {
+    fn save_state(&self,state: &mut StateWriter) {
+        self.memory.save_state(state);
+        state.u32(self.prg_bank as u32);
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        self.memory.load_state(state)?;
+        self.prg_bank = state.u32()? as usize;
+        Ok(())
+    }
+}
+
+impl Mapper for UxRom {
+    fn cpu_read(&mut self,addr: u16) -> u8 {
+        match addr {
+            PRG_RAM_START ..= PRG_RAM_END => self.memory.read_prg_ram(addr),
+            PRG_ROM_START ..= 0xBFFF => self.memory.read_prg(0x4000,self.prg_bank,(addr - PRG_ROM_START) as usize),
+            0xC000 ..= 0xFFFF => {
+                let last = self.memory.prg_banks(0x4000) - 1;
+                self.memory.read_prg(0x4000,last,(addr - 0xC000) as usize)
+            },
+            _ => 0,
+        }
+    }
+    fn cpu_write(&mut self,addr: u16,data: u8) {
+        match addr {
+            PRG_RAM_START ..= PRG_RAM_END => self.memory.write_prg_ram(addr,data),
+            PRG_ROM_START ..= 0xFFFF => self.prg_bank = data as usize,
+            _ => {},
+        }
+    }
+    fn ppu_read(&mut self,addr: u16) -> u8 {
+        self.memory.read_chr(0x2000,0,addr as usize)
+    }
+    fn ppu_write(&mut self,addr: u16,data: u8) {
+        self.memory.write_chr(0x2000,0,addr as usize,data);
+    }
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mapper::create_mapper;
+    use crate::mapper::test::test_cartridge;
+    #[test]
+    fn test_switchable_and_fixed_banks() {
+        let mut mapper = create_mapper(test_cartridge(2,0x20000,0)).unwrap();
+        // every 16 KiB bank n holds 8 KiB pages 2n and 2n+1
+        assert_eq!(mapper.cpu_read(0x8000),0);
+        assert_eq!(mapper.cpu_read(0xC000),14);
+        mapper.cpu_write(0x8000,3);
+        assert_eq!(mapper.cpu_read(0x8000),6);
+        assert_eq!(mapper.cpu_read(0xA000),7);
+        assert_eq!(mapper.cpu_read(0xE000),15);
+        // CHR RAM is writable
+        mapper.ppu_write(0x0123,0x5A);
+        assert_eq!(mapper.ppu_read(0x0123),0x5A);
+    }
+}
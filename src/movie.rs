@@ -0,0 +1,304 @@
+//! FCEUX FM2 input movies, text format only.
+//!
+//! An FM2 file starts with `key value` header lines and continues with one input record per frame:
+//!
+//! |0|R.D...BA|........||
+//!
+//! The first field holds the commands to perform before the frame (1 soft reset, 2 power), the next
+//! fields the gamepads on port 0 and port 1. A gamepad is 8 characters for Right, Left, Down, Up,
+//! Start, Select, B and A, anything but '.' or ' ' means the button is held, which maps one to one
+//! onto the BUTTON_* bits from bit 7 down to bit 0.
+//!
+//! FCEUX refuses movies without the `version`, `emuVersion`, `romFilename`, `romChecksum` and `guid` keys,
+//! `Movie::new` writes all of them. `romChecksum` is the base64 MD5 of the PRG and CHR ROM, a movie is
+//! only played back on the ROM it was recorded on.
+//!
+//! A movie that does not start at power on carries the state it starts from in the `savestate` header,
+//! base64 encoded. FCEUX stores its own state format there, ours is only understood by this emulator.
+//! spec: https://fceux.com/web/FM2.html
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use crate::cartridge::Cartridge;
+
+pub const COMMAND_RESET: u8 = 0b0000_0001;
+pub const COMMAND_POWER: u8 = 0b0000_0010;
+
+// the gamepad characters, for the BUTTON_* bits from bit 7 down to bit 0
+const GAMEPAD: &[u8; 8] = b"RLDUTSBA";
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(std::io::Error),
+    // binary input logs (header `binary 1`) are not supported
+    Binary,
+    // an input record that does not parse, 1 based
+    InvalidRecord { line: usize },
+    // the savestate header is not base64
+    InvalidSaveState,
+    // the romChecksum header names another ROM than the one inserted
+    RomMismatch,
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self,f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::Io(err) => write!(f,"failed to access movie file: {}",err),
+            MovieError::Binary => write!(f,"binary FM2 movies are not supported"),
+            MovieError::InvalidRecord { line } => write!(f,"invalid input record on line {}",line),
+            MovieError::InvalidSaveState => write!(f,"the savestate of the movie is not valid base64"),
+            MovieError::RomMismatch => write!(f,"the movie was recorded on another ROM"),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<std::io::Error> for MovieError {
+    fn from(err: std::io::Error) -> Self {
+        MovieError::Io(err)
+    }
+}
+
+/// Input of one frame.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct MovieFrame {
+    pub commands: u8,
+    pub buttons: [u8; 2],
+}
+
+pub struct Movie {
+    // header lines in file order, without the savestate
+    pub header: Vec<(String,String)>,
+    // state the movie starts from, None for movies that start at power on
+    pub start_state: Option<Vec<u8>>,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Default for Movie {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Movie {
+    /// An empty movie with the header FCEUX expects for two gamepads and a fresh guid.
+    /// `romFilename` and `romChecksum` stay empty until `set_rom` fills them.
+    pub fn new() -> Self {
+        let emu_version = format!("{}",
+            env!("CARGO_PKG_VERSION_MAJOR").parse::<u32>().unwrap_or(0) * 10000
+            + env!("CARGO_PKG_VERSION_MINOR").parse::<u32>().unwrap_or(0) * 100
+            + env!("CARGO_PKG_VERSION_PATCH").parse::<u32>().unwrap_or(0));
+        let guid = new_guid();
+        let header = [
+            ("version","3"),("emuVersion",emu_version.as_str()),("rerecordCount","0"),("palFlag","0"),
+            ("romFilename",""),("romChecksum",""),("guid",guid.as_str()),
+            ("fourscore","0"),("port0","1"),("port1","1"),("port2","0"),
+        ];
+        Movie {
+            header: header.iter().map(|(key,value)| (key.to_string(),value.to_string())).collect(),
+            start_state: None,
+            frames: Vec::new(),
+        }
+    }
+    pub fn parse(text: &str) -> Result<Movie,MovieError> {
+        let mut header = Vec::new();
+        let mut start_state = None;
+        let mut frames = Vec::new();
+        for (i,line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.starts_with('|') {
+                frames.push(parse_record(line).ok_or(MovieError::InvalidRecord { line: i + 1 })?);
+            }else if let Some(value) = line.strip_prefix("savestate ") {
+                let data = value.strip_prefix("base64:").unwrap_or(value);
+                start_state = Some(decode_base64(data).ok_or(MovieError::InvalidSaveState)?);
+            }else if let Some((key,value)) = line.split_once(' ') {
+                header.push((key.to_string(),value.to_string()));
+            }else if !line.is_empty() {
+                header.push((line.to_string(),String::new()));
+            }
+        }
+        let movie = Movie { header, start_state, frames };
+        if movie.header("binary") == Some("1") {
+            return Err(MovieError::Binary);
+        }
+        Ok(movie)
+    }
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Movie,MovieError> {
+        Movie::parse(&std::fs::read_to_string(path)?)
+    }
+    pub fn header(&self,key: &str) -> Option<&str> {
+        self.header.iter().find(|(k,_)| k == key).map(|(_,value)| value.as_str())
+    }
+    /// Replaces the value of `key`, or appends it when the header does not have it yet.
+    pub fn set_header(&mut self,key: &str,value: &str) {
+        match self.header.iter_mut().find(|(k,_)| k == key) {
+            Some((_,old)) => *old = value.to_string(),
+            None => self.header.push((key.to_string(),value.to_string())),
+        }
+    }
+    /// Ties the movie to the ROM of `cartridge` through the `romFilename` and `romChecksum` headers.
+    pub fn set_rom(&mut self,cartridge: &Cartridge) {
+        self.set_header("romFilename",&cartridge.name);
+        self.set_header("romChecksum",&format!("base64:{}",encode_base64(&cartridge.md5())));
+    }
+    /// True when the movie was recorded on the ROM of `cartridge`, or does not say which ROM it was recorded on.
+    pub fn matches_rom(&self,cartridge: &Cartridge) -> bool {
+        match self.header("romChecksum").filter(|checksum| !checksum.is_empty()) {
+            Some(checksum) => {
+                let digest = checksum.strip_prefix("base64:").and_then(decode_base64);
+                digest.as_deref() == Some(&cartridge.md5()[..])
+            },
+            None => true,
+        }
+    }
+    /// Writes the movie in the text FM2 format.
+    pub fn to_fm2(&self) -> String {
+        let mut text = String::new();
+        for (key,value) in self.header.iter() {
+            text += &format!("{} {}\n",key,value);
+        }
+        if let Some(state) = &self.start_state {
+            text += &format!("savestate base64:{}\n",encode_base64(state));
+        }
+        for frame in self.frames.iter() {
+            text += &format!("|{}|{}|{}||\n",frame.commands,format_gamepad(frame.buttons[0]),format_gamepad(frame.buttons[1]));
+        }
+        text
+    }
+    pub fn save<P: AsRef<Path>>(&self,path: P) -> Result<(),MovieError> {
+        std::fs::write(path,self.to_fm2())?;
+        Ok(())
+    }
+}
+
+// the guid only tells movies apart, it never reaches the machine, so it may be random;
+// std seeds RandomState from the OS, which spares a dependency for 16 random bytes
+fn new_guid() -> String {
+    let random = || RandomState::new().build_hasher().finish();
+    let bytes = [random().to_le_bytes(),random().to_le_bytes()].concat();
+    let hex: String = bytes.iter().map(|byte| format!("{:02X}",byte)).collect();
+    format!("{}-{}-{}-{}-{}",&hex[..8],&hex[8..12],&hex[12..16],&hex[16..20],&hex[20..])
+}
+
+fn parse_record(line: &str) -> Option<MovieFrame> {
+    let mut fields = line.split('|').skip(1);
+    let commands = fields.next()?.trim().parse().ok()?;
+    let mut buttons = [0; 2];
+    for port in buttons.iter_mut() {
+        *port = parse_gamepad(fields.next().unwrap_or(""));
+    }
+    Some(MovieFrame { commands, buttons })
+}
+
+fn format_gamepad(buttons: u8) -> String {
+    GAMEPAD.iter().enumerate()
+        .map(|(i,c)| if buttons & (0b1000_0000 >> i) != 0 { *c as char } else { '.' })
+        .collect()
+}
+
+// fields of other devices (zapper coordinates, empty ports) simply read as no buttons held
+fn parse_gamepad(field: &str) -> u8 {
+    if field.len() != 8 {
+        return 0;
+    }
+    field.chars().enumerate()
+        .filter(|(_,c)| *c != '.' && *c != ' ')
+        .fold(0,|buttons,(i,_)| buttons | 0b1000_0000 >> i)
+}
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32,|bits,(i,byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            text.push(if i <= chunk.len() { BASE64[(bits >> (18 - 6 * i) & 0x3F) as usize] as char } else { '=' });
+        }
+    }
+    text
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim().trim_end_matches('=');
+    let mut data = Vec::new();
+    let (mut bits,mut count) = (0u32,0);
+    for c in text.bytes() {
+        bits = bits << 6 | BASE64.iter().position(|b| *b == c)? as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+        }
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::*;
+    #[test]
+    fn test_parse_fm2() {
+        let text = "version 3\nemuVersion 22020\nromFilename game\nport0 1\nport1 1\nport2 0\n\
+            |1|........|........||\n|0|R......A|....TS..||\r\n|2|...U....|||\n";
+        let movie = Movie::parse(text).unwrap();
+        assert_eq!(movie.header("romFilename"),Some("game"));
+        assert_eq!(movie.header("missing"),None);
+        assert_eq!(movie.frames,vec![
+            MovieFrame { commands: COMMAND_RESET, buttons: [0,0] },
+            MovieFrame { commands: 0, buttons: [BUTTON_RIGHT | BUTTON_A,BUTTON_START | BUTTON_SELECT] },
+            MovieFrame { commands: COMMAND_POWER, buttons: [BUTTON_UP,0] },
+        ]);
+    }
+    #[test]
+    fn test_invalid_movies() {
+        assert!(matches!(Movie::parse("version 3\n|x|........|\n"),Err(MovieError::InvalidRecord { line: 2 })));
+        assert!(matches!(Movie::parse("version 3\nbinary 1\n"),Err(MovieError::Binary)));
+    }
+    #[test]
+    fn test_write_fm2() {
+        let mut movie = Movie::new();
+        movie.set_header("romFilename","game");
+        movie.start_state = Some(vec![0x00, 0xFF, 0x10, 0x20]);
+        movie.frames = vec![
+            MovieFrame { commands: COMMAND_POWER, buttons: [0,0] },
+            MovieFrame { commands: 0, buttons: [BUTTON_RIGHT | BUTTON_A,BUTTON_START | BUTTON_UP] },
+        ];
+        let text = movie.to_fm2();
+        assert!(text.starts_with("version 3\nemuVersion "));
+        assert!(text.contains("\nromFilename game\n"));
+        assert!(text.contains("port2 0\nsavestate base64:AP8QIA==\n|2|........|........||\n|0|R......A|...UT...||\n"));
+        let parsed = Movie::parse(&text).unwrap();
+        assert_eq!((parsed.header,parsed.start_state,parsed.frames),(movie.header,movie.start_state,movie.frames));
+        for len in 0..6 {
+            let data: Vec<u8> = (0..len).map(|i| i * 37 + 1).collect();
+            assert_eq!(decode_base64(&encode_base64(&data)),Some(data));
+        }
+        assert!(matches!(Movie::parse("savestate base64:#!\n"),Err(MovieError::InvalidSaveState)));
+    }
+    #[test]
+    fn test_required_header_and_rom_checksum() {
+        let movie = Movie::new();
+        for key in ["version","emuVersion","romFilename","romChecksum","guid"] {
+            assert!(movie.header(key).is_some(),"{} is missing",key);
+        }
+        let guid = movie.header("guid").unwrap();
+        assert_eq!(guid.split('-').map(str::len).collect::<Vec<_>>(),vec![8,4,4,4,12]);
+        assert_ne!(Movie::new().header("guid"),Some(guid));
+        let cartridge = crate::mapper::test::test_cartridge(0,0x4000,0x2000);
+        let mut other = cartridge.clone();
+        other.prg_rom[0] ^= 0xFF;
+        // a movie that does not name its ROM plays on any
+        assert!(movie.matches_rom(&other));
+        let mut movie = Movie::parse(&movie.to_fm2()).unwrap();
+        movie.set_rom(&cartridge);
+        let movie = Movie::parse(&movie.to_fm2()).unwrap();
+        assert_eq!(movie.header("romFilename"),Some("test"));
+        assert!(movie.header("romChecksum").unwrap().starts_with("base64:"));
+        assert!(movie.matches_rom(&cartridge));
+        assert!(!movie.matches_rom(&other));
+    }
+}
@@ -1,29 +1,44 @@
+// OpCodesMap keeps its historical name, lazy_static does not forward allow attributes to the generated static
+#![allow(non_upper_case_globals)]
+
 use crate::addressing_modes::AddrMode;
-use std::collections::HashMap;
+use crate::cpu::CpuVariant;
+use std::collections::BTreeMap;
 
+#[derive(Clone)]
 pub struct OpCode {
     pub addressing_mode: AddrMode,
     pub assembler: &'static str,
     pub opc: u8,
     pub bytes: u8,
     pub cycles: u8,
+    // false for the undocumented NMOS opcodes, traces mark them with a *
+    pub official: bool,
 }
 
 impl OpCode {
     fn new(mode: AddrMode,assembler: &'static str,opc: u8,bytes: u8,cycles: u8) -> Self {
         OpCode {
             addressing_mode: mode,
-            assembler: assembler,
-            opc: opc,
-            bytes: bytes,
-            cycles: cycles,
+            assembler,
+            opc,
+            bytes,
+            cycles,
+            official: true,
+        }
+    }
+    fn unofficial(mode: AddrMode,assembler: &'static str,opc: u8,bytes: u8,cycles: u8) -> Self {
+        OpCode {
+            official: false,
+            ..OpCode::new(mode,assembler,opc,bytes,cycles)
         }
     }
 }
 
+// ordered maps, so walking a table visits the opcodes in the same order on every run
 lazy_static! {
-    pub static ref OpCodesMap: HashMap<u8,OpCode> = {
-        let mut map = HashMap::new();
+    pub static ref OpCodesMap: BTreeMap<u8,OpCode> = {
+        let mut map = BTreeMap::new();
         // ADC
         map.insert(0x69,OpCode::new(AddrMode::Immediate, "ADC", 0x69,2,2));
         map.insert(0x65,OpCode::new(AddrMode::ZeroPage, "ADC", 0x65,2,3));
@@ -64,7 +79,7 @@ lazy_static! {
         // BPL
         map.insert(0x10,OpCode::new(AddrMode::Relative,"BPL",0x10,2,2));
         // BRK
-        map.insert(0x00,OpCode::new(AddrMode::Implied,"BRK",0x00,2,2));
+        map.insert(0x00,OpCode::new(AddrMode::Implied,"BRK",0x00,1,7));
         // BVC
         map.insert(0x50,OpCode::new(AddrMode::Relative,"BVC",0x50,2,2));
         // BVS
@@ -136,27 +151,27 @@ lazy_static! {
         map.insert(0xA1,OpCode::new(AddrMode::IndirectX,"LDA",0xA1,2,6));
         map.insert(0xB1,OpCode::new(AddrMode::IndirectY,"LDA",0xB1,2,5));
         // LDX
-        map.insert(0xA2,OpCode::new(AddrMode::Implied,"LDX",0xA2,2,2));
+        map.insert(0xA2,OpCode::new(AddrMode::Immediate,"LDX",0xA2,2,2));
         map.insert(0xA6,OpCode::new(AddrMode::ZeroPage,"LDX",0xA6,2,3));
         map.insert(0xB6,OpCode::new(AddrMode::ZeroPageY,"LDX",0xB6,2,4));
         map.insert(0xAE,OpCode::new(AddrMode::Absolute,"LDX",0xAE,3,4));
         map.insert(0xBE,OpCode::new(AddrMode::AbsoluteY,"LDX",0xBE,3,4));
         // LDY
-        map.insert(0xA0,OpCode::new(AddrMode::Implied,"LDY",0xA2,2,2));
-        map.insert(0xA4,OpCode::new(AddrMode::ZeroPage,"LDY",0xA6,2,3));
-        map.insert(0xB4,OpCode::new(AddrMode::ZeroPageX,"LDY",0xB6,2,4));
-        map.insert(0xAC,OpCode::new(AddrMode::Absolute,"LDY",0xAE,3,4));
-        map.insert(0xBC,OpCode::new(AddrMode::AbsoluteX,"LDY",0xBE,3,4));
+        map.insert(0xA0,OpCode::new(AddrMode::Immediate,"LDY",0xA0,2,2));
+        map.insert(0xA4,OpCode::new(AddrMode::ZeroPage,"LDY",0xA4,2,3));
+        map.insert(0xB4,OpCode::new(AddrMode::ZeroPageX,"LDY",0xB4,2,4));
+        map.insert(0xAC,OpCode::new(AddrMode::Absolute,"LDY",0xAC,3,4));
+        map.insert(0xBC,OpCode::new(AddrMode::AbsoluteX,"LDY",0xBC,3,4));
         // LSR
         map.insert(0x4A,OpCode::new(AddrMode::Accumulator,"LSR",0x4A,1,2));
         map.insert(0x46,OpCode::new(AddrMode::ZeroPage,"LSR",0x46,2,5));
-        map.insert(0x56,OpCode::new(AddrMode::ZeroPageX,"LSR",0x56,1,6));
-        map.insert(0x4E,OpCode::new(AddrMode::Absolute,"LSR",0x4E,3,2));
+        map.insert(0x56,OpCode::new(AddrMode::ZeroPageX,"LSR",0x56,2,6));
+        map.insert(0x4E,OpCode::new(AddrMode::Absolute,"LSR",0x4E,3,6));
         map.insert(0x5E,OpCode::new(AddrMode::AbsoluteX,"LSR",0x5E,3,7));
         // NOP
         map.insert(0xEA,OpCode::new(AddrMode::Implied,"NOP",0xEA,1,2));
         // ORA
-        map.insert(0x09,OpCode::new(AddrMode::Immediate,"ORA",0x09,2,3));
+        map.insert(0x09,OpCode::new(AddrMode::Immediate,"ORA",0x09,2,2));
         map.insert(0x05,OpCode::new(AddrMode::ZeroPage,"ORA",0x05,2,3));
         map.insert(0x15,OpCode::new(AddrMode::ZeroPageX,"ORA",0x15,2,4));
         map.insert(0x0D,OpCode::new(AddrMode::Absolute,"ORA",0x0D,3,4));
@@ -169,9 +184,9 @@ lazy_static! {
         // PHP
         map.insert(0x08,OpCode::new(AddrMode::Implied,"PHP",0x08,1,3));
         // PLA
-        map.insert(0x68,OpCode::new(AddrMode::Implied,"PHP",0x68,1,4));
+        map.insert(0x68,OpCode::new(AddrMode::Implied,"PLA",0x68,1,4));
         // PLP
-        map.insert(0x28,OpCode::new(AddrMode::Implied,"PHP",0x28,1,4));
+        map.insert(0x28,OpCode::new(AddrMode::Implied,"PLP",0x28,1,4));
         // ROL
         map.insert(0x2A,OpCode::new(AddrMode::Accumulator,"ROL",0x2A,1,2));
         map.insert(0x26,OpCode::new(AddrMode::ZeroPage,"ROL",0x26,2,5));
@@ -206,8 +221,8 @@ lazy_static! {
         // STA
         map.insert(0x85,OpCode::new(AddrMode::ZeroPage,"STA",0x85,2,3));
         map.insert(0x95,OpCode::new(AddrMode::ZeroPageX,"STA",0x95,2,4));
-        map.insert(0x80,OpCode::new(AddrMode::Absolute,"STA",0x80,3,4));
-        map.insert(0x90,OpCode::new(AddrMode::AbsoluteX,"STA",0x90,3,5));
+        map.insert(0x8D,OpCode::new(AddrMode::Absolute,"STA",0x8D,3,4));
+        map.insert(0x9D,OpCode::new(AddrMode::AbsoluteX,"STA",0x9D,3,5));
         map.insert(0x99,OpCode::new(AddrMode::AbsoluteY,"STA",0x99,3,5));
         map.insert(0x81,OpCode::new(AddrMode::IndirectX,"STA",0x81,2,6));
         map.insert(0x91,OpCode::new(AddrMode::IndirectY,"STA",0x91,2,6));
@@ -225,13 +240,206 @@ lazy_static! {
         map.insert(0xA8,OpCode::new(AddrMode::Implied,"TAY",0xA8,1,2));
         // TSX
         map.insert(0xBA,OpCode::new(AddrMode::Implied,"TSX",0xBA,1,2));
-        // TSA
-        map.insert(0x8A,OpCode::new(AddrMode::Implied,"TSA",0x8A,1,2));
+        // TXA
+        map.insert(0x8A,OpCode::new(AddrMode::Implied,"TXA",0x8A,1,2));
         // TXS
         map.insert(0x9A,OpCode::new(AddrMode::Implied,"TXS",0x9A,1,2));
         // TYA
         map.insert(0x98,OpCode::new(AddrMode::Implied,"TYA",0x98,1,2));
+        // unofficial opcodes, NMOS 6502 behavior: https://www.nesdev.org/wiki/Programming_with_unofficial_opcodes
+        // NOP: the extra opcodes read their operand and throw it away
+        map.insert(0x1A,OpCode::unofficial(AddrMode::Implied,"NOP",0x1A,1,2));
+        map.insert(0x3A,OpCode::unofficial(AddrMode::Implied,"NOP",0x3A,1,2));
+        map.insert(0x5A,OpCode::unofficial(AddrMode::Implied,"NOP",0x5A,1,2));
+        map.insert(0x7A,OpCode::unofficial(AddrMode::Implied,"NOP",0x7A,1,2));
+        map.insert(0xDA,OpCode::unofficial(AddrMode::Implied,"NOP",0xDA,1,2));
+        map.insert(0xFA,OpCode::unofficial(AddrMode::Implied,"NOP",0xFA,1,2));
+        map.insert(0x80,OpCode::unofficial(AddrMode::Immediate,"NOP",0x80,2,2));
+        map.insert(0x82,OpCode::unofficial(AddrMode::Immediate,"NOP",0x82,2,2));
+        map.insert(0x89,OpCode::unofficial(AddrMode::Immediate,"NOP",0x89,2,2));
+        map.insert(0xC2,OpCode::unofficial(AddrMode::Immediate,"NOP",0xC2,2,2));
+        map.insert(0xE2,OpCode::unofficial(AddrMode::Immediate,"NOP",0xE2,2,2));
+        map.insert(0x04,OpCode::unofficial(AddrMode::ZeroPage,"NOP",0x04,2,3));
+        map.insert(0x44,OpCode::unofficial(AddrMode::ZeroPage,"NOP",0x44,2,3));
+        map.insert(0x64,OpCode::unofficial(AddrMode::ZeroPage,"NOP",0x64,2,3));
+        map.insert(0x14,OpCode::unofficial(AddrMode::ZeroPageX,"NOP",0x14,2,4));
+        map.insert(0x34,OpCode::unofficial(AddrMode::ZeroPageX,"NOP",0x34,2,4));
+        map.insert(0x54,OpCode::unofficial(AddrMode::ZeroPageX,"NOP",0x54,2,4));
+        map.insert(0x74,OpCode::unofficial(AddrMode::ZeroPageX,"NOP",0x74,2,4));
+        map.insert(0xD4,OpCode::unofficial(AddrMode::ZeroPageX,"NOP",0xD4,2,4));
+        map.insert(0xF4,OpCode::unofficial(AddrMode::ZeroPageX,"NOP",0xF4,2,4));
+        map.insert(0x0C,OpCode::unofficial(AddrMode::Absolute,"NOP",0x0C,3,4));
+        map.insert(0x1C,OpCode::unofficial(AddrMode::AbsoluteX,"NOP",0x1C,3,4));
+        map.insert(0x3C,OpCode::unofficial(AddrMode::AbsoluteX,"NOP",0x3C,3,4));
+        map.insert(0x5C,OpCode::unofficial(AddrMode::AbsoluteX,"NOP",0x5C,3,4));
+        map.insert(0x7C,OpCode::unofficial(AddrMode::AbsoluteX,"NOP",0x7C,3,4));
+        map.insert(0xDC,OpCode::unofficial(AddrMode::AbsoluteX,"NOP",0xDC,3,4));
+        map.insert(0xFC,OpCode::unofficial(AddrMode::AbsoluteX,"NOP",0xFC,3,4));
+        // LAX: LDA and LDX at once, the immediate form is unstable (LXA)
+        map.insert(0xAB,OpCode::unofficial(AddrMode::Immediate,"LAX",0xAB,2,2));
+        map.insert(0xA7,OpCode::unofficial(AddrMode::ZeroPage,"LAX",0xA7,2,3));
+        map.insert(0xB7,OpCode::unofficial(AddrMode::ZeroPageY,"LAX",0xB7,2,4));
+        map.insert(0xAF,OpCode::unofficial(AddrMode::Absolute,"LAX",0xAF,3,4));
+        map.insert(0xBF,OpCode::unofficial(AddrMode::AbsoluteY,"LAX",0xBF,3,4));
+        map.insert(0xA3,OpCode::unofficial(AddrMode::IndirectX,"LAX",0xA3,2,6));
+        map.insert(0xB3,OpCode::unofficial(AddrMode::IndirectY,"LAX",0xB3,2,5));
+        // SAX: store A & X
+        map.insert(0x87,OpCode::unofficial(AddrMode::ZeroPage,"SAX",0x87,2,3));
+        map.insert(0x97,OpCode::unofficial(AddrMode::ZeroPageY,"SAX",0x97,2,4));
+        map.insert(0x8F,OpCode::unofficial(AddrMode::Absolute,"SAX",0x8F,3,4));
+        map.insert(0x83,OpCode::unofficial(AddrMode::IndirectX,"SAX",0x83,2,6));
+        // SBC: same as 0xE9
+        map.insert(0xEB,OpCode::unofficial(AddrMode::Immediate,"SBC",0xEB,2,2));
+        // DCP: DEC then CMP
+        map.insert(0xC7,OpCode::unofficial(AddrMode::ZeroPage,"DCP",0xC7,2,5));
+        map.insert(0xD7,OpCode::unofficial(AddrMode::ZeroPageX,"DCP",0xD7,2,6));
+        map.insert(0xCF,OpCode::unofficial(AddrMode::Absolute,"DCP",0xCF,3,6));
+        map.insert(0xDF,OpCode::unofficial(AddrMode::AbsoluteX,"DCP",0xDF,3,7));
+        map.insert(0xDB,OpCode::unofficial(AddrMode::AbsoluteY,"DCP",0xDB,3,7));
+        map.insert(0xC3,OpCode::unofficial(AddrMode::IndirectX,"DCP",0xC3,2,8));
+        map.insert(0xD3,OpCode::unofficial(AddrMode::IndirectY,"DCP",0xD3,2,8));
+        // ISC: INC then SBC, traces call it ISB like nestest does
+        map.insert(0xE7,OpCode::unofficial(AddrMode::ZeroPage,"ISB",0xE7,2,5));
+        map.insert(0xF7,OpCode::unofficial(AddrMode::ZeroPageX,"ISB",0xF7,2,6));
+        map.insert(0xEF,OpCode::unofficial(AddrMode::Absolute,"ISB",0xEF,3,6));
+        map.insert(0xFF,OpCode::unofficial(AddrMode::AbsoluteX,"ISB",0xFF,3,7));
+        map.insert(0xFB,OpCode::unofficial(AddrMode::AbsoluteY,"ISB",0xFB,3,7));
+        map.insert(0xE3,OpCode::unofficial(AddrMode::IndirectX,"ISB",0xE3,2,8));
+        map.insert(0xF3,OpCode::unofficial(AddrMode::IndirectY,"ISB",0xF3,2,8));
+        // SLO: ASL then ORA
+        map.insert(0x07,OpCode::unofficial(AddrMode::ZeroPage,"SLO",0x07,2,5));
+        map.insert(0x17,OpCode::unofficial(AddrMode::ZeroPageX,"SLO",0x17,2,6));
+        map.insert(0x0F,OpCode::unofficial(AddrMode::Absolute,"SLO",0x0F,3,6));
+        map.insert(0x1F,OpCode::unofficial(AddrMode::AbsoluteX,"SLO",0x1F,3,7));
+        map.insert(0x1B,OpCode::unofficial(AddrMode::AbsoluteY,"SLO",0x1B,3,7));
+        map.insert(0x03,OpCode::unofficial(AddrMode::IndirectX,"SLO",0x03,2,8));
+        map.insert(0x13,OpCode::unofficial(AddrMode::IndirectY,"SLO",0x13,2,8));
+        // RLA: ROL then AND
+        map.insert(0x27,OpCode::unofficial(AddrMode::ZeroPage,"RLA",0x27,2,5));
+        map.insert(0x37,OpCode::unofficial(AddrMode::ZeroPageX,"RLA",0x37,2,6));
+        map.insert(0x2F,OpCode::unofficial(AddrMode::Absolute,"RLA",0x2F,3,6));
+        map.insert(0x3F,OpCode::unofficial(AddrMode::AbsoluteX,"RLA",0x3F,3,7));
+        map.insert(0x3B,OpCode::unofficial(AddrMode::AbsoluteY,"RLA",0x3B,3,7));
+        map.insert(0x23,OpCode::unofficial(AddrMode::IndirectX,"RLA",0x23,2,8));
+        map.insert(0x33,OpCode::unofficial(AddrMode::IndirectY,"RLA",0x33,2,8));
+        // SRE: LSR then EOR
+        map.insert(0x47,OpCode::unofficial(AddrMode::ZeroPage,"SRE",0x47,2,5));
+        map.insert(0x57,OpCode::unofficial(AddrMode::ZeroPageX,"SRE",0x57,2,6));
+        map.insert(0x4F,OpCode::unofficial(AddrMode::Absolute,"SRE",0x4F,3,6));
+        map.insert(0x5F,OpCode::unofficial(AddrMode::AbsoluteX,"SRE",0x5F,3,7));
+        map.insert(0x5B,OpCode::unofficial(AddrMode::AbsoluteY,"SRE",0x5B,3,7));
+        map.insert(0x43,OpCode::unofficial(AddrMode::IndirectX,"SRE",0x43,2,8));
+        map.insert(0x53,OpCode::unofficial(AddrMode::IndirectY,"SRE",0x53,2,8));
+        // RRA: ROR then ADC
+        map.insert(0x67,OpCode::unofficial(AddrMode::ZeroPage,"RRA",0x67,2,5));
+        map.insert(0x77,OpCode::unofficial(AddrMode::ZeroPageX,"RRA",0x77,2,6));
+        map.insert(0x6F,OpCode::unofficial(AddrMode::Absolute,"RRA",0x6F,3,6));
+        map.insert(0x7F,OpCode::unofficial(AddrMode::AbsoluteX,"RRA",0x7F,3,7));
+        map.insert(0x7B,OpCode::unofficial(AddrMode::AbsoluteY,"RRA",0x7B,3,7));
+        map.insert(0x63,OpCode::unofficial(AddrMode::IndirectX,"RRA",0x63,2,8));
+        map.insert(0x73,OpCode::unofficial(AddrMode::IndirectY,"RRA",0x73,2,8));
+        // ANC: AND, then C takes the value of N
+        map.insert(0x0B,OpCode::unofficial(AddrMode::Immediate,"ANC",0x0B,2,2));
+        map.insert(0x2B,OpCode::unofficial(AddrMode::Immediate,"ANC",0x2B,2,2));
+        // ALR: AND then LSR A
+        map.insert(0x4B,OpCode::unofficial(AddrMode::Immediate,"ALR",0x4B,2,2));
+        // ARR: AND then ROR A, with C and V taken from bits 6 and 5
+        map.insert(0x6B,OpCode::unofficial(AddrMode::Immediate,"ARR",0x6B,2,2));
+        // AXS: X = (A & X) - operand, without borrow
+        map.insert(0xCB,OpCode::unofficial(AddrMode::Immediate,"AXS",0xCB,2,2));
+        // XAA: unstable, A = (A | magic) & X & operand
+        map.insert(0x8B,OpCode::unofficial(AddrMode::Immediate,"XAA",0x8B,2,2));
+        // SHA: unstable, store A & X & (high byte of address + 1)
+        map.insert(0x9F,OpCode::unofficial(AddrMode::AbsoluteY,"SHA",0x9F,3,5));
+        map.insert(0x93,OpCode::unofficial(AddrMode::IndirectY,"SHA",0x93,2,6));
+        // SHX: unstable, store X & (high byte of address + 1)
+        map.insert(0x9E,OpCode::unofficial(AddrMode::AbsoluteY,"SHX",0x9E,3,5));
+        // SHY: unstable, store Y & (high byte of address + 1)
+        map.insert(0x9C,OpCode::unofficial(AddrMode::AbsoluteX,"SHY",0x9C,3,5));
+        // TAS: unstable, SP = A & X, then store SP & (high byte of address + 1)
+        map.insert(0x9B,OpCode::unofficial(AddrMode::AbsoluteY,"TAS",0x9B,3,5));
+        // LAS: A, X and SP = memory & SP
+        map.insert(0xBB,OpCode::unofficial(AddrMode::AbsoluteY,"LAS",0xBB,3,4));
+        // JAM: locks the CPU up until reset
+        map.insert(0x02,OpCode::unofficial(AddrMode::Implied,"JAM",0x02,1,2));
+        map.insert(0x12,OpCode::unofficial(AddrMode::Implied,"JAM",0x12,1,2));
+        map.insert(0x22,OpCode::unofficial(AddrMode::Implied,"JAM",0x22,1,2));
+        map.insert(0x32,OpCode::unofficial(AddrMode::Implied,"JAM",0x32,1,2));
+        map.insert(0x42,OpCode::unofficial(AddrMode::Implied,"JAM",0x42,1,2));
+        map.insert(0x52,OpCode::unofficial(AddrMode::Implied,"JAM",0x52,1,2));
+        map.insert(0x62,OpCode::unofficial(AddrMode::Implied,"JAM",0x62,1,2));
+        map.insert(0x72,OpCode::unofficial(AddrMode::Implied,"JAM",0x72,1,2));
+        map.insert(0x92,OpCode::unofficial(AddrMode::Implied,"JAM",0x92,1,2));
+        map.insert(0xB2,OpCode::unofficial(AddrMode::Implied,"JAM",0xB2,1,2));
+        map.insert(0xD2,OpCode::unofficial(AddrMode::Implied,"JAM",0xD2,1,2));
+        map.insert(0xF2,OpCode::unofficial(AddrMode::Implied,"JAM",0xF2,1,2));
 
         map
     };
 }
+
+lazy_static! {
+    // the 65C02 keeps every documented NMOS opcode, adds new ones in the unused slots
+    // and turns the remaining ones into NOPs of various lengths
+    pub static ref Cmos65C02OpCodesMap: BTreeMap<u8,OpCode> = {
+        let mut map: BTreeMap<u8,OpCode> = OpCodesMap.iter()
+            .filter(|(_,ops_code)| ops_code.official)
+            .map(|(opc,ops_code)| (*opc,ops_code.clone()))
+            .collect();
+        // JMP ($LLHH) no longer wraps inside the page and takes a cycle more for it
+        map.insert(0x6C,OpCode::new(AddrMode::Indirect,"JMP",0x6C,3,6));
+        map.insert(0x7C,OpCode::new(AddrMode::AbsoluteIndirectX,"JMP",0x7C,3,6));
+        // BRA
+        map.insert(0x80,OpCode::new(AddrMode::Relative,"BRA",0x80,2,2));
+        // PHX PLX PHY PLY
+        map.insert(0xDA,OpCode::new(AddrMode::Implied,"PHX",0xDA,1,3));
+        map.insert(0xFA,OpCode::new(AddrMode::Implied,"PLX",0xFA,1,4));
+        map.insert(0x5A,OpCode::new(AddrMode::Implied,"PHY",0x5A,1,3));
+        map.insert(0x7A,OpCode::new(AddrMode::Implied,"PLY",0x7A,1,4));
+        // STZ
+        map.insert(0x64,OpCode::new(AddrMode::ZeroPage,"STZ",0x64,2,3));
+        map.insert(0x74,OpCode::new(AddrMode::ZeroPageX,"STZ",0x74,2,4));
+        map.insert(0x9C,OpCode::new(AddrMode::Absolute,"STZ",0x9C,3,4));
+        map.insert(0x9E,OpCode::new(AddrMode::AbsoluteX,"STZ",0x9E,3,5));
+        // TRB TSB
+        map.insert(0x14,OpCode::new(AddrMode::ZeroPage,"TRB",0x14,2,5));
+        map.insert(0x1C,OpCode::new(AddrMode::Absolute,"TRB",0x1C,3,6));
+        map.insert(0x04,OpCode::new(AddrMode::ZeroPage,"TSB",0x04,2,5));
+        map.insert(0x0C,OpCode::new(AddrMode::Absolute,"TSB",0x0C,3,6));
+        // INC A, DEC A
+        map.insert(0x1A,OpCode::new(AddrMode::Accumulator,"INC",0x1A,1,2));
+        map.insert(0x3A,OpCode::new(AddrMode::Accumulator,"DEC",0x3A,1,2));
+        // BIT
+        map.insert(0x89,OpCode::new(AddrMode::Immediate,"BIT",0x89,2,2));
+        map.insert(0x34,OpCode::new(AddrMode::ZeroPageX,"BIT",0x34,2,4));
+        map.insert(0x3C,OpCode::new(AddrMode::AbsoluteX,"BIT",0x3C,3,4));
+        // ($LL) versions of the accumulator instructions
+        for (opc,assembler) in [(0x12,"ORA"),(0x32,"AND"),(0x52,"EOR"),(0x72,"ADC"),(0x92,"STA"),(0xB2,"LDA"),(0xD2,"CMP"),(0xF2,"SBC")] {
+            map.insert(opc,OpCode::new(AddrMode::ZeroPageIndirect,assembler,opc,2,5));
+        }
+        // undefined opcodes are NOPs
+        for opc in [0x02,0x22,0x42,0x62,0x82,0xC2,0xE2] {
+            map.insert(opc,OpCode::unofficial(AddrMode::Immediate,"NOP",opc,2,2));
+        }
+        map.insert(0x44,OpCode::unofficial(AddrMode::ZeroPage,"NOP",0x44,2,3));
+        for opc in [0x54,0xD4,0xF4] {
+            map.insert(opc,OpCode::unofficial(AddrMode::ZeroPageX,"NOP",opc,2,4));
+        }
+        map.insert(0x5C,OpCode::unofficial(AddrMode::Absolute,"NOP",0x5C,3,8));
+        for opc in [0xDC,0xFC] {
+            map.insert(opc,OpCode::unofficial(AddrMode::Absolute,"NOP",opc,3,4));
+        }
+        // columns 3, 7, B and F, the Rockwell bit instructions are not part of the base 65C02
+        for opc in 0..=0xFF {
+            map.entry(opc).or_insert_with(|| OpCode::unofficial(AddrMode::Implied,"NOP",opc,1,1));
+        }
+        map
+    };
+}
+
+pub fn opcode_table(variant: CpuVariant) -> &'static BTreeMap<u8,OpCode> {
+    match variant {
+        CpuVariant::Cmos65C02 => &Cmos65C02OpCodesMap,
+        _ => &OpCodesMap,
+    }
+}
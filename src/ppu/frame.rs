@@ -0,0 +1,125 @@
+//! A rendered picture, 256x240 pixels stored row by row as RGB triplets.
+//!
+//! `to_png` encodes it as an 8 bit RGB PNG. The image data is wrapped in uncompressed deflate blocks,
+//! which keeps the encoder tiny at the cost of ~180 KiB per screenshot.
+//! spec: https://www.w3.org/TR/png/
+
+use crate::hash::crc32;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+pub struct Frame {
+    pub data: Vec<u8>,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Frame {
+    pub const WIDTH: usize = 256;
+    pub const HEIGHT: usize = 240;
+
+    pub fn new() -> Self {
+        Frame {
+            data: vec![0; Frame::WIDTH * Frame::HEIGHT * 3],
+        }
+    }
+    pub fn set_pixel(&mut self,x: usize,y: usize,rgb: (u8,u8,u8)) {
+        let base = (y * Frame::WIDTH + x) * 3;
+        if base + 2 < self.data.len() {
+            self.data[base] = rgb.0;
+            self.data[base + 1] = rgb.1;
+            self.data[base + 2] = rgb.2;
+        }
+    }
+    pub fn get_pixel(&self,x: usize,y: usize) -> (u8,u8,u8) {
+        let base = (y * Frame::WIDTH + x) * 3;
+        (self.data[base],self.data[base + 1],self.data[base + 2])
+    }
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&(Frame::WIDTH as u32).to_be_bytes());
+        header.extend_from_slice(&(Frame::HEIGHT as u32).to_be_bytes());
+        // 8 bits per channel, truecolor, deflate, adaptive filtering, no interlace
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        // every row starts with its filter type, 0 is none
+        let mut raw = Vec::with_capacity(Frame::HEIGHT * (Frame::WIDTH * 3 + 1));
+        for row in self.data.chunks(Frame::WIDTH * 3) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+        let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+        write_chunk(&mut png,b"IHDR",&header);
+        write_chunk(&mut png,b"IDAT",&zlib_stored(&raw));
+        write_chunk(&mut png,b"IEND",&[]);
+        png
+    }
+}
+
+fn write_chunk(png: &mut Vec<u8>,kind: &[u8; 4],data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// zlib stream made of stored deflate blocks, at most 65535 bytes each
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    while let Some(block) = blocks.next() {
+        out.push(if blocks.peek().is_none() { 1 } else { 0 });
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a,mut b) = (1u32,0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+// the picture is saved too, so a loaded state shows the same screen before the next frame is drawn
+impl Snapshot for Frame {
+    fn save_state(&self,state: &mut StateWriter) {
+        state.bytes(&self.data);
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        state.bytes_into(&mut self.data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b"Wikipedia"),0x11E6_0398);
+    }
+    #[test]
+    fn test_png_layout() {
+        let mut frame = Frame::new();
+        frame.set_pixel(0,0,(0xFF,0x00,0x80));
+        let png = frame.to_png();
+        assert_eq!(&png[..8],&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]);
+        assert_eq!(&png[12..16],b"IHDR");
+        assert_eq!(&png[16..24],&[0, 0, 1, 0, 0, 0, 0, 240]);
+        assert_eq!(&png[png.len() - 12..],&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+        // IDAT: zlib header, first stored block header, filter byte of row 0, then the pixel
+        let idat = 8 + 25;
+        assert_eq!(&png[idat + 4..idat + 8],b"IDAT");
+        assert_eq!(&png[idat + 8..idat + 19],&[0x78, 0x01, 0, 0xFF, 0xFF, 0x00, 0x00, 0, 0xFF, 0x00, 0x80]);
+    }
+}
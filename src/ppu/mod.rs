@@ -0,0 +1,708 @@
+//! 2C02 picture processing unit.
+//!
+//! CPU facing registers, mirrored every 8 bytes in [0x2000 .. 0x3FFF]:
+//! 0x2000 PPUCTRL   (write) nametable select, VRAM increment, pattern tables, sprite size, NMI enable
+//! 0x2001 PPUMASK   (write) greyscale, left column clipping, background/sprite enable
+//! 0x2002 PPUSTATUS (read)  sprite overflow, sprite 0 hit, vblank
+//! 0x2003 OAMADDR   (write)
+//! 0x2004 OAMDATA   (read/write)
+//! 0x2005 PPUSCROLL (write x2)
+//! 0x2006 PPUADDR   (write x2)
+//! 0x2007 PPUDATA   (read/write)
+//!
+//! PPU memory map:
+//! [0x0000 .. 0x1FFF] pattern tables, served by the cartridge mapper
+//! [0x2000 .. 0x2FFF] nametables, 2 KiB of VRAM arranged by the mirroring mode, mirrored up to 0x3EFF
+//! [0x3F00 .. 0x3F1F] palette RAM, mirrored up to 0x3FFF
+//!
+//! Scrolling follows the "loopy" model: v is the current VRAM address, t the temporary one,
+//! x the fine X scroll and w the shared write toggle of 0x2005/0x2006.
+//! https://www.nesdev.org/wiki/PPU_scrolling
+//!
+//! A frame is 262 scanlines of 341 dots: 0-239 are visible, 241 starts vblank and 261 is the pre-render line.
+
+pub mod frame;
+pub mod palette;
+
+use crate::cartridge::Mirroring;
+use crate::mapper::Mapper;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use frame::Frame;
+use palette::SYSTEM_PALETTE;
+
+// PPUCTRL
+pub static CTRL_NAMETABLE: u8 = 0b0000_0011;
+pub static CTRL_VRAM_INCREMENT: u8 = 0b0000_0100;
+pub static CTRL_SPRITE_PATTERN: u8 = 0b0000_1000;
+pub static CTRL_BACKGROUND_PATTERN: u8 = 0b0001_0000;
+pub static CTRL_SPRITE_SIZE: u8 = 0b0010_0000;
+pub static CTRL_GENERATE_NMI: u8 = 0b1000_0000;
+// PPUMASK
+pub static MASK_GREYSCALE: u8 = 0b0000_0001;
+pub static MASK_BACKGROUND_LEFT: u8 = 0b0000_0010;
+pub static MASK_SPRITES_LEFT: u8 = 0b0000_0100;
+pub static MASK_BACKGROUND: u8 = 0b0000_1000;
+pub static MASK_SPRITES: u8 = 0b0001_0000;
+// PPUSTATUS
+pub static STATUS_SPRITE_OVERFLOW: u8 = 0b0010_0000;
+pub static STATUS_SPRITE_ZERO_HIT: u8 = 0b0100_0000;
+pub static STATUS_VBLANK: u8 = 0b1000_0000;
+
+pub const DOTS_PER_SCANLINE: u16 = 341;
+pub const SCANLINES_PER_FRAME: u16 = 262;
+pub const VBLANK_SCANLINE: u16 = 241;
+pub const PRE_RENDER_SCANLINE: u16 = 261;
+
+#[derive(Clone, Copy, Default)]
+struct Sprite {
+    x: u8,
+    attributes: u8,
+    // pattern bytes of the row on this line, already flipped horizontally if needed
+    pattern_lo: u8,
+    pattern_hi: u8,
+    is_sprite_zero: bool,
+}
+
+pub struct NesPPU {
+    // 4 KiB so four screen boards can use all four nametables, the others only touch the first 2 KiB
+    pub vram: [u8; 0x1000],
+    pub palette_table: [u8; 32],
+    pub oam_data: [u8; 256],
+    pub oam_addr: u8,
+    pub ctrl: u8,
+    pub mask: u8,
+    pub status: u8,
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    write_toggle: bool,
+    // reads of 0x2007 outside the palette return the previously fetched byte
+    read_buffer: u8,
+    // the data bus between CPU and PPU keeps the last value written, write-only registers read it back
+    open_bus: u8,
+    pub scanline: u16,
+    pub dot: u16,
+    pub frame_count: u64,
+    odd_frame: bool,
+    nmi_pending: bool,
+    // background fetch pipeline
+    next_tile_id: u8,
+    next_tile_attribute: u8,
+    next_tile_lo: u8,
+    next_tile_hi: u8,
+    bg_shifter_pattern_lo: u16,
+    bg_shifter_pattern_hi: u16,
+    bg_shifter_attribute_lo: u16,
+    bg_shifter_attribute_hi: u16,
+    // sprites selected for the line being drawn
+    sprites: [Sprite; 8],
+    sprite_count: usize,
+    pub frame: Frame,
+}
+
+impl Default for NesPPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NesPPU {
+    pub fn new() -> Self {
+        NesPPU {
+            vram: [0; 0x1000],
+            palette_table: [0; 32],
+            oam_data: [0; 256],
+            oam_addr: 0,
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            write_toggle: false,
+            read_buffer: 0,
+            open_bus: 0,
+            scanline: 0,
+            dot: 0,
+            frame_count: 0,
+            odd_frame: false,
+            nmi_pending: false,
+            next_tile_id: 0,
+            next_tile_attribute: 0,
+            next_tile_lo: 0,
+            next_tile_hi: 0,
+            bg_shifter_pattern_lo: 0,
+            bg_shifter_pattern_hi: 0,
+            bg_shifter_attribute_lo: 0,
+            bg_shifter_attribute_hi: 0,
+            sprites: [Sprite::default(); 8],
+            sprite_count: 0,
+            frame: Frame::new(),
+        }
+    }
+    // the reset line clears PPUCTRL, PPUMASK, the write toggle and the read buffer, VRAM and OAM keep their contents
+    pub fn reset(&mut self) {
+        self.ctrl = 0;
+        self.mask = 0;
+        self.write_toggle = false;
+        self.read_buffer = 0;
+        self.odd_frame = false;
+    }
+    // NMI is raised when vblank starts with NMI enabled, or when NMI gets enabled during vblank
+    pub fn poll_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi_pending)
+    }
+    fn rendering_enabled(&self) -> bool {
+        self.mask & (MASK_BACKGROUND | MASK_SPRITES) != 0
+    }
+    fn sprite_height(&self) -> u16 {
+        if self.ctrl & CTRL_SPRITE_SIZE != 0 { 16 } else { 8 }
+    }
+    // register op
+    pub fn read_register(&mut self,addr: u16,mapper: &mut dyn Mapper) -> u8 {
+        match addr & 0x0007 {
+            2 => {
+                // the lower 5 bits are not driven and keep whatever was last on the bus
+                let data = (self.status & 0b1110_0000) | (self.open_bus & 0b0001_1111);
+                self.status &= !STATUS_VBLANK;
+                self.write_toggle = false;
+                self.open_bus = data;
+            },
+            4 => self.open_bus = self.oam_data[self.oam_addr as usize],
+            7 => {
+                let addr = self.v & 0x3FFF;
+                let data = self.read_vram(addr,mapper);
+                self.open_bus = if addr >= 0x3F00 {
+                    // palette reads are immediate, the buffer is filled with the nametable byte underneath
+                    self.read_buffer = self.read_vram(addr - 0x1000,mapper);
+                    (data & 0b0011_1111) | (self.open_bus & 0b1100_0000)
+                }else {
+                    std::mem::replace(&mut self.read_buffer,data)
+                };
+                self.increment_vram_addr();
+            },
+            _ => {},
+        }
+        self.open_bus
+    }
+    pub fn write_register(&mut self,addr: u16,data: u8,mapper: &mut dyn Mapper) {
+        self.open_bus = data;
+        match addr & 0x0007 {
+            0 => {
+                let nmi_before = self.ctrl & CTRL_GENERATE_NMI != 0;
+                self.ctrl = data;
+                self.t = (self.t & !0x0C00) | (((data & CTRL_NAMETABLE) as u16) << 10);
+                if !nmi_before && data & CTRL_GENERATE_NMI != 0 && self.status & STATUS_VBLANK != 0 {
+                    self.nmi_pending = true;
+                }
+            },
+            1 => self.mask = data,
+            3 => self.oam_addr = data,
+            4 => self.write_oam(data),
+            5 => {
+                if !self.write_toggle {
+                    self.t = (self.t & !0x001F) | (data >> 3) as u16;
+                    self.fine_x = data & 0b111;
+                }else {
+                    self.t = (self.t & !0x73E0) | (((data & 0b111) as u16) << 12) | (((data >> 3) as u16) << 5);
+                }
+                self.write_toggle = !self.write_toggle;
+            },
+            6 => {
+                if !self.write_toggle {
+                    self.t = (self.t & 0x00FF) | (((data & 0x3F) as u16) << 8);
+                }else {
+                    self.t = (self.t & 0xFF00) | data as u16;
+                    self.v = self.t;
+                }
+                self.write_toggle = !self.write_toggle;
+            },
+            7 => {
+                self.write_vram(self.v & 0x3FFF,data,mapper);
+                self.increment_vram_addr();
+            },
+            _ => {},
+        }
+    }
+    /// Writes a byte straight into OAM at the current OAMADDR, as OAM DMA does.
+    pub fn write_oam(&mut self,data: u8) {
+        self.oam_data[self.oam_addr as usize] = data;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+    fn increment_vram_addr(&mut self) {
+        let step = if self.ctrl & CTRL_VRAM_INCREMENT != 0 { 32 } else { 1 };
+        self.v = self.v.wrapping_add(step) & 0x7FFF;
+    }
+    // memory op
+    fn nametable_index(&self,addr: u16,mirroring: Mirroring) -> usize {
+        let addr = (addr - 0x2000) & 0x0FFF;
+        let table = addr / 0x400;
+        let offset = (addr % 0x400) as usize;
+        let physical = match mirroring {
+            Mirroring::Vertical => table & 1,
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => table,
+        };
+        physical as usize * 0x400 + offset
+    }
+    fn palette_index(addr: u16) -> usize {
+        let index = (addr & 0x1F) as usize;
+        // the backdrop entries of the sprite palettes mirror the background ones
+        if index & 0x13 == 0x10 { index & 0x0F } else { index }
+    }
+    fn read_vram(&mut self,addr: u16,mapper: &mut dyn Mapper) -> u8 {
+        match addr {
+            0x0000 ..= 0x1FFF => mapper.ppu_read(addr),
+            0x2000 ..= 0x3EFF => self.vram[self.nametable_index(addr,mapper.mirroring())],
+            _ => self.palette_table[NesPPU::palette_index(addr)],
+        }
+    }
+    fn write_vram(&mut self,addr: u16,data: u8,mapper: &mut dyn Mapper) {
+        match addr {
+            0x0000 ..= 0x1FFF => mapper.ppu_write(addr,data),
+            0x2000 ..= 0x3EFF => self.vram[self.nametable_index(addr,mapper.mirroring())] = data,
+            _ => self.palette_table[NesPPU::palette_index(addr)] = data & 0b0011_1111,
+        }
+    }
+    // scroll op, see "Wrapping around" in the loopy document
+    fn increment_coarse_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        }else {
+            self.v += 1;
+        }
+    }
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        }else if coarse_y == 31 {
+            // coarse Y in the attribute area wraps without switching nametables
+            coarse_y = 0;
+        }else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+    fn copy_horizontal_bits(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+    fn copy_vertical_bits(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+    // background op
+    fn load_background_shifters(&mut self) {
+        self.bg_shifter_pattern_lo = (self.bg_shifter_pattern_lo & 0xFF00) | self.next_tile_lo as u16;
+        self.bg_shifter_pattern_hi = (self.bg_shifter_pattern_hi & 0xFF00) | self.next_tile_hi as u16;
+        let attribute_lo = if self.next_tile_attribute & 0b01 != 0 { 0xFF } else { 0x00 };
+        let attribute_hi = if self.next_tile_attribute & 0b10 != 0 { 0xFF } else { 0x00 };
+        self.bg_shifter_attribute_lo = (self.bg_shifter_attribute_lo & 0xFF00) | attribute_lo;
+        self.bg_shifter_attribute_hi = (self.bg_shifter_attribute_hi & 0xFF00) | attribute_hi;
+    }
+    fn update_background_shifters(&mut self) {
+        if self.mask & MASK_BACKGROUND != 0 {
+            self.bg_shifter_pattern_lo <<= 1;
+            self.bg_shifter_pattern_hi <<= 1;
+            self.bg_shifter_attribute_lo <<= 1;
+            self.bg_shifter_attribute_hi <<= 1;
+        }
+    }
+    // the 8 dot fetch cycle: nametable byte, attribute byte, pattern low, pattern high
+    fn fetch_background(&mut self,mapper: &mut dyn Mapper) {
+        match (self.dot - 1) % 8 {
+            0 => {
+                self.load_background_shifters();
+                self.next_tile_id = self.read_vram(0x2000 | (self.v & 0x0FFF),mapper);
+            },
+            2 => {
+                let addr = 0x23C0 | (self.v & 0x0C00) | ((self.v >> 4) & 0x38) | ((self.v >> 2) & 0x07);
+                let mut attribute = self.read_vram(addr,mapper);
+                if self.v & 0x0040 != 0 {
+                    attribute >>= 4;
+                }
+                if self.v & 0x0002 != 0 {
+                    attribute >>= 2;
+                }
+                self.next_tile_attribute = attribute & 0b11;
+            },
+            4 => {
+                let addr = self.background_pattern_addr();
+                self.next_tile_lo = self.read_vram(addr,mapper);
+            },
+            6 => {
+                let addr = self.background_pattern_addr() + 8;
+                self.next_tile_hi = self.read_vram(addr,mapper);
+            },
+            7 => self.increment_coarse_x(),
+            _ => {},
+        }
+    }
+    fn background_pattern_addr(&self) -> u16 {
+        let table = if self.ctrl & CTRL_BACKGROUND_PATTERN != 0 { 0x1000 } else { 0 };
+        table + self.next_tile_id as u16 * 16 + ((self.v >> 12) & 0b111)
+    }
+    // returns the 2 bit pixel and 2 bit palette of the background at the current dot
+    fn background_pixel(&self,x: u16) -> (u8,u8) {
+        if self.mask & MASK_BACKGROUND == 0 || (x < 8 && self.mask & MASK_BACKGROUND_LEFT == 0) {
+            return (0,0);
+        }
+        let mux = 0x8000 >> self.fine_x;
+        let pixel = ((self.bg_shifter_pattern_hi & mux != 0) as u8) << 1 | (self.bg_shifter_pattern_lo & mux != 0) as u8;
+        let palette = ((self.bg_shifter_attribute_hi & mux != 0) as u8) << 1 | (self.bg_shifter_attribute_lo & mux != 0) as u8;
+        (pixel,palette)
+    }
+    // sprite op
+    // picks the first 8 sprites in range of the next scanline and fetches their pattern rows
+    fn evaluate_sprites(&mut self,mapper: &mut dyn Mapper) {
+        self.sprite_count = 0;
+        if self.scanline == PRE_RENDER_SCANLINE {
+            return;
+        }
+        let height = self.sprite_height();
+        for index in 0..64 {
+            let y = self.oam_data[index * 4] as u16;
+            // OAM holds the sprite Y minus one, so the row drawn on the next line is scanline - y
+            let row = self.scanline.wrapping_sub(y);
+            if row >= height {
+                continue;
+            }
+            if self.sprite_count == 8 {
+                self.status |= STATUS_SPRITE_OVERFLOW;
+                break;
+            }
+            let tile = self.oam_data[index * 4 + 1] as u16;
+            let attributes = self.oam_data[index * 4 + 2];
+            let row = if attributes & 0b1000_0000 != 0 { height - 1 - row } else { row };
+            let addr = if height == 16 {
+                // 8x16 sprites take their pattern table from bit 0 of the tile number
+                let table = (tile & 1) * 0x1000;
+                let tile = (tile & 0xFE) + (row >> 3);
+                table + tile * 16 + (row & 0b111)
+            }else {
+                let table = if self.ctrl & CTRL_SPRITE_PATTERN != 0 { 0x1000 } else { 0 };
+                table + tile * 16 + row
+            };
+            let mut pattern_lo = self.read_vram(addr,mapper);
+            let mut pattern_hi = self.read_vram(addr + 8,mapper);
+            if attributes & 0b0100_0000 != 0 {
+                pattern_lo = pattern_lo.reverse_bits();
+                pattern_hi = pattern_hi.reverse_bits();
+            }
+            self.sprites[self.sprite_count] = Sprite {
+                x: self.oam_data[index * 4 + 3],
+                attributes,
+                pattern_lo,
+                pattern_hi,
+                is_sprite_zero: index == 0,
+            };
+            self.sprite_count += 1;
+        }
+    }
+    // returns pixel, palette, behind-background priority and whether it is sprite 0 for the first opaque sprite at x
+    fn sprite_pixel(&self,x: u16) -> Option<(u8,u8,bool,bool)> {
+        if self.mask & MASK_SPRITES == 0 || (x < 8 && self.mask & MASK_SPRITES_LEFT == 0) {
+            return None;
+        }
+        self.sprites[..self.sprite_count].iter().find_map(|sprite| {
+            let offset = x.wrapping_sub(sprite.x as u16);
+            if offset >= 8 {
+                return None;
+            }
+            let bit = 7 - offset;
+            let pixel = ((sprite.pattern_hi >> bit) & 1) << 1 | ((sprite.pattern_lo >> bit) & 1);
+            if pixel == 0 {
+                return None;
+            }
+            Some((pixel,(sprite.attributes & 0b11) + 4,sprite.attributes & 0b0010_0000 != 0,sprite.is_sprite_zero))
+        })
+    }
+    fn render_pixel(&mut self) {
+        let x = self.dot - 1;
+        let (bg_pixel,bg_palette) = self.background_pixel(x);
+        let sprite = self.sprite_pixel(x);
+        let (pixel,palette) = match sprite {
+            Some((sprite_pixel,sprite_palette,behind,is_sprite_zero)) => {
+                if is_sprite_zero && bg_pixel != 0 && x != 255 {
+                    self.status |= STATUS_SPRITE_ZERO_HIT;
+                }
+                if bg_pixel != 0 && behind {
+                    (bg_pixel,bg_palette)
+                }else {
+                    (sprite_pixel,sprite_palette)
+                }
+            },
+            None => (bg_pixel,bg_palette),
+        };
+        let addr = if pixel == 0 { 0 } else { palette as usize * 4 + pixel as usize };
+        let mut color = self.palette_table[NesPPU::palette_index(addr as u16)];
+        if self.mask & MASK_GREYSCALE != 0 {
+            color &= 0x30;
+        }
+        self.frame.set_pixel(x as usize,self.scanline as usize,SYSTEM_PALETTE[(color & 0x3F) as usize]);
+    }
+    /// Advances the PPU by one dot.
+    pub fn tick(&mut self,mapper: &mut dyn Mapper) {
+        let visible = self.scanline < 240;
+        let pre_render = self.scanline == PRE_RENDER_SCANLINE;
+        let rendering = self.rendering_enabled();
+
+        if pre_render && self.dot == 1 {
+            self.status &= !(STATUS_VBLANK | STATUS_SPRITE_ZERO_HIT | STATUS_SPRITE_OVERFLOW);
+        }
+        if rendering && (visible || pre_render) {
+            if (2..=257).contains(&self.dot) || (322..=337).contains(&self.dot) {
+                self.update_background_shifters();
+            }
+            if (1..=256).contains(&self.dot) || (321..=336).contains(&self.dot) {
+                self.fetch_background(mapper);
+            }
+            match self.dot {
+                256 => self.increment_y(),
+                257 => {
+                    self.load_background_shifters();
+                    self.copy_horizontal_bits();
+                    self.evaluate_sprites(mapper);
+                },
+                260 => mapper.scanline(),
+                280 ..= 304 if pre_render => self.copy_vertical_bits(),
+                _ => {},
+            }
+        }
+        // drawn after the shifters moved, so dot 1 shows the first bit of the tile fetched during the previous line
+        if visible && (1..=256).contains(&self.dot) {
+            self.render_pixel();
+        }
+        if self.scanline == VBLANK_SCANLINE && self.dot == 1 {
+            self.status |= STATUS_VBLANK;
+            self.frame_count += 1;
+            if self.ctrl & CTRL_GENERATE_NMI != 0 {
+                self.nmi_pending = true;
+            }
+        }
+
+        self.dot += 1;
+        // odd frames skip the last dot of the pre-render line while rendering
+        if pre_render && self.dot == 340 && self.odd_frame && rendering {
+            self.dot += 1;
+        }
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == SCANLINES_PER_FRAME {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+}
+
+impl Snapshot for NesPPU {
+    fn save_state(&self,state: &mut StateWriter) {
+        state.section(b"PPU ",|state| {
+            state.bytes(&self.vram);
+            state.bytes(&self.palette_table);
+            state.bytes(&self.oam_data);
+            for value in [self.oam_addr,self.ctrl,self.mask,self.status,self.fine_x,self.read_buffer,self.open_bus] {
+                state.u8(value);
+            }
+            state.u16(self.v);
+            state.u16(self.t);
+            state.bool(self.write_toggle);
+            state.u16(self.scanline);
+            state.u16(self.dot);
+            state.u64(self.frame_count);
+            state.bool(self.odd_frame);
+            state.bool(self.nmi_pending);
+            for value in [self.next_tile_id,self.next_tile_attribute,self.next_tile_lo,self.next_tile_hi] {
+                state.u8(value);
+            }
+            for value in [self.bg_shifter_pattern_lo,self.bg_shifter_pattern_hi,self.bg_shifter_attribute_lo,self.bg_shifter_attribute_hi] {
+                state.u16(value);
+            }
+            for sprite in self.sprites.iter() {
+                state.u8(sprite.x);
+                state.u8(sprite.attributes);
+                state.u8(sprite.pattern_lo);
+                state.u8(sprite.pattern_hi);
+                state.bool(sprite.is_sprite_zero);
+            }
+            state.u8(self.sprite_count as u8);
+            self.frame.save_state(state);
+        });
+    }
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+        state.section(b"PPU ",|state| {
+            state.bytes_into(&mut self.vram)?;
+            state.bytes_into(&mut self.palette_table)?;
+            state.bytes_into(&mut self.oam_data)?;
+            for value in [&mut self.oam_addr,&mut self.ctrl,&mut self.mask,&mut self.status,&mut self.fine_x,&mut self.read_buffer,&mut self.open_bus] {
+                *value = state.u8()?;
+            }
+            self.v = state.u16()?;
+            self.t = state.u16()?;
+            self.write_toggle = state.bool()?;
+            self.scanline = state.u16()?;
+            self.dot = state.u16()?;
+            state.ensure(self.scanline < SCANLINES_PER_FRAME && self.dot < DOTS_PER_SCANLINE)?;
+            self.frame_count = state.u64()?;
+            self.odd_frame = state.bool()?;
+            self.nmi_pending = state.bool()?;
+            for value in [&mut self.next_tile_id,&mut self.next_tile_attribute,&mut self.next_tile_lo,&mut self.next_tile_hi] {
+                *value = state.u8()?;
+            }
+            for value in [&mut self.bg_shifter_pattern_lo,&mut self.bg_shifter_pattern_hi,&mut self.bg_shifter_attribute_lo,&mut self.bg_shifter_attribute_hi] {
+                *value = state.u16()?;
+            }
+            for sprite in self.sprites.iter_mut() {
+                sprite.x = state.u8()?;
+                sprite.attributes = state.u8()?;
+                sprite.pattern_lo = state.u8()?;
+                sprite.pattern_hi = state.u8()?;
+                sprite.is_sprite_zero = state.bool()?;
+            }
+            self.sprite_count = state.u8()? as usize;
+            state.ensure(self.sprite_count <= self.sprites.len())?;
+            self.frame.load_state(state)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::NoCartridge;
+    fn write_addr(ppu: &mut NesPPU,mapper: &mut NoCartridge,addr: u16) {
+        ppu.write_register(0x2006,(addr >> 8) as u8,mapper);
+        ppu.write_register(0x2006,(addr & 0xFF) as u8,mapper);
+    }
+    fn run_frame(ppu: &mut NesPPU,mapper: &mut NoCartridge) {
+        let frame = ppu.frame_count;
+        while ppu.frame_count == frame {
+            ppu.tick(mapper);
+        }
+    }
+    #[test]
+    fn test_vram_reads_are_buffered() {
+        let mut ppu = NesPPU::new();
+        let mut mapper = NoCartridge::new();
+        write_addr(&mut ppu,&mut mapper,0x2305);
+        ppu.write_register(0x2007,0x66,&mut mapper);
+        ppu.write_register(0x2007,0x77,&mut mapper);
+        write_addr(&mut ppu,&mut mapper,0x2305);
+        ppu.read_register(0x2007,&mut mapper);
+        assert_eq!(ppu.read_register(0x2007,&mut mapper),0x66);
+        assert_eq!(ppu.read_register(0x2007,&mut mapper),0x77);
+    }
+    #[test]
+    fn test_vram_increment_32_and_horizontal_mirroring() {
+        let mut ppu = NesPPU::new();
+        let mut mapper = NoCartridge::new();
+        ppu.write_register(0x2000,CTRL_VRAM_INCREMENT,&mut mapper);
+        write_addr(&mut ppu,&mut mapper,0x2000);
+        ppu.write_register(0x2007,0x11,&mut mapper);
+        ppu.write_register(0x2007,0x22,&mut mapper);
+        assert_eq!(ppu.vram[0x20],0x22);
+        // horizontal mirroring maps 0x2400 onto 0x2000
+        write_addr(&mut ppu,&mut mapper,0x2420);
+        ppu.read_register(0x2007,&mut mapper);
+        assert_eq!(ppu.read_register(0x2007,&mut mapper),0x22);
+    }
+    #[test]
+    fn test_palette_mirrors() {
+        let mut ppu = NesPPU::new();
+        let mut mapper = NoCartridge::new();
+        write_addr(&mut ppu,&mut mapper,0x3F10);
+        ppu.write_register(0x2007,0x2C,&mut mapper);
+        write_addr(&mut ppu,&mut mapper,0x3F20);
+        // palette reads are not buffered
+        assert_eq!(ppu.read_register(0x2007,&mut mapper),0x2C);
+        assert_eq!(ppu.palette_table[0],0x2C);
+    }
+    #[test]
+    fn test_status_read_clears_vblank_and_toggle() {
+        let mut ppu = NesPPU::new();
+        let mut mapper = NoCartridge::new();
+        ppu.status = STATUS_VBLANK | STATUS_SPRITE_ZERO_HIT;
+        ppu.write_register(0x2006,0x21,&mut mapper);
+        assert_eq!(ppu.read_register(0x2002,&mut mapper) & 0xE0,STATUS_VBLANK | STATUS_SPRITE_ZERO_HIT);
+        assert_eq!(ppu.status,STATUS_SPRITE_ZERO_HIT);
+        // the toggle was reset, so this is again the high byte
+        write_addr(&mut ppu,&mut mapper,0x2345);
+        assert_eq!(ppu.v,0x2345);
+    }
+    #[test]
+    fn test_oam_registers() {
+        let mut ppu = NesPPU::new();
+        let mut mapper = NoCartridge::new();
+        ppu.write_register(0x2003,0x10,&mut mapper);
+        ppu.write_register(0x2004,0x66,&mut mapper);
+        ppu.write_register(0x2004,0x77,&mut mapper);
+        ppu.write_register(0x2003,0x11,&mut mapper);
+        assert_eq!(ppu.read_register(0x2004,&mut mapper),0x77);
+    }
+    #[test]
+    fn test_vblank_raises_nmi_once_per_frame() {
+        let mut ppu = NesPPU::new();
+        let mut mapper = NoCartridge::new();
+        ppu.write_register(0x2000,CTRL_GENERATE_NMI,&mut mapper);
+        run_frame(&mut ppu,&mut mapper);
+        assert_eq!((ppu.scanline,ppu.dot),(VBLANK_SCANLINE,2));
+        assert_ne!(ppu.status & STATUS_VBLANK,0);
+        assert!(ppu.poll_nmi());
+        assert!(!ppu.poll_nmi());
+        // enabling NMI again inside vblank raises another one
+        ppu.write_register(0x2000,0,&mut mapper);
+        ppu.write_register(0x2000,CTRL_GENERATE_NMI,&mut mapper);
+        assert!(ppu.poll_nmi());
+    }
+    #[test]
+    fn test_renders_background_and_sprite_zero_hit() {
+        let mut ppu = NesPPU::new();
+        let mut mapper = NoCartridge::new();
+        // tile 1 is solid color 1, tile 2 is solid color 3
+        for row in 0..8 {
+            mapper.ppu_write(16 + row,0xFF);
+            mapper.ppu_write(32 + row,0xFF);
+            mapper.ppu_write(32 + row + 8,0xFF);
+        }
+        // tile 1 at the second tile column of the first row
+        write_addr(&mut ppu,&mut mapper,0x2001);
+        ppu.write_register(0x2007,0x01,&mut mapper);
+        write_addr(&mut ppu,&mut mapper,0x3F00);
+        for color in [0x0F,0x30,0x16,0x27] {
+            ppu.write_register(0x2007,color,&mut mapper);
+        }
+        write_addr(&mut ppu,&mut mapper,0x3F13);
+        ppu.write_register(0x2007,0x1A,&mut mapper);
+        // sprite 0: tile 2 at x=12 on line 21
+        ppu.oam_data.fill(0xFF);
+        ppu.oam_data[0..4].copy_from_slice(&[20,2,0,12]);
+        write_addr(&mut ppu,&mut mapper,0x0000);
+        ppu.write_register(0x2005,0,&mut mapper);
+        ppu.write_register(0x2005,0,&mut mapper);
+        ppu.write_register(0x2001,MASK_BACKGROUND | MASK_SPRITES | MASK_BACKGROUND_LEFT | MASK_SPRITES_LEFT,&mut mapper);
+        run_frame(&mut ppu,&mut mapper);
+        run_frame(&mut ppu,&mut mapper);
+        assert_eq!(ppu.frame.get_pixel(0,0),SYSTEM_PALETTE[0x0F]);
+        assert_eq!(ppu.frame.get_pixel(8,0),SYSTEM_PALETTE[0x30]);
+        assert_eq!(ppu.frame.get_pixel(15,7),SYSTEM_PALETTE[0x30]);
+        assert_eq!(ppu.frame.get_pixel(16,7),SYSTEM_PALETTE[0x0F]);
+        assert_eq!(ppu.frame.get_pixel(12,21),SYSTEM_PALETTE[0x1A]);
+        assert_eq!(ppu.frame.get_pixel(12,20),SYSTEM_PALETTE[0x0F]);
+        // the sprite only overlaps transparent background
+        assert_eq!(ppu.status & STATUS_SPRITE_ZERO_HIT,0);
+
+        ppu.oam_data[0..4].copy_from_slice(&[0,2,0,12]);
+        run_frame(&mut ppu,&mut mapper);
+        assert_ne!(ppu.status & STATUS_SPRITE_ZERO_HIT,0);
+    }
+}
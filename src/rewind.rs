@@ -0,0 +1,208 @@
+//! Rewind buffer: a ring of save states taken every `interval` frames.
+//!
+//! Every `KEYFRAME_INTERVAL`th snapshot is kept whole as a keyframe, the ones in between only store
+//! their XOR against the snapshot before them, run length encoded. Consecutive states share almost
+//! all of their RAM, VRAM and cartridge memory, so a delta is a small fraction of a keyframe.
+//! Evicting a keyframe only decodes the delta after it, which becomes the new keyframe, while
+//! seeking decodes the chain from the keyframe up to the snapshot asked for.
+//!
+//! Deltas against the previous snapshot rather than against the keyframe are deliberate: with
+//! keyframe relative deltas every eviction of a keyframe re-encodes all the deltas after it, and
+//! once the ring is full that happens on nearly every push. Chained deltas are also smaller, at the
+//! cost of keeping the newest state in full to encode the next delta against.
+//!
+//! Delta encoding, repeated until the end: varint count of unchanged bytes, varint count of
+//! changed bytes, then the changed bytes XORed with the previous snapshot. Varints are LEB128.
+
+use std::collections::VecDeque;
+
+// snapshots per keyframe, longer chains save memory but seeking has more deltas to decode
+pub const KEYFRAME_INTERVAL: usize = 16;
+
+struct Entry {
+    frame: u64,
+    keyframe: bool,
+    data: Vec<u8>,
+}
+
+pub struct Rewind {
+    interval: u64,
+    capacity: usize,
+    entries: VecDeque<Entry>,
+    // the newest state in full, the next delta is encoded against it
+    newest: Vec<u8>,
+}
+
+impl Rewind {
+    /// Snapshots every `interval` frames, keeping at most `capacity` of them.
+    pub fn new(interval: u64,capacity: usize) -> Self {
+        assert!(interval > 0 && capacity > 0,"rewind needs a non zero interval and capacity");
+        Rewind { interval, capacity, entries: VecDeque::new(), newest: Vec::new() }
+    }
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    /// Bytes held by the snapshots, including the full copy of the newest state.
+    pub fn memory_usage(&self) -> usize {
+        self.entries.iter().map(|entry| entry.data.len()).sum::<usize>() + self.newest.len()
+    }
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.newest.clear();
+    }
+    // index of the keyframe the newest snapshot decodes from
+    fn last_keyframe(&self) -> Option<usize> {
+        self.entries.iter().rposition(|entry| entry.keyframe)
+    }
+    /// Adds the state of `frame`, the oldest snapshot makes room when the ring is full.
+    pub fn push(&mut self,frame: u64,state: Vec<u8>) {
+        let entry = match self.last_keyframe() {
+            Some(index) if self.entries.len() - index < KEYFRAME_INTERVAL && self.newest.len() == state.len() => {
+                Entry { frame, keyframe: false, data: encode_delta(&self.newest,&state) }
+            },
+            _ => Entry { frame, keyframe: true, data: state.clone() },
+        };
+        self.entries.push_back(entry);
+        self.newest = state;
+        if self.entries.len() > self.capacity {
+            self.evict_oldest();
+        }
+    }
+    // the oldest snapshot is always a keyframe, the delta after it is decoded to take its place
+    fn evict_oldest(&mut self) {
+        let old_key = self.entries.pop_front().unwrap().data;
+        if let Some(next) = self.entries.front_mut().filter(|entry| !entry.keyframe) {
+            next.data = decode_delta(&old_key,&next.data);
+            next.keyframe = true;
+        }
+    }
+    /// Drops every snapshot newer than `frame` and returns the newest one left, which is kept so rewinding again goes further back.
+    /// Returns the oldest snapshot when none is that old, None when the ring is empty.
+    pub fn seek(&mut self,frame: u64) -> Option<(u64,Vec<u8>)> {
+        let keep = self.entries.iter().rposition(|entry| entry.frame <= frame).unwrap_or(0);
+        self.entries.truncate(keep + 1);
+        let index = self.last_keyframe()?;
+        let mut state = self.entries[index].data.clone();
+        for entry in self.entries.iter().skip(index + 1) {
+            state = decode_delta(&state,&entry.data);
+        }
+        self.newest = state.clone();
+        Some((self.entries.back()?.frame,state))
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>,mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8],pos: &mut usize) -> usize {
+    let (mut value,mut shift) = (0,0);
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+fn encode_delta(key: &[u8],state: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < state.len() {
+        let unchanged = key[pos..].iter().zip(&state[pos..]).take_while(|(a,b)| a == b).count();
+        pos += unchanged;
+        // a trailing unchanged run is implied
+        if pos == state.len() {
+            break;
+        }
+        let changed = key[pos..].iter().zip(&state[pos..]).take_while(|(a,b)| a != b).count();
+        write_varint(&mut out,unchanged);
+        write_varint(&mut out,changed);
+        out.extend(key[pos..pos + changed].iter().zip(&state[pos..pos + changed]).map(|(a,b)| a ^ b));
+        pos += changed;
+    }
+    out
+}
+
+fn decode_delta(key: &[u8],delta: &[u8]) -> Vec<u8> {
+    let mut state = key.to_vec();
+    let (mut pos,mut out) = (0,0);
+    while pos < delta.len() {
+        out += read_varint(delta,&mut pos);
+        let changed = read_varint(delta,&mut pos);
+        for (byte,diff) in state[out..out + changed].iter_mut().zip(&delta[pos..pos + changed]) {
+            *byte ^= diff;
+        }
+        pos += changed;
+        out += changed;
+    }
+    state
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    // a 4 KiB state where only a counter and one other byte change from frame to frame
+    fn state(frame: u64) -> Vec<u8> {
+        let mut state = vec![0x55; 0x1000];
+        state[..8].copy_from_slice(&frame.to_le_bytes());
+        state[0x800 + frame as usize % 0x100] = 0xAA;
+        state
+    }
+    #[test]
+    fn test_delta_round_trip() {
+        let key = state(1);
+        for frame in [1,2,300] {
+            let delta = encode_delta(&key,&state(frame));
+            assert_eq!(decode_delta(&key,&delta),state(frame));
+            assert!(delta.len() < 32);
+        }
+        assert!(encode_delta(&key,&key).is_empty());
+    }
+    #[test]
+    fn test_ring_keeps_capacity_and_seeks() {
+        let mut rewind = Rewind::new(2,20);
+        for frame in (2..=80).step_by(2) {
+            rewind.push(frame,state(frame));
+        }
+        assert_eq!(rewind.len(),20);
+        // 2 keyframes and the copy of the newest state, the rest are small deltas
+        assert!((3 * 0x1000..4 * 0x1000).contains(&rewind.memory_usage()));
+        assert_eq!(rewind.seek(75),Some((74,state(74))));
+        assert_eq!(rewind.seek(73),Some((72,state(72))));
+        // the oldest snapshot left was promoted to a keyframe when its own keyframe got evicted
+        assert_eq!(rewind.seek(0),Some((42,state(42))));
+        assert_eq!(rewind.len(),1);
+        rewind.push(44,state(44));
+        assert_eq!(rewind.seek(44),Some((44,state(44))));
+        rewind.clear();
+        assert_eq!(rewind.seek(44),None);
+    }
+    #[test]
+    fn test_every_snapshot_decodes_after_evictions() {
+        let mut rewind = Rewind::new(1,KEYFRAME_INTERVAL + 3);
+        for frame in 1..=100 {
+            rewind.push(frame,state(frame));
+            assert!(rewind.entries[0].keyframe);
+        }
+        for frame in (100 - KEYFRAME_INTERVAL as u64 - 2..=100).rev() {
+            assert_eq!(rewind.seek(frame),Some((frame,state(frame))));
+        }
+        // pushing after a seek continues the chain from the state seeked to
+        rewind.push(83,state(83));
+        assert_eq!(rewind.seek(83),Some((83,state(83))));
+    }
+}
@@ -0,0 +1,267 @@
+//! Save states.
+//!
+//! A state starts with a header, then one section per component:
+//! [0 .. 8]   magic "RNESSAVE"
+//! [8 .. 10]  format version, little endian like every other number
+//! [10 .. 14] CRC32 of the PRG and CHR ROM the state was taken with, 0 without a cartridge
+//! then until the end: 4 byte ASCII tag, u32 payload length, payload
+//!
+//! Every component writes its fields in a fixed order into its own section, so a state can be listed
+//! and compared section by section without knowing the fields. A change to any payload layout must bump `STATE_VERSION`.
+
+use std::fmt;
+
+pub const STATE_MAGIC: &[u8; 8] = b"RNESSAVE";
+pub const STATE_VERSION: u16 = 1;
+const HEADER_LEN: usize = 14;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion { version: u16 },
+    // the state was taken with another game
+    RomMismatch { expected: u32, found: u32 },
+    // a section is missing, truncated, longer than its fields or holds an impossible value
+    Corrupt { section: String },
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self,f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f,"not a save state"),
+            StateError::UnsupportedVersion { version } => write!(f,"save state version {} is not supported, expected {}",version,STATE_VERSION),
+            StateError::RomMismatch { expected, found } => write!(f,"save state was taken with ROM {:08X}, this one is {:08X}",found,expected),
+            StateError::Corrupt { section } => write!(f,"save state section {} is corrupt",section),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// A component of the machine that can be saved and restored.
+pub trait Snapshot {
+    fn save_state(&self,state: &mut StateWriter);
+    fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError>;
+}
+
+/// Serializes `machine` with the header for the ROM with checksum `rom`.
+pub fn save<S: Snapshot>(machine: &S,rom: u32) -> Vec<u8> {
+    let mut state = StateWriter { data: STATE_MAGIC.to_vec() };
+    state.u16(STATE_VERSION);
+    state.u32(rom);
+    machine.save_state(&mut state);
+    state.data
+}
+
+/// Restores `machine` from `data`, a state that fails to load leaves the machine as it was.
+pub fn load<S: Snapshot>(machine: &mut S,rom: u32,data: &[u8]) -> Result<(),StateError> {
+    let mut state = StateReader::new(&data[HEADER_LEN.min(data.len())..]);
+    let header = data.get(..HEADER_LEN).ok_or(StateError::NotAState)?;
+    if &header[..8] != STATE_MAGIC {
+        return Err(StateError::NotAState);
+    }
+    let version = u16::from_le_bytes([header[8],header[9]]);
+    if version != STATE_VERSION {
+        return Err(StateError::UnsupportedVersion { version });
+    }
+    let found = u32::from_le_bytes([header[10],header[11],header[12],header[13]]);
+    if found != rom {
+        return Err(StateError::RomMismatch { expected: rom, found });
+    }
+    let backup = save(machine,rom);
+    let result = machine.load_state(&mut state).and_then(|_| state.finish());
+    if result.is_err() {
+        // a state we just wrote always loads
+        machine.load_state(&mut StateReader::new(&backup[HEADER_LEN..])).unwrap();
+    }
+    result
+}
+
+/// Splits a state into its (tag, payload) sections.
+pub fn sections(data: &[u8]) -> Result<Vec<(String,&[u8])>,StateError> {
+    if data.len() < HEADER_LEN || &data[..8] != STATE_MAGIC {
+        return Err(StateError::NotAState);
+    }
+    let mut state = StateReader::new(&data[HEADER_LEN..]);
+    let mut sections = Vec::new();
+    while state.pos < state.data.len() {
+        let (tag,payload) = state.next_section()?;
+        sections.push((tag,payload));
+    }
+    Ok(sections)
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Writes the fields added by `fields` as section `tag`.
+    pub fn section<F: FnOnce(&mut StateWriter)>(&mut self,tag: &[u8; 4],fields: F) {
+        self.data.extend(tag);
+        let start = self.data.len();
+        self.u32(0);
+        fields(self);
+        let len = (self.data.len() - start - 4) as u32;
+        self.data[start..start + 4].copy_from_slice(&len.to_le_bytes());
+    }
+    pub fn u8(&mut self,value: u8) {
+        self.data.push(value);
+    }
+    pub fn bool(&mut self,value: bool) {
+        self.u8(value as u8);
+    }
+    pub fn u16(&mut self,value: u16) {
+        self.data.extend(value.to_le_bytes());
+    }
+    pub fn u32(&mut self,value: u32) {
+        self.data.extend(value.to_le_bytes());
+    }
+    pub fn u64(&mut self,value: u64) {
+        self.data.extend(value.to_le_bytes());
+    }
+    pub fn f32(&mut self,value: f32) {
+        self.u32(value.to_bits());
+    }
+    // length prefixed
+    pub fn bytes(&mut self,value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend(value);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    // the section being read, for errors
+    tag: String,
+}
+
+impl<'a> StateReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        StateReader { data, pos: 0, tag: "header".to_string() }
+    }
+    fn corrupt(&self) -> StateError {
+        StateError::Corrupt { section: self.tag.clone() }
+    }
+    fn take(&mut self,len: usize) -> Result<&'a [u8],StateError> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or_else(|| self.corrupt())?;
+        self.pos += len;
+        Ok(bytes)
+    }
+    fn next_section(&mut self) -> Result<(String,&'a [u8]),StateError> {
+        let tag = String::from_utf8_lossy(self.take(4)?).into_owned();
+        let len = self.u32()? as usize;
+        self.tag = tag.clone();
+        Ok((tag,self.take(len)?))
+    }
+    fn finish(&self) -> Result<(),StateError> {
+        if self.pos == self.data.len() { Ok(()) } else { Err(self.corrupt()) }
+    }
+    /// Reads section `tag` with `fields`, which must consume exactly its payload.
+    pub fn section<F: FnOnce(&mut StateReader<'a>) -> Result<(),StateError>>(&mut self,tag: &[u8; 4],fields: F) -> Result<(),StateError> {
+        let expected = String::from_utf8_lossy(tag).into_owned();
+        let (found,payload) = self.next_section().map_err(|_| StateError::Corrupt { section: expected.clone() })?;
+        if found != expected {
+            return Err(StateError::Corrupt { section: expected });
+        }
+        let mut section = StateReader { data: payload, pos: 0, tag: found };
+        fields(&mut section)?;
+        section.finish()
+    }
+    pub fn u8(&mut self) -> Result<u8,StateError> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn bool(&mut self) -> Result<bool,StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(self.corrupt()),
+        }
+    }
+    pub fn u16(&mut self) -> Result<u16,StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    pub fn u32(&mut self) -> Result<u32,StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn u64(&mut self) -> Result<u64,StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    pub fn f32(&mut self) -> Result<f32,StateError> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+    pub fn bytes(&mut self) -> Result<&'a [u8],StateError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+    // for fixed size memories, the saved length has to match
+    pub fn bytes_into(&mut self,dest: &mut [u8]) -> Result<(),StateError> {
+        let bytes = self.bytes()?;
+        if bytes.len() != dest.len() {
+            return Err(self.corrupt());
+        }
+        dest.copy_from_slice(bytes);
+        Ok(())
+    }
+    /// Fails the section unless `check` holds, for values with a limited range.
+    pub fn ensure(&self,check: bool) -> Result<(),StateError> {
+        if check { Ok(()) } else { Err(self.corrupt()) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[derive(Default, Debug, PartialEq)]
+    struct Chip {
+        flag: bool,
+        counter: u16,
+        memory: [u8; 4],
+    }
+    impl Snapshot for Chip {
+        fn save_state(&self,state: &mut StateWriter) {
+            state.section(b"CHIP",|state| {
+                state.bool(self.flag);
+                state.u16(self.counter);
+                state.bytes(&self.memory);
+            });
+        }
+        fn load_state(&mut self,state: &mut StateReader) -> Result<(),StateError> {
+            state.section(b"CHIP",|state| {
+                self.flag = state.bool()?;
+                self.counter = state.u16()?;
+                state.bytes_into(&mut self.memory)
+            })
+        }
+    }
+    #[test]
+    fn test_round_trip_and_sections() {
+        let chip = Chip { flag: true, counter: 0x1234, memory: [1, 2, 3, 4] };
+        let data = save(&chip,0xCAFE);
+        assert_eq!(&data[..8],STATE_MAGIC);
+        let mut loaded = Chip::default();
+        load(&mut loaded,0xCAFE,&data).unwrap();
+        assert_eq!(loaded,chip);
+        assert_eq!(sections(&data).unwrap(),vec![("CHIP".to_string(),&[1, 0x34, 0x12, 4, 0, 0, 0, 1, 2, 3, 4][..])]);
+    }
+    #[test]
+    fn test_rejects_bad_states() {
+        let chip = Chip { flag: true, counter: 7, memory: [9; 4] };
+        let data = save(&chip,1);
+        let mut loaded = Chip::default();
+        assert_eq!(load(&mut loaded,2,&data),Err(StateError::RomMismatch { expected: 2, found: 1 }));
+        assert_eq!(load(&mut loaded,1,&data[..5]),Err(StateError::NotAState));
+        let mut newer = data.clone();
+        newer[8] = STATE_VERSION as u8 + 1;
+        assert_eq!(load(&mut loaded,1,&newer),Err(StateError::UnsupportedVersion { version: STATE_VERSION + 1 }));
+        // a truncated state restores nothing, not even the fields read before the error
+        let truncated = &data[..data.len() - 1];
+        assert_eq!(load(&mut loaded,1,truncated),Err(StateError::Corrupt { section: "CHIP".to_string() }));
+        assert_eq!(loaded,Chip::default());
+        let mut extra = data.clone();
+        extra.push(0);
+        assert!(load(&mut loaded,1,&extra).is_err());
+        assert_eq!(loaded,Chip::default());
+    }
+}
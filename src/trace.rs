@@ -0,0 +1,192 @@
+//! Per instruction CPU trace in the Nintendulator format used by the nestest golden log:
+//!
+//! C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+//!
+//! The instruction is written by the disassembler, the trace adds the effective address and the value
+//! stored there before the instruction runs.
+//! Memory is only peeked, so tracing never changes what the program sees.
+
+use crate::addressing_modes::AddrMode;
+use crate::bus::Bus;
+use crate::cpu::{CpuVariant, CPU};
+use crate::disasm::disassemble_one;
+use crate::ops_codes::opcode_table;
+
+fn peek_u16<B: Bus>(bus: &mut B,addr: u16) -> u16 {
+    u16::from_le_bytes([bus.peek(addr),bus.peek(addr.wrapping_add(1))])
+}
+
+// pointers in the zero page wrap around without leaving it
+fn peek_u16_zero_page<B: Bus>(bus: &mut B,addr: u8) -> u16 {
+    u16::from_le_bytes([bus.peek(addr as u16),bus.peek(addr.wrapping_add(1) as u16)])
+}
+
+/// Formats the instruction at the program counter together with the CPU state before it executes.
+pub fn trace<B: Bus>(cpu: &mut CPU<B>) -> String {
+    let pc = cpu.program_counter;
+    let variant = cpu.variant;
+    let instruction = disassemble_one(variant,pc,|addr| cpu.bus.peek(addr));
+    let mode = &opcode_table(variant)[&instruction.bytes[0]].addressing_mode;
+    let asm = instruction.text() + &annotate_operand(cpu,mode,instruction.mnemonic,&instruction.bytes);
+    // unofficial opcodes take the column before the mnemonic for their marker
+    let marker = if instruction.official { ' ' } else { '*' };
+    let hex: Vec<String> = instruction.bytes.iter().map(|byte| format!("{:02X}",byte)).collect();
+    let (scanline,dot) = cpu.bus.ppu_position();
+    format!(
+        "{:04X}  {:<8} {}{:<31} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+        pc,hex.join(" "),marker,asm,cpu.accumulator,cpu.register_x,cpu.register_y,cpu.status,cpu.stack_ptr,scanline,dot,cpu.cycles
+    )
+}
+
+// what goes after the operand the disassembler wrote: `@ addr` for indexed modes, `= value` for memory operands
+fn annotate_operand<B: Bus>(cpu: &mut CPU<B>,mode: &AddrMode,mnemonic: &str,bytes: &[u8]) -> String {
+    let (x,y) = (cpu.register_x,cpu.register_y);
+    let page_wrap_bug = cpu.variant != CpuVariant::Cmos65C02;
+    let bus = &mut cpu.bus;
+    let byte = bytes.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte,bytes.get(2).copied().unwrap_or(0)]);
+    match mode {
+        AddrMode::Implied | AddrMode::Accumulator | AddrMode::Immediate | AddrMode::Relative => String::new(),
+        AddrMode::ZeroPage => format!(" = {:02X}",bus.peek(byte as u16)),
+        AddrMode::ZeroPageX => {
+            let addr = byte.wrapping_add(x);
+            format!(" @ {:02X} = {:02X}",addr,bus.peek(addr as u16))
+        },
+        AddrMode::ZeroPageY => {
+            let addr = byte.wrapping_add(y);
+            format!(" @ {:02X} = {:02X}",addr,bus.peek(addr as u16))
+        },
+        // jumps show their target only
+        AddrMode::Absolute if mnemonic == "JMP" || mnemonic == "JSR" => String::new(),
+        AddrMode::Absolute => format!(" = {:02X}",bus.peek(word)),
+        AddrMode::AbsoluteX => {
+            let addr = word.wrapping_add(x as u16);
+            format!(" @ {:04X} = {:02X}",addr,bus.peek(addr))
+        },
+        AddrMode::AbsoluteY => {
+            let addr = word.wrapping_add(y as u16);
+            format!(" @ {:04X} = {:02X}",addr,bus.peek(addr))
+        },
+        AddrMode::Indirect => {
+            // same page wrap as the CPU
+            let target = if word & 0x00FF == 0x00FF && page_wrap_bug {
+                u16::from_le_bytes([bus.peek(word),bus.peek(word & 0xFF00)])
+            }else {
+                peek_u16(bus,word)
+            };
+            format!(" = {:04X}",target)
+        },
+        AddrMode::ZeroPageIndirect => {
+            let addr = peek_u16_zero_page(bus,byte);
+            format!(" = {:04X} = {:02X}",addr,bus.peek(addr))
+        },
+        AddrMode::AbsoluteIndirectX => format!(" = {:04X}",peek_u16(bus,word.wrapping_add(x as u16))),
+        AddrMode::IndirectX => {
+            let ptr = byte.wrapping_add(x);
+            let addr = peek_u16_zero_page(bus,ptr);
+            format!(" @ {:02X} = {:04X} = {:02X}",ptr,addr,bus.peek(addr))
+        },
+        AddrMode::IndirectY => {
+            let deref = peek_u16_zero_page(bus,byte);
+            let addr = deref.wrapping_add(y as u16);
+            format!(" = {:04X} @ {:04X} = {:02X}",deref,addr,bus.peek(addr))
+        },
+    }
+}
+
+/// Compares a trace against a golden log and describes the first line that differs, if any.
+/// Trailing whitespace is ignored, a trace that stops early diverges on the first missing line.
+pub fn first_divergence(expected: &[&str],actual: &[String]) -> Option<String> {
+    expected.iter().enumerate().find_map(|(i,expected_line)| {
+        let expected_line = expected_line.trim_end();
+        let actual_line = actual.get(i).map(|line| line.trim_end()).unwrap_or("<trace ended>");
+        if expected_line == actual_line {
+            None
+        }else {
+            let previous = if i > 0 { expected[i - 1].trim_end() } else { "" };
+            Some(format!("line {} differs\n previous: {}\n expected: {}\n actual:   {}",i + 1,previous,expected_line,actual_line))
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::NesBus;
+    use crate::cartridge::Cartridge;
+    use std::fs;
+    // nestest boots through its reset vector, automation mode starts it at $C000 with P=24 instead
+    fn nestest_cpu(bus: NesBus) -> CPU {
+        let mut cpu = CPU::new(bus);
+        cpu.power_on();
+        cpu.program_counter = 0xC000;
+        cpu.status = 0x24;
+        cpu
+    }
+    #[test]
+    fn test_trace_format() {
+        let mut bus = NesBus::new();
+        let program = [
+            0x4C, 0xF5, 0xC5,
+            0x86, 0x00,
+            0xB1, 0x89,
+            0x6C, 0xFF, 0x02,
+            0x4A,
+            0x04, 0xA9,
+        ];
+        for (i,byte) in program.iter().enumerate() {
+            bus.write(0xC000 + i as u16,*byte);
+        }
+        bus.write(0x0089,0x00);
+        bus.write(0x008A,0x03);
+        bus.write(0x0300,0x89);
+        bus.write(0x02FF,0x7E);
+        bus.write(0x0200,0xDB);
+        let mut cpu = nestest_cpu(bus);
+        assert_eq!(trace(&mut cpu),"C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7");
+        cpu.program_counter = 0xC003;
+        assert_eq!(trace(&mut cpu),"C003  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7");
+        cpu.program_counter = 0xC005;
+        assert_eq!(trace(&mut cpu),"C005  B1 89     LDA ($89),Y = 0300 @ 0300 = 89  A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7");
+        cpu.program_counter = 0xC007;
+        assert_eq!(trace(&mut cpu),"C007  6C FF 02  JMP ($02FF) = DB7E              A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7");
+        cpu.program_counter = 0xC00A;
+        assert_eq!(trace(&mut cpu),"C00A  4A        LSR A                           A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7");
+        cpu.program_counter = 0xC00B;
+        assert_eq!(trace(&mut cpu),"C00B  04 A9    *NOP $A9 = 00                    A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7");
+    }
+    #[test]
+    fn test_first_divergence() {
+        let expected = ["a", "b", "c"];
+        let actual = vec!["a".to_string(), "b ".to_string()];
+        let report = first_divergence(&expected,&actual).unwrap();
+        assert!(report.starts_with("line 3 differs"));
+        assert!(first_divergence(&expected[..2],&actual).is_none());
+    }
+    // needs test_roms/nestest.nes and its golden test_roms/nestest.log, which are not in the repo,
+    // see "Test ROMs" in the README, then run `cargo test -- --ignored`
+    #[test]
+    #[ignore = "needs test_roms/nestest.nes and test_roms/nestest.log"]
+    fn test_nestest_golden_log() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"),"/test_roms/");
+        let rom = fs::read(format!("{}nestest.nes",dir)).unwrap_or_else(|err| panic!("{}nestest.nes: {}",dir,err));
+        let log = fs::read_to_string(format!("{}nestest.log",dir)).unwrap_or_else(|err| panic!("{}nestest.log: {}",dir,err));
+        let mut bus = NesBus::new();
+        bus.insert_cartridge(Cartridge::new(&rom).unwrap()).unwrap();
+        let mut cpu = nestest_cpu(bus);
+        let expected: Vec<&str> = log.lines().collect();
+        let mut actual = Vec::new();
+        cpu.run_with_callback(|cpu| {
+            let line = trace(cpu);
+            // compare as we go, so a broken instruction is reported before it can derail the run
+            let diverged = expected.get(actual.len()).map(|expected| expected.trim_end() != line).unwrap_or(true);
+            actual.push(line);
+            !diverged && actual.len() < expected.len()
+        }).unwrap();
+        if let Some(report) = first_divergence(&expected,&actual) {
+            panic!("nestest diverged from the golden log at {}",report);
+        }
+        // nestest leaves its error codes in $02 and $03
+        assert_eq!((cpu.bus.peek(0x02),cpu.bus.peek(0x03)),(0,0));
+    }
+}